@@ -1,25 +1,228 @@
+use futures_util::future::RemoteHandle;
 use log::error;
 use smithay_client_toolkit::compositor::Region;
 use smithay_client_toolkit::output::OutputInfo;
-use smithay_client_toolkit::shell::wlr_layer::{Anchor, Layer, LayerSurface};
+use smithay_client_toolkit::shell::wlr_layer::{
+    Anchor, KeyboardInteractivity, Layer, LayerSurface,
+};
 use smithay_client_toolkit::shell::WaylandSurface;
 use std::convert::TryInto;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use wayland_client::protocol::wl_output::WlOutput;
 
-use crate::event::EventSink;
+use crate::event::{EventSink, HoverKey};
 use crate::item::*;
 use crate::render::Renderer;
 use crate::state::{NotifierList, Runtime};
-use crate::util::{spawn_noerr, UID};
+use crate::util::{spawn_handle, spawn_noerr, toml_to_f64, toml_to_string, UID};
 use crate::wayland::{Button, Popup, SurfaceData, SurfaceEvents, WaylandClient};
 
+/// How long a popup stays open without any interaction, unless configured
+const POPUP_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub struct BarPopup {
     pub wl: Popup,
     desc: PopupDesc,
     vanish: Option<Instant>,
+    /// The horizontal extent of the item that opened this popup
+    region: (f32, f32),
+    /// Close the popup if there is no interaction with it for this long
+    timeout: Option<Duration>,
+    idle_until: Option<Instant>,
+    /// The pending wakeup for the idle timeout, and when it fires
+    idle_timer: Option<(Instant, RemoteHandle<()>)>,
+}
+
+impl BarPopup {
+    /// Restart the idle timeout after an interaction
+    fn touch(&mut self) {
+        self.idle_until = self.timeout.map(|t| Instant::now() + t);
+    }
+
+    /// Returns true if the idle timeout has expired; otherwise, make sure a wakeup is pending
+    fn idle_expired(&mut self, runtime: &Runtime) -> bool {
+        let until = match self.idle_until {
+            Some(until) => until,
+            None => return false,
+        };
+        let now = Instant::now();
+        if until <= now {
+            return true;
+        }
+        if self.idle_timer.as_ref().map_or(true, |(at, _)| *at <= now) {
+            let mut notify = NotifierList::active(runtime);
+            let rh = spawn_handle("Popup timeout", async move {
+                tokio::time::sleep_until(until.into()).await;
+                notify.notify_data("popup-timeout");
+                Ok(())
+            });
+            self.idle_timer = Some((until, rh));
+        }
+        false
+    }
+}
+
+/// The bar keys that control where it is docked, in the order used by [Docking::from_keys]
+const DOCKING_KEYS: [&str; 3] = ["side", "layer", "size-exclusive"];
+
+/// Where a bar is placed on its output.  The keys for this may use text expansions, in which case
+/// the bar is moved whenever they change.
+#[derive(Debug, Clone, PartialEq)]
+struct Docking {
+    layer: Layer,
+    anchor_top: bool,
+    floating: bool,
+    /// Docked to the left or right edge.  The bar is laid out as a horizontal one turned a
+    /// quarter turn clockwise, so its top is on the right.
+    vertical: bool,
+    /// The exclusive zone, or None to use the bar's size
+    exclusive: Option<i32>,
+}
+
+impl Docking {
+    fn from_keys([side, layer, exclusive]: &[Option<String>; 3]) -> Self {
+        let layer = match layer.as_deref() {
+            Some("overlay") => Layer::Overlay,
+            Some("bottom") => Layer::Bottom,
+            Some("background") => Layer::Background,
+            Some("top") | None => Layer::Top,
+            Some(layer) => {
+                error!("Unknown layer '{layer}', defaulting to top");
+                Layer::Top
+            }
+        };
+        let (anchor_top, floating, vertical) = match side.as_deref() {
+            Some("top") => (true, false, false),
+            None | Some("bottom") => (false, false, false),
+            Some("center") => (false, true, false),
+            Some("left") => (false, false, true),
+            Some("right") => (true, false, true),
+            Some(side) => {
+                error!("Unknown side '{}', defaulting to bottom", side);
+                (false, false, false)
+            }
+        };
+        let exclusive = exclusive
+            .as_deref()
+            .and_then(|v| v.trim().parse::<i32>().ok())
+            .filter(|&v| v >= -1);
+        Docking {
+            layer,
+            anchor_top,
+            floating,
+            vertical,
+            exclusive,
+        }
+    }
+
+    fn apply(&self, ls: &LayerSurface, fixed_width: Option<u32>, size: u32, margin: Margin) {
+        ls.set_layer(self.layer);
+        let (side, ends) = match (self.vertical, self.anchor_top) {
+            (false, true) => (Anchor::TOP, Anchor::LEFT | Anchor::RIGHT),
+            (false, false) => (Anchor::BOTTOM, Anchor::LEFT | Anchor::RIGHT),
+            (true, true) => (Anchor::RIGHT, Anchor::TOP | Anchor::BOTTOM),
+            (true, false) => (Anchor::LEFT, Anchor::TOP | Anchor::BOTTOM),
+        };
+        ls.set_anchor(match fixed_width {
+            // Anchored to opposite edges with a fixed size, the bar is centered between them, and
+            // the margins move that center (unlike an unanchored bar, where they are ignored)
+            _ if self.floating => Anchor::all(),
+            Some(_) => side,
+            None => side | ends,
+        });
+        let length = fixed_width.unwrap_or(0);
+        if self.vertical {
+            ls.set_size(size, length);
+        } else {
+            ls.set_size(length, size);
+        }
+        // The compositor adds the margin on the docked edge to the exclusive zone
+        let (top, right, bottom, left) = margin;
+        ls.set_margin(top, right, bottom, left);
+        ls.set_exclusive_zone(self.exclusive_zone(size));
+    }
+
+    /// The exclusive zone for a bar of the given size.  Following layer-shell: a positive zone
+    /// reserves that much space, 0 overlaps other windows but is still moved out of the way of
+    /// other bars' reserved space, and -1 also ignores other exclusive zones (for drawing over
+    /// another panel).  A floating bar does not reserve any space.
+    fn exclusive_zone(&self, size: u32) -> i32 {
+        match self.exclusive {
+            _ if self.floating => 0,
+            Some(v) => v,
+            None => size as i32,
+        }
+    }
+}
+
+/// The values of a bar's `output` item: the name, description, make, and model of the output the
+/// bar is shown on, along with its scale and logical size
+fn output_values(
+    [name, description, make, model]: [&str; 4],
+    scale: i32,
+    logical_size: Option<(i32, i32)>,
+    primary: Option<&toml::Value>,
+) -> toml::Value {
+    let mut info = toml::value::Table::new();
+    info.insert("name".into(), name.into());
+    info.insert("description".into(), description.into());
+    info.insert("make".into(), make.into());
+    info.insert("model".into(), model.into());
+    info.insert("scale".into(), (scale as i64).into());
+    if let Some((w, h)) = logical_size {
+        info.insert("width".into(), (w as i64).into());
+        info.insert("height".into(), (h as i64).into());
+    }
+    if let Some(primary) = primary {
+        info.insert("primary".into(), primary.clone());
+    }
+    toml::Value::Table(info)
+}
+
+/// Make the `bar` and `output` items of a bar visible to text expansions, see [Bar::set_items]
+fn set_bar_items(runtime: &mut Runtime, item: &Rc<Item>, output: &Rc<Item>) {
+    runtime.items.insert("bar".into(), item.clone());
+    runtime.items.insert("output".into(), output.clone());
+}
+
+/// The space (top, right, bottom, left) between a bar and the edges of the output
+type Margin = (i32, i32, i32, i32);
+
+/// Only handle input in the exclusive region; clicks in the overhang region will go through to
+/// the window we cover (hopefully transparently, to avoid confusion)
+fn add_click_region(region: &Region, size: u32, click_size: u32, docking: &Docking) {
+    let yoff = if docking.anchor_top {
+        0
+    } else {
+        size.saturating_sub(click_size) as i32
+    };
+    let (x, y, w, h) = surface_rect((0, yoff, i32::MAX, click_size as i32), docking, size);
+    region.add(x, y, w, h);
+}
+
+/// The size of a bar's surface along and across the bar
+fn bar_size(data: &SurfaceData, vertical: bool) -> (u32, u32) {
+    if vertical {
+        (data.height(), data.width())
+    } else {
+        (data.width(), data.height())
+    }
+}
+
+/// Convert a rectangle (x, y, width, height) in the bar's layout to one on its surface, which
+/// differ for a vertical bar
+fn surface_rect(
+    (x, y, w, h): (i32, i32, i32, i32),
+    docking: &Docking,
+    size: u32,
+) -> (i32, i32, i32, i32) {
+    if docking.vertical {
+        ((size as i32).saturating_sub(y).saturating_sub(h), x, h, w)
+    } else {
+        (x, y, w, h)
+    }
 }
 
 /// A single taskbar on a single output
@@ -30,10 +233,40 @@ pub struct Bar {
     pub popup: Option<BarPopup>,
     pub sink: EventSink,
     pub anchor_top: bool,
+    docking: Docking,
+    /// The docking keys and their last expanded values, if any of them use text expansions
+    docking_keys: Option<([Option<String>; 3], [Option<String>; 3])>,
+    size: u32,
     click_size: u32,
     sparse: bool,
+    opaque: bool,
+    click_feedback: bool,
+    /// Skip committing frames that are identical to the one already shown
+    skip_unchanged: bool,
+    pressed: Option<(Button, f32, f32)>,
+    /// Rectangles (x, y, width, height) that never receive input
+    click_through: Vec<(i32, i32, i32, i32)>,
+    hovered: Option<HoverKey>,
+    /// Open popups at the pointer instead of centered on the item
+    popup_at_pointer: bool,
+    /// Open popups above the bar instead of below it
+    pub popup_above: bool,
+    /// The configured popup side, or None to open them away from the bar's edge
+    popup_side: Option<bool>,
+    popup_timeout: Option<Duration>,
+    /// The cursor shown over items that can be clicked
+    clickable_cursor: Box<str>,
+    /// Length to use regardless of the size of the output, if `width` is set
+    fixed_width: Option<u32>,
+    margin: Margin,
     pub item: Rc<Item>,
+    /// Values describing the output, available as `{output.*}`
+    output: Rc<Item>,
+    /// The output's logical size and scale when this bar was created
+    pub output_mode: (Option<(i32, i32)>, i32),
     pub cfg_index: usize,
+    /// Shown on the primary output, as `{bar.primary}`
+    pub primary: bool,
     pub id: UID,
 }
 
@@ -45,28 +278,46 @@ impl Bar {
         cfg: toml::Value,
         cfg_index: usize,
     ) -> Bar {
-        let scale = output_data.scale_factor;
-        let layer = match cfg.get("layer").and_then(|v| v.as_str()) {
-            Some("overlay") => Layer::Overlay,
-            Some("bottom") => Layer::Bottom,
-            Some("top") | None => Layer::Top,
-            Some(layer) => {
-                error!("Unknown layer '{layer}', defaulting to top");
-                Layer::Top
+        // An integer scale from the config, either for all outputs or keyed by output name
+        let fixed_scale = match cfg.get("scale") {
+            Some(toml::Value::Table(t)) => output_data
+                .name
+                .as_deref()
+                .and_then(|name| t.get(name))
+                .and_then(|v| v.as_integer()),
+            Some(v) => v.as_integer(),
+            None => None,
+        }
+        .and_then(|v| match v {
+            1..=16 => Some(v as i32),
+            _ => {
+                error!("Invalid scale {}, using the output's scale", v);
+                None
             }
-        };
+        });
+        // Rendering at a fraction of the output's scale leaves the compositor to upscale the
+        // bar, which is blurrier but needs less drawing on high-resolution outputs
+        let fixed_scale = fixed_scale.or_else(|| {
+            let mut factor = toml_to_f64(cfg.get("render-scale"))?;
+            if !(0.25..=1.0).contains(&factor) {
+                error!("Invalid render-scale {}, clamping to 0.25-1", factor);
+                factor = factor.clamp(0.25, 1.0);
+            }
+            let scale = (output_data.scale_factor as f64 * factor).round() as i32;
+            Some(scale.max(1))
+        });
+        let scale = fixed_scale.unwrap_or(output_data.scale_factor);
         let size = cfg
             .get("size")
             .and_then(|v| v.as_integer())
             .filter(|&v| v > 0 && v < i32::MAX as _)
             .and_then(|v| v.try_into().ok())
             .unwrap_or(20);
-        let size_excl = cfg
-            .get("size-exclusive")
-            .and_then(|v| v.as_integer())
-            .filter(|&v| v >= -1 && v < i32::MAX as _)
-            .and_then(|v| v.try_into().ok())
-            .unwrap_or(size as i32);
+        // Keys using text expansions take effect on the first render
+        let docking_keys = DOCKING_KEYS.map(|key| toml_to_string(cfg.get(key)));
+        let literal = docking_keys.clone().map(|k| k.filter(|k| !k.contains('{')));
+        let docking = Docking::from_keys(&literal);
+        let size_excl = docking.exclusive.unwrap_or(size as i32);
         let click_size = cfg
             .get("size-clickable")
             .and_then(|v| v.as_integer())
@@ -74,76 +325,316 @@ impl Bar {
             .and_then(|v| v.try_into().ok())
             .or_else(|| size_excl.try_into().ok().filter(|&v| v > 0))
             .unwrap_or(size);
-        let anchor_top = match cfg.get("side").and_then(|v| v.as_str()) {
-            Some("top") => true,
-            None | Some("bottom") => false,
-            Some(side) => {
-                error!("Unknown side '{}', defaulting to bottom", side);
-                false
+        let fixed_width: Option<u32> = cfg
+            .get("width")
+            .or_else(|| cfg.get("length"))
+            .and_then(|v| v.as_integer())
+            .filter(|&v| v > 0 && v < i32::MAX as _)
+            .and_then(|v| v.try_into().ok());
+        let fixed_width = match fixed_width {
+            None if docking.floating => {
+                error!("A floating bar (side = \"center\") requires a width; using 400");
+                Some(400)
             }
+            w => w,
+        };
+        let anchor_top = docking.anchor_top;
+        let margin = match toml_to_string(cfg.get("margin")) {
+            None => (0, 0, 0, 0),
+            Some(v) => match Formatting::parse_trbl(v.as_str().into()) {
+                Some((t, r, b, l)) => (t as i32, r as i32, b as i32, l as i32),
+                None => {
+                    error!("Invalid margin '{}', using 0", v);
+                    (0, 0, 0, 0)
+                }
+            },
         };
 
-        let surf = wayland.create_surface(scale);
+        let surf = wayland.create_surface(scale, fixed_scale);
         let ls = wayland.layer.create_layer_surface(
             &wayland.queue,
             surf,
-            layer,
+            docking.layer,
             Some("bar"),
             Some(output),
         );
-        ls.set_size(0, size);
-        ls.set_anchor(if anchor_top {
-            Anchor::TOP | Anchor::LEFT | Anchor::RIGHT
-        } else {
-            Anchor::BOTTOM | Anchor::LEFT | Anchor::RIGHT
-        });
-        ls.set_exclusive_zone(size_excl);
+        docking.apply(&ls, fixed_width, size, margin);
+        // rwaybar does not use the keyboard itself, but this lets an overlay bar keep (or not
+        // take) keyboard focus from the windows below it
+        let keyboard = match cfg.get("keyboard").and_then(|v| v.as_str()) {
+            None | Some("none") => KeyboardInteractivity::None,
+            Some("on-demand") => KeyboardInteractivity::OnDemand,
+            Some("exclusive") => KeyboardInteractivity::Exclusive,
+            Some(v) => {
+                error!("Unknown keyboard mode '{}', defaulting to none", v);
+                KeyboardInteractivity::None
+            }
+        };
+        ls.set_keyboard_interactivity(keyboard);
         let sparse = cfg
             .get("sparse-clicks")
             .and_then(|v| v.as_bool())
             .unwrap_or(true);
-        if size != click_size {
-            // Only handle input in the exclusive region; clicks in the overhang region will go
-            // through to the window we cover (hopefully transparently, to avoid confusion)
+        let click_feedback = cfg
+            .get("click-feedback")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        let skip_unchanged = cfg
+            .get("skip-unchanged")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        let popup_at_pointer = match cfg
+            .get("popup")
+            .and_then(|v| v.get("anchor"))
+            .and_then(|v| v.as_str())
+        {
+            Some("pointer") => true,
+            None | Some("item") => false,
+            Some(anchor) => {
+                error!("Unknown popup anchor '{}', defaulting to item", anchor);
+                false
+            }
+        };
+        // By default, popups open away from the edge of the screen the bar is on
+        let popup_side = match cfg
+            .get("popup")
+            .and_then(|v| v.get("side"))
+            .and_then(|v| v.as_str())
+        {
+            Some("above") => Some(true),
+            Some("below") => Some(false),
+            None | Some("auto") => None,
+            Some(side) => {
+                error!("Unknown popup side '{}', defaulting to auto", side);
+                None
+            }
+        };
+        let popup_timeout = match cfg.get("popup").and_then(|v| v.get("timeout")) {
+            None => Some(POPUP_TIMEOUT),
+            Some(v) => match toml_to_f64(Some(v)) {
+                Some(t) if t > 0.0 && t < 1e9 => Some(Duration::from_secs_f64(t)),
+                Some(_) => None,
+                None => {
+                    error!("Invalid popup timeout, using {:?}", POPUP_TIMEOUT);
+                    Some(POPUP_TIMEOUT)
+                }
+            },
+        };
+        let clickable_cursor = cfg
+            .get("cursor")
+            .and_then(|v| v.as_str())
+            .unwrap_or("pointer")
+            .into();
+        let click_through: Vec<_> = cfg
+            .get("click-through")
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|rect| {
+                let v: Vec<_> = rect
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|v| v.as_integer())
+                    .filter_map(|v| i32::try_from(v).ok())
+                    .collect();
+                match v[..] {
+                    [x, y, w, h] => Some((x, y, w, h)),
+                    [x, w] => Some((x, 0, w, i32::MAX)),
+                    _ => {
+                        error!(
+                            "Invalid click-through region {}, expected [x, y, width, height]",
+                            rect
+                        );
+                        None
+                    }
+                }
+            })
+            .collect();
+        if size != click_size || !click_through.is_empty() {
             let region = Region::new(&wayland.compositor).unwrap();
-            let yoff = if anchor_top {
-                0
-            } else {
-                size.saturating_sub(click_size) as i32
-            };
             if sparse {
                 // start with an empty region to match the empty EventSink
             } else {
-                region.add(0, yoff, i32::MAX, click_size as i32);
+                add_click_region(&region, size, click_size, &docking);
+            }
+            for &rect in &click_through {
+                let (x, y, w, h) = surface_rect(rect, &docking, size);
+                region.subtract(x, y, w, h);
             }
             ls.wl_surface().set_input_region(Some(&region.wl_region()));
         }
         ls.wl_surface().set_buffer_scale(scale);
         ls.wl_surface().commit();
 
+        // Each bar has its own copy, so a bar shown on several outputs shows each one's values
+        let names: [&str; 4] = [
+            output_data.name.as_deref().unwrap_or_default(),
+            output_data.description.as_deref().unwrap_or_default(),
+            &output_data.make,
+            &output_data.model,
+        ];
+        let output_info = output_values(names, scale, output_data.logical_size, cfg.get("primary"));
+        let output = Rc::new(Item::new_bar(output_info));
+
         Bar {
             name: output_data.name.clone().unwrap_or_default().into(),
             ls,
             item: Rc::new(Item::new_bar(cfg)),
+            output,
             click_size,
             anchor_top,
+            docking,
+            docking_keys: docking_keys
+                .iter()
+                .flatten()
+                .any(|k| k.contains('{'))
+                .then(|| (docking_keys, literal)),
+            size,
             sink: EventSink::default(),
             sparse,
+            opaque: false,
+            click_feedback,
+            skip_unchanged,
+            pressed: None,
+            click_through,
+            hovered: None,
+            popup_at_pointer,
+            popup_above: popup_side.unwrap_or(!anchor_top),
+            popup_side,
+            popup_timeout,
+            clickable_cursor,
+            fixed_width,
+            margin,
             popup: None,
+            output_mode: (output_data.logical_size, output_data.scale_factor),
             cfg_index,
+            primary: false,
             id: UID::new(),
         }
     }
 
+    /// Move the bar if any of its docking keys expand to something new
+    fn update_docking(&mut self, runtime: &Runtime) {
+        let (keys, last) = match &mut self.docking_keys {
+            Some(keys) => keys,
+            None => return,
+        };
+        let mut values: [Option<String>; 3] = Default::default();
+        for (key, value) in keys.iter().zip(&mut values) {
+            *value = key
+                .as_deref()
+                .map(|key| runtime.format_or(key, "bar").into_text().into_owned())
+                .filter(|v| !v.is_empty());
+        }
+        if values == *last {
+            return;
+        }
+        let mut docking = Docking::from_keys(&values);
+        *last = values;
+        if docking == self.docking {
+            return;
+        }
+        let fixed_width = self.fixed_width;
+        if docking.floating && fixed_width.is_none() {
+            error!("A floating bar (side = \"center\") requires a width");
+            docking.floating = false;
+        }
+        docking.apply(&self.ls, fixed_width, self.size, self.margin);
+        self.anchor_top = docking.anchor_top;
+        self.popup_above = self.popup_side.unwrap_or(!self.anchor_top);
+        self.docking = docking;
+        if !self.sparse && (self.size != self.click_size || !self.click_through.is_empty()) {
+            let region = Region::new(&runtime.wayland.compositor).unwrap();
+            add_click_region(&region, self.size, self.click_size, &self.docking);
+            for &rect in &self.click_through {
+                let (x, y, w, h) = surface_rect(rect, &self.docking, self.size);
+                region.subtract(x, y, w, h);
+            }
+            self.ls
+                .wl_surface()
+                .set_input_region(Some(region.wl_region()));
+        }
+        // recalculate a sparse input region on the next render
+        self.sink = EventSink::default();
+        self.popup = None;
+        self.ls.wl_surface().commit();
+    }
+
+    /// The surface size to keep regardless of the size of the output, if `width` is set
+    pub fn fixed_size(&self) -> Option<(u32, u32)> {
+        let length = self.fixed_width?;
+        Some(if self.docking.vertical {
+            (self.size, length)
+        } else {
+            (length, self.size)
+        })
+    }
+
+    pub fn vertical(&self) -> bool {
+        self.docking.vertical
+    }
+
+    /// Convert a position on the surface to one in the bar's layout
+    fn bar_pos(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        if self.docking.vertical {
+            let width = SurfaceData::from_wl(self.ls.wl_surface()).width();
+            (y, width as f64 - x)
+        } else {
+            (x, y)
+        }
+    }
+
+    /// Make this bar's `bar` and `output` items visible to text expansions
+    pub fn set_items(&self, runtime: &mut Runtime) {
+        set_bar_items(runtime, &self.item, &self.output);
+    }
+
     pub fn render_with(&mut self, runtime: &mut Runtime, renderer: &mut Renderer) {
-        runtime.items.insert("bar".into(), self.item.clone());
+        self.set_items(runtime);
+        self.update_docking(runtime);
 
         let surface_data = SurfaceData::from_wl(self.ls.wl_surface());
         if surface_data.start_render() {
             let surf = self.ls.wl_surface();
-            renderer.render(runtime, surf, |ctx| {
+            let vertical = self.docking.vertical;
+            runtime.set_hovered(self.hovered.clone());
+            renderer.render(runtime, surf, vertical, self.skip_unchanged, |ctx| {
                 let new_sink = ctx.runtime.items["bar"].render(ctx);
 
+                if let Some((_, min, max)) = self.pressed {
+                    let (width, height) = bar_size(surface_data, vertical);
+                    let (width, height) = (width as f32, height as f32);
+                    if let Some(rect) =
+                        tiny_skia::Rect::from_ltrb(min.max(0.0), 0.0, max.min(width), height)
+                    {
+                        let paint = tiny_skia::Paint {
+                            shader: tiny_skia::Shader::SolidColor(
+                                tiny_skia::Color::from_rgba8(0, 0, 0, 64),
+                            ),
+                            ..Default::default()
+                        };
+                        ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
+                    }
+                }
+
+                // Let the compositor skip drawing what is behind us if we covered everything.  An
+                // opaque background does; otherwise, check what was drawn.
+                let opaque = ctx.runtime.items["bar"].format.has_opaque_bg(ctx.runtime)
+                    || ctx.canvas.data_mut().chunks_exact(4).all(|px| px[3] == 255);
+                if opaque != self.opaque {
+                    if opaque {
+                        let region = Region::new(&ctx.runtime.wayland.compositor).unwrap();
+                        region.add(0, 0, i32::MAX, i32::MAX);
+                        surf.set_opaque_region(Some(region.wl_region()));
+                    } else {
+                        surf.set_opaque_region(None);
+                    }
+                    // the region is only applied by a commit
+                    surface_data.force_commit();
+                    self.opaque = opaque;
+                }
+
                 if self.sparse {
                     let mut old_regions = Vec::new();
                     let mut new_regions = Vec::new();
@@ -156,23 +647,36 @@ impl Bar {
 
                     if old_regions != new_regions {
                         let region = Region::new(&ctx.runtime.wayland.compositor).unwrap();
+                        let height = bar_size(surface_data, vertical).1;
                         let yoff = if self.anchor_top {
                             0
                         } else {
-                            surface_data.height().saturating_sub(self.click_size) as i32
+                            height.saturating_sub(self.click_size) as i32
                         };
+                        let click_size = self.click_size as i32;
                         for (lo, len) in new_regions {
-                            region.add(lo, yoff, len, self.click_size as i32);
+                            let rect = (lo, yoff, len, click_size);
+                            let (x, y, w, h) = surface_rect(rect, &self.docking, height);
+                            region.add(x, y, w, h);
+                        }
+                        for &rect in &self.click_through {
+                            let (x, y, w, h) = surface_rect(rect, &self.docking, height);
+                            region.subtract(x, y, w, h);
                         }
                         surf.set_input_region(Some(region.wl_region()));
+                        surface_data.force_commit();
                     }
                 }
                 self.sink = new_sink;
             });
+            runtime.set_hovered(None);
         }
         if let Some(popup) = &mut self.popup {
             if popup.vanish.map_or(false, |vanish| vanish < Instant::now()) {
                 self.popup = None;
+            } else if popup.idle_expired(runtime) {
+                // the compositor may not have told us that the pointer left
+                self.popup = None;
             }
         }
         let mut scale = 1;
@@ -188,7 +692,9 @@ impl Bar {
 
         if let Some(popup) = popup {
             if let Some(new_size) =
-                renderer.render(runtime, &popup.wl.surf, |ctx| popup.desc.render_popup(ctx))
+                renderer.render(runtime, &popup.wl.surf, false, self.skip_unchanged, |ctx| {
+                    popup.desc.render_popup(ctx)
+                })
             {
                 if new_size.0 > popup.wl.req_size.0
                     || new_size.1 > popup.wl.req_size.1
@@ -205,47 +711,104 @@ impl Bar {
             }
         }
     }
-}
 
-impl SurfaceEvents for Bar {
-    fn hover(&mut self, (x, y): (f64, f64), runtime: &mut Runtime) {
+    /// Open the popup of the item at this position after the pointer moved there or it was
+    /// clicked.  Popups that open on click (the tray drawer) are closed by a second click.
+    fn show_popup(&mut self, x: f64, y: f64, clicked: bool, runtime: &mut Runtime) {
         if let Some((min_x, max_x, desc)) = self.sink.get_hover(x as f32, y as f32) {
+            let on_click = desc.opens_on_click();
+            if clicked && !on_click {
+                return;
+            }
             if let Some(popup) = &mut self.popup {
-                if x < popup.wl.anchor.0 as f64
-                    || x > (popup.wl.anchor.0 + popup.wl.anchor.2) as f64
-                {
+                if x < popup.region.0 as f64 || x > popup.region.1 as f64 {
                     self.popup = None;
                 } else if popup.desc == *desc {
+                    if clicked {
+                        self.popup = None;
+                    } else {
+                        popup.touch();
+                    }
                     return;
                 } else {
                     self.popup = None;
                 }
             }
+            if on_click && !clicked {
+                return;
+            }
             let surf_data = SurfaceData::from_wl(self.ls.wl_surface());
-            let anchor = (
-                min_x as i32,
-                0,
-                (max_x - min_x) as i32,
-                surf_data.height() as i32,
-            );
+            let height = bar_size(surf_data, self.docking.vertical).1;
+            let anchor = if self.popup_at_pointer {
+                // the compositor will still slide or flip the popup to keep it on-screen
+                (x as i32, 0, 1, height as i32)
+            } else {
+                (min_x as i32, 0, (max_x - min_x) as i32, height as i32)
+            };
+            let anchor = surface_rect(anchor, &self.docking, height);
 
+            // not set_items, since desc still borrows the sink
             runtime.items.insert("bar".into(), self.item.clone());
+            runtime.items.insert("output".into(), self.output.clone());
             let size = Renderer::render_dummy(runtime, |ctx| desc.render_popup(ctx));
             if size.0 <= 0 || size.1 <= 0 {
                 return;
             }
             let desc = desc.clone();
 
-            let popup = BarPopup {
+            let mut popup = BarPopup {
                 wl: Popup::on_bar(&mut runtime.wayland, self, anchor, size),
                 desc,
                 vanish: None,
+                region: (min_x, max_x),
+                timeout: self.popup_timeout,
+                idle_until: None,
+                idle_timer: None,
             };
+            popup.touch();
+            popup.idle_expired(runtime);
             self.popup = Some(popup);
         }
     }
 
+    /// Run the actions for a click on the bar, and open or close a popup that opens on click
+    fn click(&mut self, x: f64, y: f64, button: Button, runtime: &mut Runtime) {
+        self.sink.button(x as f32, y as f32, button, runtime);
+        if matches!(button, Button::Left | Button::Tap) {
+            self.show_popup(x, y, true, runtime);
+        }
+    }
+}
+
+impl SurfaceEvents for Bar {
+    fn cursor(&self, pos: (f64, f64)) -> &str {
+        let (x, _) = self.bar_pos(pos);
+        if self.sink.clickable(x as f32) {
+            &*self.clickable_cursor
+        } else {
+            "default"
+        }
+    }
+
+    fn hover(&mut self, pos: (f64, f64), runtime: &mut Runtime) {
+        let (x, y) = self.bar_pos(pos);
+        let hovered = self.sink.get_hover_style(x as f32).cloned();
+        if hovered != self.hovered {
+            self.hovered = hovered;
+            // redraw just this bar
+            let mut notify = NotifierList::default();
+            notify.add(runtime);
+            notify.notify_data("bar-hover-style");
+        }
+        self.show_popup(x, y, false, runtime);
+    }
+
     fn no_hover(&mut self, runtime: &mut Runtime) {
+        if self.hovered.take().is_some() {
+            let mut notify = NotifierList::default();
+            notify.add(runtime);
+            notify.notify_data("bar-hover-style");
+        }
         if let Some(popup) = &mut self.popup {
             let vanish = Instant::now() + std::time::Duration::from_millis(100);
             popup.vanish = Some(vanish);
@@ -257,17 +820,53 @@ impl SurfaceEvents for Bar {
         }
     }
 
-    fn button(&mut self, (x, y): (f64, f64), button: Button, runtime: &mut Runtime) {
-        self.sink.button(x as f32, y as f32, button, runtime);
+    fn button(&mut self, pos: (f64, f64), button: Button, runtime: &mut Runtime) {
+        let (x, y) = self.bar_pos(pos);
+        self.click(x, y, button, runtime);
+    }
+
+    fn press(&mut self, pos: (f64, f64), button: Button, runtime: &mut Runtime) {
+        let (x, y) = self.bar_pos(pos);
+        if !self.click_feedback {
+            self.click(x, y, button, runtime);
+            return;
+        }
+        // Show the item as pressed; the action runs when the button is released
+        if let Some((min, max)) = self.sink.get_click_region(x as f32, button) {
+            self.pressed = Some((button, min, max));
+            SurfaceData::from_wl(self.ls.wl_surface()).damage_full();
+        }
+    }
+
+    fn release(&mut self, pos: (f64, f64), button: Button, runtime: &mut Runtime) {
+        let (x, y) = self.bar_pos(pos);
+        match self.pressed {
+            Some((pressed, min, max)) if pressed == button => {
+                self.pressed = None;
+                SurfaceData::from_wl(self.ls.wl_surface()).damage_full();
+                // releasing outside the item cancels the click
+                if x as f32 >= min && x as f32 <= max {
+                    self.click(x, y, button, runtime);
+                }
+            }
+            _ => {}
+        }
     }
 }
 
 impl SurfaceEvents for BarPopup {
-    fn hover(&mut self, _: (f64, f64), _: &mut Runtime) {
+    fn hover(&mut self, pos: (f64, f64), runtime: &mut Runtime) {
         self.vanish = None;
+        self.touch();
+        if self.desc.hover(Some(pos)) {
+            let mut notify = NotifierList::default();
+            notify.add(runtime);
+            notify.notify_data("popup-highlight");
+        }
     }
 
     fn no_hover(&mut self, runtime: &mut Runtime) {
+        self.desc.hover(None);
         let vanish = Instant::now() + std::time::Duration::from_millis(100);
         self.vanish = Some(vanish);
         let mut notify = NotifierList::active(runtime);
@@ -278,6 +877,120 @@ impl SurfaceEvents for BarPopup {
     }
 
     fn button(&mut self, (x, y): (f64, f64), button: Button, runtime: &mut Runtime) {
+        self.touch();
         self.desc.button(x, y, button, runtime);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::FontMapped;
+    use crate::render::{Align, Render};
+    use tiny_skia::Point;
+
+    fn zone(side: &str, exclusive: Option<&str>) -> i32 {
+        let keys = [Some(side.into()), None, exclusive.map(Into::into)];
+        Docking::from_keys(&keys).exclusive_zone(30)
+    }
+
+    /// The value of a key in the `output` item of a bar
+    fn output_value(item: &Item, key: &str) -> Option<toml::Value> {
+        match &item.data {
+            crate::data::Module::Bar { config, .. } => config.get(key).cloned(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn output_per_bar() {
+        // one bar config shown on two outputs
+        let primary = toml::Value::Boolean(true);
+        let left = ["DP-1", "Left", "Dell", "U2720Q"];
+        let right = ["HDMI-A-1", "Right", "LG", "27UL"];
+        let outputs = [
+            output_values(left, 2, Some((1920, 1080)), None),
+            output_values(right, 1, None, Some(&primary)),
+        ];
+        let bars = outputs.map(|info| Rc::new(Item::new_bar(info)));
+        assert!(!Rc::ptr_eq(&bars[0], &bars[1]));
+        assert_eq!(output_value(&bars[0], "name"), Some("DP-1".into()));
+        assert_eq!(output_value(&bars[1], "name"), Some("HDMI-A-1".into()));
+        assert_eq!(output_value(&bars[0], "scale"), Some(2.into()));
+        assert_eq!(output_value(&bars[0], "width"), Some(1920.into()));
+        assert_eq!(output_value(&bars[1], "width"), None);
+        assert_eq!(output_value(&bars[0], "primary"), None);
+        assert_eq!(output_value(&bars[1], "primary"), Some(primary));
+
+        // each bar shows its own output while it is drawn
+        let mut runtime = Runtime::new_test();
+        let bar = Rc::new(Item::new_bar(toml::Value::Table(Default::default())));
+        for (output, shown) in bars
+            .iter()
+            .zip([("DP-1", "DP-1 at 2x"), ("HDMI-A-1", "HDMI-A-1 at 1x")])
+        {
+            set_bar_items(&mut runtime, &bar, output);
+            let name = runtime.format("{output.name}").unwrap();
+            assert_eq!(name.into_text(), shown.0);
+            let text = runtime.format("{output.name} at {output.scale}x").unwrap();
+            assert_eq!(text.into_text(), shown.1);
+        }
+    }
+
+    #[test]
+    fn opaque_background() {
+        let runtime = Runtime::new_test();
+        let bar = |cfg: &str| {
+            let cfg = toml::from_str(cfg).unwrap();
+            Item::new_bar(cfg).format.has_opaque_bg(&runtime)
+        };
+        assert!(bar("bg = '#123456'"));
+        assert!(bar("bg = '#123456'\nborder = '1'\nborder-color = 'red'"));
+        assert!(!bar("bg = '#123456'\nbg-alpha = 0.5"));
+        assert!(!bar("bg = '#1234'"));
+        // the border is drawn in the text color unless it is given
+        assert!(!bar("bg = '#123456'\nborder = '1'"));
+        assert!(!bar(""));
+
+        // and the background does cover the whole bar
+        let path = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+        let font = match FontMapped::new("sans".into(), path.into()) {
+            Ok(font) => font,
+            Err(e) => return eprintln!("Skipping, could not load {}: {}", path, e),
+        };
+        let cfg = toml::toml! {
+            bg = "#123456"
+            center = { format = "12:34" }
+        };
+        let mut pixmap = tiny_skia::Pixmap::new(300, 30).unwrap();
+        let mut ctx = Render {
+            canvas: &mut pixmap.as_mut(),
+            cache: &runtime.cache,
+            render_xform: tiny_skia::Transform::from_scale(1.5, 1.5),
+            render_extents: (Point::zero(), Point::from_xy(200.0, 20.0)),
+            render_pos: Point::zero(),
+            render_flex: false,
+            font: &font,
+            font_size: 12.0,
+            font_color: tiny_skia::Color::WHITE,
+            text_stroke: None,
+            text_stroke_size: None,
+            align: Align::bar_default(),
+            err_name: "bar",
+            runtime: &runtime,
+        };
+        Rc::new(Item::new_bar(cfg.into())).render(&mut ctx);
+        assert!(pixmap.pixels().iter().all(|px| px.alpha() == 255));
+    }
+
+    #[test]
+    fn exclusive_zone() {
+        assert_eq!(zone("top", None), 30);
+        assert_eq!(zone("top", Some("12")), 12);
+        assert_eq!(zone("bottom", Some("0")), 0);
+        assert_eq!(zone("bottom", Some("-1")), -1);
+        // anything below -1 is not valid in layer-shell
+        assert_eq!(zone("top", Some("-2")), 30);
+        assert_eq!(zone("center", Some("12")), 0);
+    }
+}
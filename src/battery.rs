@@ -0,0 +1,200 @@
+//! Battery status read directly from /sys/class/power_supply
+use crate::data::{Periodic, Value};
+use crate::state::Runtime;
+use crate::util::{toml_to_f64, toml_to_string, Cell};
+use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const SYSFS_PATH: &str = "/sys/class/power_supply/";
+
+/// The combined status of one or more batteries.  This is also used by the upower module when
+/// UPower is not running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub percent: f64,
+    /// One of charging, discharging, full, not-charging, or unknown
+    pub status: &'static str,
+    /// Seconds until empty (when discharging) or full (when charging), if known
+    pub time: Option<f64>,
+    /// The model name of the first battery that reports one
+    pub model: Option<Rc<str>>,
+}
+
+impl Reading {
+    /// Read one power supply (such as "BAT0"), or all batteries if the device is empty
+    pub fn read(device: &str) -> Option<Self> {
+        let dirs = if device.is_empty() {
+            find_batteries()
+        } else {
+            vec![PathBuf::from(SYSFS_PATH).join(device)]
+        };
+
+        // Energy is in µWh and power in µW, or charge in µAh and current in µA; the times
+        // come out the same either way
+        let mut now = 0.0;
+        let mut full = 0.0;
+        let mut rate = 0.0;
+        let mut capacity = Vec::new();
+        let mut statuses = Vec::new();
+        let mut model = None;
+        for dir in &dirs {
+            let read = |file: &str| {
+                fs::read_to_string(dir.join(file))
+                    .ok()
+                    .map(|s| s.trim_end().to_owned())
+            };
+            let num = |file: &str| read(file).and_then(|s| s.parse::<f64>().ok());
+            if read("present").as_deref() == Some("0") {
+                continue;
+            }
+            let status = match read("status") {
+                Some(status) => status,
+                None => {
+                    debug!("Could not read the status of {}", dir.display());
+                    continue;
+                }
+            };
+            statuses.push(status);
+            if model.is_none() {
+                model = read("model_name").map(Rc::from);
+            }
+            let level = num("energy_now").or_else(|| num("charge_now"));
+            let max = num("energy_full").or_else(|| num("charge_full"));
+            if let (Some(level), Some(max)) = (level, max) {
+                now += level;
+                full += max;
+            }
+            if let Some(c) = num("capacity") {
+                capacity.push(c);
+            }
+            rate += num("power_now")
+                .or_else(|| num("current_now"))
+                .map_or(0.0, f64::abs);
+        }
+        if statuses.is_empty() {
+            return None;
+        }
+
+        let percent = if full > 0.0 {
+            (now / full * 100.0).min(100.0)
+        } else if !capacity.is_empty() {
+            capacity.iter().sum::<f64>() / capacity.len() as f64
+        } else {
+            return None;
+        };
+        // With several batteries, one may be charging or discharging while the others are idle
+        let status = if statuses.iter().any(|s| s == "Charging") {
+            "charging"
+        } else if statuses.iter().any(|s| s == "Discharging") {
+            "discharging"
+        } else if statuses.iter().all(|s| s == "Full") {
+            "full"
+        } else if statuses.iter().any(|s| s == "Not charging") {
+            "not-charging"
+        } else {
+            "unknown"
+        };
+        let time = match status {
+            _ if rate <= 0.0 || full <= 0.0 => None,
+            "charging" => Some((full - now).max(0.0) / rate * 3600.0),
+            "discharging" => Some(now / rate * 3600.0),
+            _ => None,
+        };
+        Some(Reading {
+            percent,
+            status,
+            time,
+            model,
+        })
+    }
+}
+
+/// All power supplies that are batteries, in name order
+fn find_batteries() -> Vec<PathBuf> {
+    let dir = match fs::read_dir(SYSFS_PATH) {
+        Ok(dir) => dir,
+        Err(e) => {
+            debug!("Could not read {}: {}", SYSFS_PATH, e);
+            return Vec::new();
+        }
+    };
+    let is_battery = |path: &Path| {
+        fs::read_to_string(path.join("type")).map_or(false, |t| t.trim_end() == "Battery")
+    };
+    let mut rv: Vec<_> = dir
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| is_battery(path))
+        .collect();
+    rv.sort();
+    rv
+}
+
+/// Format a duration in seconds as H:MM
+fn format_time(secs: f64) -> String {
+    let mins = (secs / 60.0).round() as u64;
+    format!("{}:{:02}", mins / 60, mins % 60)
+}
+
+#[derive(Debug)]
+pub struct Battery {
+    device: Box<str>,
+    poll: Periodic<Cell<Option<Reading>>>,
+}
+
+impl Battery {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        Battery {
+            device: toml_to_string(config.get("device"))
+                .unwrap_or_default()
+                .into(),
+            poll: Periodic::new(
+                toml_to_f64(config.get("poll")).unwrap_or(30.0),
+                Cell::default(),
+            ),
+        }
+    }
+
+    pub fn carry_over(&self, old: &Self) {
+        if self.device == old.device {
+            self.poll.carry_over(&old.poll);
+        }
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        let device = self.device.clone();
+        self.poll.read_refresh(rt, move |reading| {
+            let new = Reading::read(&device);
+            if reading.replace(new.clone()) == new {
+                None
+            } else {
+                Some("battery")
+            }
+        });
+        let reading = match self.poll.data().take_in(|r| r.clone()) {
+            Some(reading) => reading,
+            None => return f(Value::Null),
+        };
+        match key {
+            "" | "percent" => f(Value::Float(reading.percent)),
+            "status" => f(Value::Borrow(reading.status)),
+            "time" => match reading.time {
+                Some(secs) => f(Value::Owned(format_time(secs))),
+                None => f(Value::Null),
+            },
+            "seconds" => match reading.time {
+                Some(secs) => f(Value::Float(secs.round())),
+                None => f(Value::Null),
+            },
+            _ => f(Value::Null),
+        }
+    }
+}
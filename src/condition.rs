@@ -0,0 +1,216 @@
+//! Conditions used by `condition` and `blink`, with comparisons and boolean logic
+//!
+//! The operators are only recognized outside of `{}` expansions and quoted strings, so a value
+//! that happens to contain one (such as a window title) is never parsed as part of the condition.
+use crate::data::Value;
+use crate::state::Runtime;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    Text(&'a str),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Cmp(Cmp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+const OPERATORS: &[(&str, Token<'static>)] = &[
+    ("&&", Token::And),
+    ("||", Token::Or),
+    ("==", Token::Cmp(Cmp::Eq)),
+    ("!=", Token::Cmp(Cmp::Ne)),
+    ("<=", Token::Cmp(Cmp::Le)),
+    (">=", Token::Cmp(Cmp::Ge)),
+    ("<", Token::Cmp(Cmp::Lt)),
+    (">", Token::Cmp(Cmp::Gt)),
+    ("!", Token::Not),
+    ("(", Token::Open),
+    (")", Token::Close),
+];
+
+fn tokenize(cond: &str) -> Result<Vec<Token>, String> {
+    let mut rv = Vec::new();
+    let mut depth = 0;
+    let mut quote = false;
+    let mut start = 0;
+    let mut i = 0;
+    let bytes = cond.as_bytes();
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' if depth == 0 => quote = !quote,
+            b'{' if !quote => depth += 1,
+            b'}' if !quote && depth > 0 => depth -= 1,
+            b if quote || depth > 0 || !b.is_ascii() => {}
+            _ => {
+                let found = OPERATORS.iter().find(|(op, _)| cond[i..].starts_with(*op));
+                if let Some((op, token)) = found {
+                    let text = cond[start..i].trim();
+                    if !text.is_empty() {
+                        rv.push(Token::Text(text));
+                    }
+                    rv.push(*token);
+                    i += op.len();
+                    start = i;
+                    continue;
+                }
+            }
+        }
+        i += 1;
+    }
+    if quote {
+        return Err("Unterminated string".into());
+    }
+    let text = cond[start..].trim();
+    if !text.is_empty() {
+        rv.push(Token::Text(text));
+    }
+    Ok(rv)
+}
+
+/// Empty values, zero, and false values from modules are false; anything else is true
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Float(f) => *f != 0.0,
+        Value::Bool(b) => *b,
+        Value::Null => false,
+        v => {
+            let text = v.as_str_fast().trim();
+            !text.is_empty() && text.parse::<f64>().map_or(true, |n| n != 0.0)
+        }
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token<'a>],
+    pos: usize,
+    rt: &'a Runtime,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let rv = self.peek();
+        self.pos += 1;
+        rv
+    }
+
+    // Each level takes `eval`, which is false on the side of && or || that is skipped so that
+    // values that do not matter are not read
+
+    fn or(&mut self, eval: bool) -> Result<bool, String> {
+        let mut rv = self.and(eval)?;
+        while self.peek() == Some(Token::Or) {
+            self.pos += 1;
+            rv |= self.and(eval && !rv)?;
+        }
+        Ok(rv)
+    }
+
+    fn and(&mut self, eval: bool) -> Result<bool, String> {
+        let mut rv = self.unary(eval)?;
+        while self.peek() == Some(Token::And) {
+            self.pos += 1;
+            rv &= self.unary(eval && rv)?;
+        }
+        Ok(rv)
+    }
+
+    fn unary(&mut self, eval: bool) -> Result<bool, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(!self.unary(eval)?)
+            }
+            Some(Token::Open) => {
+                self.pos += 1;
+                let rv = self.or(eval)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(rv),
+                    _ => Err("Missing ')'".into()),
+                }
+            }
+            _ => self.comparison(eval),
+        }
+    }
+
+    fn comparison(&mut self, eval: bool) -> Result<bool, String> {
+        let lhs = self.operand(eval)?;
+        let op = match self.peek() {
+            Some(Token::Cmp(op)) => op,
+            _ => return Ok(truthy(&lhs)),
+        };
+        self.pos += 1;
+        let rhs = self.operand(eval)?;
+        if !eval {
+            return Ok(false);
+        }
+        // compare as numbers if both sides are numbers, otherwise as strings
+        let ord = match (lhs.parse_f64(), rhs.parse_f64()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => Some(lhs.into_text().cmp(&rhs.into_text())),
+        };
+        let ord = match ord {
+            Some(ord) => ord,
+            None => return Ok(op == Cmp::Ne),
+        };
+        Ok(match op {
+            Cmp::Eq => ord.is_eq(),
+            Cmp::Ne => ord.is_ne(),
+            Cmp::Lt => ord.is_lt(),
+            Cmp::Le => ord.is_le(),
+            Cmp::Gt => ord.is_gt(),
+            Cmp::Ge => ord.is_ge(),
+        })
+    }
+
+    fn operand(&mut self, eval: bool) -> Result<Value<'a>, String> {
+        let text = match self.next() {
+            Some(Token::Text(text)) => text,
+            Some(t) => return Err(format!("Unexpected {:?}", t)),
+            None => return Err("Missing value".into()),
+        };
+        if !eval {
+            return Ok(Value::Null);
+        }
+        // quotes allow a string to contain spaces or operators
+        let text = text
+            .strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .unwrap_or(text);
+        self.rt.format(text).map_err(|e| e.to_string())
+    }
+}
+
+/// Evaluate a condition.  Values (including `{}` expansions) may be compared using `==`, `!=`,
+/// `<`, `<=`, `>`, and `>=`, and combined using `&&`, `||`, `!`, and parentheses.
+pub fn eval(rt: &Runtime, cond: &str) -> Result<bool, String> {
+    let tokens = tokenize(cond)?;
+    if tokens.is_empty() {
+        return Ok(false);
+    }
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        rt,
+    };
+    let rv = parser.or(true)?;
+    match parser.peek() {
+        None => Ok(rv),
+        Some(t) => Err(format!("Unexpected {:?}", t)),
+    }
+}
@@ -0,0 +1,99 @@
+//! CPU usage sampled from /proc/stat
+use crate::data::{Periodic, Value};
+use crate::state::Runtime;
+use crate::util::{toml_to_f64, Cell};
+use log::debug;
+use std::fs;
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// The (busy, total) time counters of the last sample: the total for all CPUs, followed by
+    /// each core
+    counters: Vec<(u64, u64)>,
+    /// The percentage of time busy between the last two samples, in the same order
+    percent: Vec<f64>,
+}
+
+impl Usage {
+    fn update(&mut self) {
+        let text = match fs::read_to_string("/proc/stat") {
+            Ok(text) => text,
+            Err(e) => {
+                debug!("Could not read /proc/stat: {}", e);
+                return;
+            }
+        };
+        // "cpu  4705 356 584 3699 23 23 0 0 0 0" followed by "cpu0 ...", "cpu1 ..."
+        let counters: Vec<_> = text
+            .lines()
+            .take_while(|line| line.starts_with("cpu"))
+            .map(|line| {
+                let fields: Vec<u64> = line
+                    .split_ascii_whitespace()
+                    .skip(1)
+                    .take(8)
+                    .map(|v| v.parse().unwrap_or(0))
+                    .collect();
+                let total = fields.iter().sum::<u64>();
+                // idle and iowait
+                let idle = fields.iter().skip(3).take(2).sum::<u64>();
+                (total - idle, total)
+            })
+            .collect();
+        // The first sample (or a core that just came online) has nothing to compare to
+        self.percent = counters
+            .iter()
+            .enumerate()
+            .map(|(i, &(busy, total))| match self.counters.get(i) {
+                Some(&(old_busy, old_total)) if total > old_total => {
+                    busy.saturating_sub(old_busy) as f64 * 100.0 / (total - old_total) as f64
+                }
+                _ => 0.0,
+            })
+            .collect();
+        self.counters = counters;
+    }
+}
+
+#[derive(Debug)]
+pub struct Cpu {
+    poll: Periodic<Cell<Usage>>,
+}
+
+impl Cpu {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        Cpu {
+            poll: Periodic::new(
+                toml_to_f64(config.get("poll")).unwrap_or(2.0),
+                Cell::default(),
+            ),
+        }
+    }
+
+    /// Keep the last sample across a reload so the first new reading is not 0
+    pub fn carry_over(&self, old: &Self) {
+        self.poll.carry_over(&old.poll);
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        self.poll.read_refresh(rt, |usage| {
+            usage.take_in(Usage::update);
+            Some("cpu")
+        });
+        let value = self.poll.data().take_in(|usage| {
+            let index = match key {
+                "" | "percent" => 0,
+                "cores" => return Some(usage.percent.len().saturating_sub(1) as f64),
+                _ => key.strip_prefix("core")?.parse::<usize>().ok()? + 1,
+            };
+            usage.percent.get(index).copied()
+        });
+        f(value.map_or(Value::Null, Value::Float))
+    }
+}
@@ -1,17 +1,32 @@
 //! Text expansion and data sources
+use crate::battery;
+use crate::cpu;
 #[cfg(feature = "dbus")]
 use crate::dbus::DbusValue;
-use crate::item::{Item, ItemFormat};
+#[cfg(feature = "http")]
+use crate::http;
+use crate::item::{Item, ItemFormat, Separator};
+use crate::load;
+use crate::memory;
 #[cfg(feature = "dbus")]
 use crate::mpris;
+use crate::network;
 #[cfg(feature = "pulse")]
 use crate::pulse;
 use crate::state::NotifierList;
 use crate::state::Runtime;
+use crate::socket;
+use crate::sparkline;
 use crate::sway;
+use crate::temperature;
 #[cfg(feature = "dbus")]
 use crate::tray;
-use crate::util::{glob_expand, spawn_handle, spawn_noerr, toml_to_f64, toml_to_string, Cell, Fd};
+#[cfg(feature = "dbus")]
+use crate::upower;
+use crate::uptime;
+use crate::util::{
+    format_size, glob_expand, spawn_handle, spawn_noerr, toml_to_f64, toml_to_string, Cell, Fd,
+};
 use crate::wlr::ClipboardData;
 use evalexpr::Node as EvalExpr;
 use futures_util::future::RemoteHandle;
@@ -24,11 +39,12 @@ use std::fs;
 use std::future::Future;
 use std::io;
 use std::io::Write;
-use std::os::unix::io::{AsRawFd, IntoRawFd};
-use std::process::{ChildStdin, Command, Stdio};
+use std::os::unix::io::{AsRawFd, IntoRawFd, OwnedFd};
+use std::process::{ChildStderr, ChildStdin, Command, Stdio};
 use std::rc::{Rc, Weak};
 use std::time::{Duration, Instant};
 use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncBufReadExt, BufReader};
 
 /// The result of a data source or text expansion
 #[derive(Debug)]
@@ -229,8 +245,9 @@ pub struct Periodic<T> {
 #[derive(Debug)]
 struct PeriodicInner<T> {
     interested: Cell<NotifierList>,
-    last_read: Cell<u64>,
     last_update: Cell<Option<Instant>>,
+    /// The value of [Runtime::refresh_seq] at the last read
+    refresh_seq: Cell<u64>,
     data: T,
 }
 
@@ -240,8 +257,8 @@ impl<T: 'static> Periodic<T> {
             period,
             shared: Rc::new(PeriodicInner {
                 interested: Cell::default(),
-                last_read: Cell::default(),
                 last_update: Cell::default(),
+                refresh_seq: Cell::default(),
                 data,
             }),
             timer: Cell::default(),
@@ -287,19 +304,17 @@ impl<T: 'static> Periodic<T> {
         F: FnMut(Option<&Cell<NotifierList>>, &T) -> Option<Fut> + 'static,
         Fut: Future<Output = ()> + 'static,
     {
-        let last_update = self.shared.last_update.get();
+        // a refresh of all values acts as if the last read never happened
+        let refresh_seq = rt.refresh_seq();
+        let refreshed = self.shared.refresh_seq.replace(refresh_seq) != refresh_seq;
+        let last_update = self.shared.last_update.get().filter(|_| !refreshed);
         if self.period <= 0.0 && last_update.is_some() {
             // the one-shot read is already done
             return;
         }
 
         let now = Instant::now();
-        let seq = self.shared.interested.take_in(|notify| {
-            notify.add(rt);
-            notify.data_update_seq()
-        });
-        debug_assert_ne!(seq, 0);
-        self.shared.last_read.set(seq);
+        self.shared.interested.take_in(|notify| notify.add(rt));
         if let Some(last_update) = last_update {
             // Read a new values if we are currently redrawing and it's at least 90% of the
             // deadline.  This avoids waking up several times in a row to update each of a
@@ -332,19 +347,18 @@ impl<T: 'static> Periodic<T> {
 
                     // Try to avoid reading if nobody is listening.
                     //
-                    // If someone is actively reading our value, then last_read and interest_seq
-                    // will be equal (since the sequence update and initial draw attempt are in the
-                    // same task, which is not this one).  If last_read has fallen behind, then we
-                    // know that all items that contain our data are already marked dirty, and will
-                    // be refreshed as soon as they become visible (even without our polling there
-                    // to nudge them).  When that happens, the read will notice the value is
-                    // out-of-date and fix that immediately, along with starting a new timer task.
-                    //
                     // If interest_seq is 0, then nobody has read the value since the last update
-                    // notification that we sent, so the same logic applies.
+                    // notification that we sent.  All bars that contain our data are already
+                    // marked dirty, and will be refreshed as soon as they become visible (even
+                    // without our polling there to nudge them).  When that happens, the read will
+                    // notice the value is out-of-date and fix that immediately, along with
+                    // starting a new timer task.
+                    //
+                    // Note that the global sequence advancing past our last read does not mean
+                    // the same thing, since updates to other values may only redraw other bars.
                     let interest_seq = shared.interested.take_in(|n| n.data_update_seq());
 
-                    if interest_seq != shared.last_read.get() {
+                    if interest_seq == 0 {
                         return Ok(());
                     }
 
@@ -364,9 +378,25 @@ impl<T: 'static> Periodic<T> {
     }
 }
 
+impl<T: Default + 'static> Periodic<Cell<T>> {
+    /// Keep the last reading of a module across a reload, so that its value does not blank out
+    /// until the next poll
+    pub fn carry_over(&self, old: &Self) {
+        self.data().set(old.data().take());
+    }
+}
+
 /// Type-specific part of an [Item]
 #[derive(Debug)]
 pub enum Module {
+    Arc {
+        value: Box<Module>,
+        radius: Box<str>,
+        thickness: Box<str>,
+        start: Box<str>,
+        track: Box<str>,
+        label: Option<Rc<Item>>,
+    },
     Bar {
         left: Rc<Item>,
         center: Rc<Item>,
@@ -374,6 +404,7 @@ pub enum Module {
         tooltips: ItemFormat,
         config: toml::Value,
     },
+    Battery(battery::Battery),
     Calendar {
         day_fmt: Box<str>,
         today_fmt: Box<str>,
@@ -396,14 +427,16 @@ pub enum Module {
     Clock {
         format: Box<str>,
         zone: Box<str>,
+        idle_after: Option<f64>,
         timer: Cell<Option<RemoteHandle<()>>>,
     },
+    Cpu(cpu::Cpu),
     #[cfg(feature = "dbus")]
     DbusCall {
         poll: Periodic<Rc<DbusValue>>,
     },
     Disk {
-        poll: Periodic<(Box<str>, Cell<libc::statvfs>)>,
+        poll: Periodic<(Box<str>, Cell<Option<libc::statvfs>>)>,
     },
     Eval {
         expr: EvalExpr,
@@ -412,7 +445,7 @@ pub enum Module {
     ExecJson {
         command: Box<str>,
         stdin: Cell<Option<ChildStdin>>,
-        value: Cell<Option<Rc<(Cell<JsonValue>, Cell<NotifierList>)>>>,
+        value: Cell<Option<Rc<ExecJsonData>>>,
         handle: Cell<Option<RemoteHandle<()>>>,
     },
     Fade {
@@ -436,14 +469,24 @@ pub enum Module {
         items: Vec<Rc<Item>>,
         tooltip: Option<Rc<Item>>,
         spacing: Box<str>,
+        separator: Option<Separator>,
         vertical: bool,
+        /// Lay out the items (and summary) from last to first
+        reverse: bool,
+        /// Shown in place of the items until clicked
+        collapsed: Option<Rc<Item>>,
+        expanded: Rc<Cell<bool>>,
         // TODO crop ordering: allow specific items to be cropped first
         // TODO use min-width to force earlier cropping
     },
+    #[cfg(feature = "http")]
+    Http(http::Http),
     Icon {
         name: Box<str>,
         fallback: Box<str>,
         tooltip: Box<str>,
+        /// Color for symbolic icons; empty to use the foreground color
+        recolor: Option<Box<str>>,
     },
     Item {
         // unique variant for the reserved "item" item
@@ -452,16 +495,27 @@ pub enum Module {
     ItemReference {
         value: Cell<ItemReference>,
     },
+    /// An icon followed by a text value, sharing one set of formatting and actions
+    Labeled {
+        icon: Box<str>,
+        fallback: Box<str>,
+        recolor: Option<Box<str>>,
+        format: Box<str>,
+        gap: Box<str>,
+    },
+    Load(load::Load),
     #[cfg(feature = "dbus")]
     MediaPlayer2 {
         target: Box<str>,
     },
+    Memory(memory::Memory),
     Meter {
         min: Box<str>,
         max: Box<str>,
         src: Box<Module>,
         values: Box<[Box<str>]>,
     },
+    Network(network::Network),
     ParseError {
         msg: Cow<'static, str>,
     },
@@ -478,6 +532,8 @@ pub enum Module {
         text: Box<str>,
         replace: Box<str>,
     },
+    Socket(socket::Socket),
+    Sparkline(sparkline::Sparkline),
     SwayMode(sway::Mode),
     SwayTree(sway::Tree),
     SwayWorkspace(sway::Workspace),
@@ -486,6 +542,7 @@ pub enum Module {
         cases: toml::value::Table,
         default: Box<str>,
     },
+    Temperature(temperature::Temperature),
     Thermal {
         poll: Periodic<(Box<str>, Cell<u32>)>,
         label: Option<Box<str>>,
@@ -494,13 +551,29 @@ pub enum Module {
         passive: Rc<Item>,
         active: Rc<Item>,
         urgent: Rc<Item>,
+        spacing: Box<str>,
+        reverse: bool,
+        sort: TraySort,
+        /// The scroll delta that must add up before it is sent to an item
+        scroll_threshold: i32,
+        /// Shown in place of the icons, which are shown in a popup when it is clicked
+        drawer: Option<Rc<Item>>,
     },
+    #[cfg(feature = "dbus")]
+    Upower {
+        device: Box<str>,
+        sysfs: Periodic<upower::Fallback>,
+    },
+    Uptime(uptime::Uptime),
     Value {
         value: Cell<Value<'static>>,
         interested: Cell<NotifierList>,
     },
 }
 
+/// The last output of an exec-json command, its interested list, and its last error
+type ExecJsonData = (Cell<JsonValue>, Cell<NotifierList>, Cell<Option<String>>);
+
 /// Possible contents of the "item" block
 #[derive(Debug, Clone)]
 pub enum IterationItem {
@@ -535,6 +608,39 @@ impl PartialEq for IterationItem {
     }
 }
 
+/// The order of the icons in a tray
+#[derive(Debug, Default)]
+pub enum TraySort {
+    /// The order in which the items registered
+    #[default]
+    Arrival,
+    Id,
+    Title,
+    /// Items with these IDs first, in this order, followed by the others sorted by ID
+    Pinned(Vec<Box<str>>),
+}
+
+impl TraySort {
+    fn from_toml(value: Option<&toml::Value>) -> Result<Self, &'static str> {
+        match value {
+            None => Ok(TraySort::Arrival),
+            Some(toml::Value::String(s)) => match &**s {
+                "arrival" => Ok(TraySort::Arrival),
+                "id" => Ok(TraySort::Id),
+                "title" => Ok(TraySort::Title),
+                _ => Err("'sort' must be arrival, id, title, or a list of IDs"),
+            },
+            Some(toml::Value::Array(ids)) => ids
+                .iter()
+                .map(|id| id.as_str().map(Into::into))
+                .collect::<Option<_>>()
+                .map(TraySort::Pinned)
+                .ok_or("'sort' must be arrival, id, title, or a list of IDs"),
+            Some(_) => Err("'sort' must be arrival, id, title, or a list of IDs"),
+        }
+    }
+}
+
 /// The context of a parsed item, used to disambiguate strings
 pub enum ModuleContext {
     Source,
@@ -545,6 +651,26 @@ impl Module {
     pub fn from_toml_in(value: &toml::Value, ctx: ModuleContext) -> Self {
         match value.get("type").and_then(|v| v.as_str()) {
             // keep values in alphabetical order
+            Some("arc") => {
+                let get = |key| toml_to_string(value.get(key)).unwrap_or_default().into();
+                let label = value.get("label").map(Item::from_toml_format).map(Rc::new);
+                let src = if value.get("expr").is_some() {
+                    Box::new(Self::new_eval(value))
+                } else if let Some(item) = value.get("value") {
+                    Box::new(Self::from_toml_in(item, ModuleContext::Source))
+                } else {
+                    return Module::parse_error("'value' or 'expr' is required");
+                };
+                Module::Arc {
+                    value: src,
+                    radius: get("radius"),
+                    thickness: get("thickness"),
+                    start: get("start"),
+                    track: get("track-color"),
+                    label,
+                }
+            }
+            Some("battery") => Module::Battery(battery::Battery::from_toml(value)),
             Some("calendar") => {
                 let day_fmt = value
                     .get("day-format")
@@ -633,12 +759,15 @@ impl Module {
                     .and_then(|v| v.as_str())
                     .unwrap_or("")
                     .into();
+                let idle_after = toml_to_f64(value.get("idle-after"));
                 Module::Clock {
                     format,
                     zone,
+                    idle_after,
                     timer: Default::default(),
                 }
             }
+            Some("cpu") => Module::Cpu(cpu::Cpu::from_toml(value)),
             #[cfg(feature = "dbus")]
             Some("dbus") => {
                 let rc = match DbusValue::from_toml(value) {
@@ -654,10 +783,9 @@ impl Module {
                     .and_then(|v| v.as_str())
                     .unwrap_or("/")
                     .into();
-                let v: libc::statvfs = unsafe { std::mem::zeroed() };
                 let poll = Periodic::new(
                     toml_to_f64(value.get("poll")).unwrap_or(60.0),
-                    (path, Cell::new(v)),
+                    (path, Cell::new(None)),
                 );
                 Module::Disk { poll }
             }
@@ -781,15 +909,35 @@ impl Module {
                     .map(Item::from_toml_ref)
                     .map(Rc::new)
                     .collect();
+                let collapsed = value
+                    .get("collapsed")
+                    .map(Item::from_toml_format)
+                    .map(Rc::new);
+                let expanded = value
+                    .get("expanded")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
 
                 Module::Group {
                     condition,
                     items,
                     tooltip,
                     spacing,
+                    separator: value.get("separator").map(Separator::from_toml),
                     vertical,
+                    reverse: value
+                        .get("reverse")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false),
+                    collapsed,
+                    expanded: Rc::new(Cell::new(expanded)),
                 }
             }
+            #[cfg(feature = "http")]
+            Some("http") => match http::Http::from_toml(value) {
+                Ok(h) => Module::Http(h),
+                Err(e) => Module::parse_error(e),
+            },
             Some("icon") => {
                 let name = value
                     .get("name")
@@ -811,8 +959,24 @@ impl Module {
                     name,
                     fallback,
                     tooltip,
+                    recolor: Self::parse_recolor(value),
                 }
             }
+            Some("labeled") => Module::Labeled {
+                icon: toml_to_string(value.get("icon")).unwrap_or_default().into(),
+                fallback: toml_to_string(value.get("fallback"))
+                    .unwrap_or_default()
+                    .into(),
+                recolor: Self::parse_recolor(value),
+                format: toml_to_string(value.get("format"))
+                    .unwrap_or_default()
+                    .into(),
+                gap: toml_to_string(value.get("gap"))
+                    .unwrap_or_else(|| "4".into())
+                    .into(),
+            },
+            Some("load") => Module::Load(load::Load::from_toml(value)),
+            Some("memory") => Module::Memory(memory::Memory::from_toml(value)),
             Some("meter") => {
                 let min = toml_to_string(value.get("min")).unwrap_or_default().into();
                 let max = toml_to_string(value.get("max")).unwrap_or_default().into();
@@ -864,6 +1028,7 @@ impl Module {
                 let target = toml_to_string(value.get("name")).unwrap_or_default().into();
                 Module::MediaPlayer2 { target }
             }
+            Some("network") => Module::Network(network::Network::from_toml(value)),
             #[cfg(feature = "pulse")]
             Some("pulse") => {
                 let target = toml_to_string(value.get("target"))
@@ -931,6 +1096,14 @@ impl Module {
                 );
                 Module::ReadFile { on_err, poll }
             }
+            Some("socket") => match socket::Socket::from_toml(value) {
+                Ok(s) => Module::Socket(s),
+                Err(e) => Module::parse_error(e),
+            },
+            Some("sparkline") => match sparkline::Sparkline::from_toml(value) {
+                Ok(s) => Module::Sparkline(s),
+                Err(e) => Module::parse_error(e),
+            },
             Some("sway-mode") => Module::SwayMode(sway::Mode::from_toml(value)),
             Some("sway-tree") => Module::SwayTree(sway::Tree::from_toml(value)),
             Some("sway-workspace") => Module::SwayWorkspace(sway::Workspace::from_toml(value)),
@@ -957,6 +1130,10 @@ impl Module {
                     default,
                 }
             }
+            Some("temperature") => match temperature::Temperature::from_toml(value) {
+                Ok(t) => Module::Temperature(t),
+                Err(e) => Module::parse_error(e),
+            },
             // "text" is an alias for "formatted"
             Some("thermal") => {
                 let label;
@@ -1028,30 +1205,87 @@ impl Module {
             Some("tray") => {
                 let active = Rc::new(value.get("item").map(Item::from_toml_ref).unwrap_or_else(
                     || {
-                        Module::Icon {
+                        let icon = Module::Icon {
                             name: "{item.icon}".into(),
                             fallback: "{item.title}".into(),
                             tooltip: "".into(),
+                            recolor: Self::parse_recolor(value),
+                        };
+                        // Some (ayatana) items show a text label beside the icon
+                        let label = Module::Group {
+                            items: vec![Rc::new(Module::new_format(" {item.label}").into())],
+                            condition: Some("{item.label}".into()),
+                            tooltip: None,
+                            spacing: "".into(),
+                            separator: None,
+                            vertical: false,
+                            reverse: false,
+                            collapsed: None,
+                            expanded: Default::default(),
+                        };
+                        Module::Group {
+                            items: vec![Rc::new(icon.into()), Rc::new(label.into())],
+                            condition: None,
+                            tooltip: None,
+                            spacing: "".into(),
+                            separator: None,
+                            vertical: false,
+                            reverse: false,
+                            collapsed: None,
+                            expanded: Default::default(),
                         }
                         .into()
                     },
                 ));
-                let passive = Rc::new(
-                    value
-                        .get("passive")
-                        .map_or_else(Item::none, Item::from_toml_ref),
-                );
+                let show_passive = value
+                    .get("show-passive")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                let passive = match value.get("passive") {
+                    Some(item) => Rc::new(Item::from_toml_ref(item)),
+                    None if show_passive => active.clone(),
+                    None => Rc::new(Item::none()),
+                };
                 let urgent = value
                     .get("urgent")
                     .map(Item::from_toml_ref)
                     .map(Rc::new)
                     .unwrap_or_else(|| active.clone());
+                let spacing = toml_to_string(value.get("spacing"))
+                    .unwrap_or_default()
+                    .into();
+                let reverse = value
+                    .get("reverse")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                let sort = match TraySort::from_toml(value.get("sort")) {
+                    Ok(sort) => sort,
+                    Err(e) => return Module::parse_error(e),
+                };
+                let scroll_threshold =
+                    toml_to_f64(value.get("scroll-threshold")).map_or(15, |v| v.max(1.0) as i32);
+                let drawer = value.get("drawer").map(Item::from_toml_format).map(Rc::new);
                 Module::Tray {
                     passive,
                     active,
                     urgent,
+                    spacing,
+                    reverse,
+                    sort,
+                    scroll_threshold,
+                    drawer,
                 }
             }
+            #[cfg(feature = "dbus")]
+            Some("upower") => {
+                let device = toml_to_string(value.get("device"))
+                    .unwrap_or_default()
+                    .into();
+                let poll = toml_to_f64(value.get("poll")).unwrap_or(30.0);
+                let sysfs = Periodic::new(poll, Default::default());
+                Module::Upower { device, sysfs }
+            }
+            Some("uptime") => Module::Uptime(uptime::Uptime::from_toml(value)),
             Some("value") => {
                 Module::new_value(toml_to_string(value.get("value")).unwrap_or_default())
             }
@@ -1103,6 +1337,15 @@ impl Module {
         }
     }
 
+    /// The `recolor` key is either a color or `true` to use the foreground color
+    fn parse_recolor(value: &toml::Value) -> Option<Box<str>> {
+        match value.get("recolor") {
+            Some(toml::Value::Boolean(true)) => Some("".into()),
+            Some(v) => v.as_str().map(Into::into),
+            None => None,
+        }
+    }
+
     pub fn new_eval(value: &toml::Value) -> Self {
         match value
             .get("expr")
@@ -1135,7 +1378,7 @@ impl Module {
     }
 
     /// One-time setup, if needed
-    pub fn init(&self, name: &str, _rt: &Runtime, from: Option<&Self>) {
+    pub fn init(&self, name: &str, rt: &Runtime, from: Option<&Self>) {
         match (self, from) {
             (
                 Module::ExecJson {
@@ -1170,13 +1413,28 @@ impl Module {
                     .arg(&**command)
                     .stdin(Stdio::piped())
                     .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
                     .spawn()
                 {
-                    Err(e) => error!("Could not execute {}: {}", command, e),
+                    Err(e) => {
+                        error!("Could not execute {}: {}", command, e);
+                        let msg = format!("Could not execute: {}", e);
+                        let rc = (
+                            Cell::new(JsonValue::Null),
+                            Default::default(),
+                            Cell::new(Some(msg)),
+                        );
+                        value.set(Some(Rc::new(rc)));
+                    }
                     Ok(mut child) => {
-                        let rc = Rc::new((Cell::new(JsonValue::Null), Default::default()));
+                        let rc = Rc::new((
+                            Cell::new(JsonValue::Null),
+                            Default::default(),
+                            Cell::new(None),
+                        ));
                         let pipe_in = child.stdin.take().unwrap();
                         let fd = child.stdout.take().unwrap().into_raw_fd();
+                        let pipe_err = child.stderr.take().unwrap();
                         unsafe {
                             libc::fcntl(pipe_in.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK);
                         }
@@ -1185,13 +1443,74 @@ impl Module {
                         }
                         stdin.set(Some(pipe_in));
                         value.set(Some(rc.clone()));
-                        handle.set(Some(spawn_handle(
-                            "ExecJson",
-                            do_exec_json(fd, name.to_owned(), rc),
-                        )));
+                        let name = name.to_owned();
+                        handle.set(Some(spawn_handle("ExecJson", async move {
+                            let (out, err) = futures_util::future::join(
+                                do_exec_json(fd, name.clone(), rc.clone()),
+                                read_exec_stderr(pipe_err, name, rc),
+                            )
+                            .await;
+                            out.and(err)
+                        })));
                     }
                 }
             }
+            (Module::Socket(socket), Some(Module::Socket(old))) => {
+                socket.init(name, rt, Some(old));
+            }
+            (Module::Socket(socket), _) => socket.init(name, rt, None),
+            #[cfg(feature = "http")]
+            (Module::Http(http), Some(Module::Http(old))) => http.init(name, rt, Some(old)),
+            #[cfg(feature = "http")]
+            (Module::Http(http), _) => http.init(name, rt, None),
+
+            // For the rest, keep the last value read from the same source so that it does not
+            // blank out until the next poll
+            (Module::Battery(b), Some(Module::Battery(old))) => b.carry_over(old),
+            (Module::Cpu(cpu), Some(Module::Cpu(old))) => cpu.carry_over(old),
+            #[cfg(feature = "dbus")]
+            (Module::DbusCall { poll }, Some(Module::DbusCall { poll: old })) => {
+                poll.data().carry_over(old.data());
+            }
+            (Module::Disk { poll }, Some(Module::Disk { poll: old }))
+                if poll.data().0 == old.data().0 =>
+            {
+                poll.data().1.set(old.data().1.get());
+            }
+            (Module::Load(load), Some(Module::Load(old))) => load.carry_over(old),
+            (Module::Memory(mem), Some(Module::Memory(old))) => mem.carry_over(old),
+            (Module::Network(net), Some(Module::Network(old))) => net.carry_over(old),
+            (Module::Sparkline(s), Some(Module::Sparkline(old))) => s.carry_over(old),
+            (Module::Temperature(t), Some(Module::Temperature(old))) => t.carry_over(old),
+            (Module::ReadFile { poll, .. }, Some(Module::ReadFile { poll: old, .. }))
+                if poll.data().0 == old.data().0 =>
+            {
+                poll.data().1.set(old.data().1.take());
+            }
+            (Module::Thermal { poll, .. }, Some(Module::Thermal { poll: old, .. }))
+                if poll.data().0 == old.data().0 =>
+            {
+                poll.data().1.set(old.data().1.get());
+            }
+            // Keep groups that were expanded or collapsed by a click as they were
+            (
+                Module::Group {
+                    collapsed: Some(_),
+                    expanded,
+                    ..
+                },
+                Some(Module::Group {
+                    collapsed: Some(_),
+                    expanded: old,
+                    ..
+                }),
+            ) => {
+                expanded.set(old.get());
+            }
+            // Values may have been changed by actions; keep them
+            (Module::Value { value, .. }, Some(Module::Value { value: old, .. })) => {
+                value.set(old.take());
+            }
             _ => {}
         }
     }
@@ -1224,8 +1543,29 @@ impl Module {
             }
         };
 
+        // A numeric first segment selects one entry of a list, as in {workspaces.2.name}
+        let (index, rest) = key.split_once('.').unwrap_or((key, ""));
+        if let (Ok(index), true) = (index.parse::<usize>(), self.is_list()) {
+            let mut found = None;
+            let mut i = 0;
+            self.read_focus_list(rt, |_, item| {
+                if i == index {
+                    found = Some(item);
+                }
+                i += 1;
+            });
+            return match found {
+                Some(item) => Module::Item {
+                    value: Cell::new(Some(item)),
+                }
+                .read_in(name, rest, rt, f),
+                None => f(Value::Null),
+            };
+        }
+
         match self {
-            Module::Group { .. }
+            Module::Arc { .. }
+            | Module::Group { .. }
             | Module::Fade { .. }
             | Module::FocusList { .. }
             | Module::Tray { .. } => {
@@ -1237,6 +1577,7 @@ impl Module {
                 Some(value) => f(Value::Owned(value)),
                 None => f(Value::Null),
             },
+            Module::Battery(battery) => battery.read_in(name, key, rt, f),
             Module::Calendar {
                 day_fmt,
                 today_fmt,
@@ -1325,6 +1666,7 @@ impl Module {
             Module::Clock {
                 format,
                 zone,
+                idle_after,
                 timer,
             } => {
                 let real_format = rt.format_or(&format, &name).into_text();
@@ -1358,13 +1700,18 @@ impl Module {
 
                 // Set a timer to expire when the subsecond offset will be zero
                 // add another 1ms delay because epoll only gets 1ms granularity
+                let idle = idle_after.map_or(false, |secs| {
+                    rt.pointer_idle_time()
+                        .map_or(false, |t| t.as_secs_f64() >= secs)
+                });
                 let delay;
-                if value != nv {
+                if value != nv && !idle {
                     // we need to tick every second
                     delay = 1_000_999_999u64.checked_sub(subsec);
                 } else {
-                    // the displayed text in one second is the same; tick every minute
-                    // (really, we could continue on, but that's unlikely to help)
+                    // the displayed text in one second is the same, or nobody is looking at the
+                    // bar; tick every minute (really, we could continue on, but that's unlikely
+                    // to help)
                     let sec = chrono::Timelike::second(&now) as u64;
                     delay = (1_000_000_000 * (60 - sec) + 999_999).checked_sub(subsec);
                 }
@@ -1378,6 +1725,7 @@ impl Module {
 
                 f(Value::Owned(value))
             }
+            Module::Cpu(cpu) => cpu.read_in(name, key, rt, f),
             #[cfg(feature = "dbus")]
             Module::DbusCall { poll } => {
                 poll.read_refresh_async(rt, move |rc| rc.clone().do_call());
@@ -1385,92 +1733,60 @@ impl Module {
             }
             Module::Disk { poll } => {
                 poll.read_refresh(rt, |(path, contents)| {
-                    let cstr = std::ffi::CString::new(path.as_bytes()).unwrap();
-                    let rv = unsafe { libc::statvfs(cstr.as_ptr(), contents.as_ptr()) };
-                    if rv != 0 {
+                    let mut vfs: libc::statvfs = unsafe { std::mem::zeroed() };
+                    let rv = match std::ffi::CString::new(path.as_bytes()) {
+                        Ok(cstr) => unsafe { libc::statvfs(cstr.as_ptr(), &mut vfs) },
+                        Err(_) => -1,
+                    };
+                    if rv == 0 {
+                        contents.set(Some(vfs));
+                    } else {
                         warn!(
                             "Could not read disk at '{}': {}",
                             path,
                             std::io::Error::last_os_error()
                         );
+                        contents.set(None);
                     }
                     Some(path)
                 });
-                let vfs = poll.data().1.get();
+                let vfs = match poll.data().1.get() {
+                    Some(vfs) => vfs,
+                    None => return f(Value::Null),
+                };
+                let size = (vfs.f_frsize * vfs.f_blocks) as f64;
+                let free = (vfs.f_frsize * vfs.f_bfree) as f64;
+                let avail = (vfs.f_frsize * vfs.f_bavail) as f64;
                 match key {
-                    "size" => f(Value::Float((vfs.f_frsize * vfs.f_blocks) as f64)),
-                    "free" => f(Value::Float((vfs.f_bsize * vfs.f_bfree) as f64)),
-                    "avail" => f(Value::Float((vfs.f_bsize * vfs.f_bavail) as f64)),
-
-                    "size-mb" => f(Value::Float(
-                        (vfs.f_frsize * vfs.f_blocks) as f64 / 1_000_000.0,
-                    )),
-                    "free-mb" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bfree) as f64 / 1_000_000.0,
-                    )),
-                    "avail-mb" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bavail) as f64 / 1_000_000.0,
-                    )),
-
-                    "size-gb" => f(Value::Float(
-                        (vfs.f_frsize * vfs.f_blocks) as f64 / 1_000_000_000.0,
-                    )),
-                    "free-gb" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bfree) as f64 / 1_000_000_000.0,
-                    )),
-                    "avail-gb" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bavail) as f64 / 1_000_000_000.0,
-                    )),
-
-                    "size-tb" => f(Value::Float(
-                        (vfs.f_frsize * vfs.f_blocks) as f64 / 1_000_000_000_000.0,
-                    )),
-                    "free-tb" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bfree) as f64 / 1_000_000_000_000.0,
-                    )),
-                    "avail-tb" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bavail) as f64 / 1_000_000_000_000.0,
-                    )),
-
-                    "size-mib" => f(Value::Float(
-                        (vfs.f_frsize * vfs.f_blocks) as f64 / 1048576.0,
-                    )),
-                    "free-mib" => f(Value::Float((vfs.f_bsize * vfs.f_bfree) as f64 / 1048576.0)),
-                    "avail-mib" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bavail) as f64 / 1048576.0,
-                    )),
-
-                    "size-gib" => f(Value::Float(
-                        (vfs.f_frsize * vfs.f_blocks) as f64 / 1073741824.0,
-                    )),
-                    "free-gib" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bfree) as f64 / 1073741824.0,
-                    )),
-                    "avail-gib" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bavail) as f64 / 1073741824.0,
-                    )),
-
-                    "size-tib" => f(Value::Float(
-                        (vfs.f_frsize * vfs.f_blocks) as f64 / 1099511627776.0,
-                    )),
-                    "free-tib" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bfree) as f64 / 1099511627776.0,
-                    )),
-                    "avail-tib" => f(Value::Float(
-                        (vfs.f_bsize * vfs.f_bavail) as f64 / 1099511627776.0,
-                    )),
-
-                    "" | "text" | "percent-used" if vfs.f_frsize * vfs.f_blocks != 0 => {
-                        let size = (vfs.f_frsize * vfs.f_blocks) as f64;
-                        let free = (vfs.f_bsize * vfs.f_bfree) as f64;
-                        let pct = 100.0 - 100.0 * free / size;
-                        if key == "" || key == "text" {
-                            f(Value::Owned(format!("{:.0}%", pct)))
-                        } else {
-                            f(Value::Float(pct))
-                        }
+                    "" | "text" if size > 0.0 => {
+                        f(Value::Owned(format!("{:.0}%", 100.0 - 100.0 * free / size)))
+                    }
+                    "percent" | "percent-used" if size > 0.0 => {
+                        f(Value::Float(100.0 - 100.0 * free / size))
+                    }
+                    _ => {
+                        // A size, optionally followed by its unit, like "free-gib"
+                        let (base, unit) = key.split_once('-').unwrap_or((key, ""));
+                        let bytes = match base {
+                            "size" | "total" => size,
+                            "free" => free,
+                            "avail" => avail,
+                            "used" => size - free,
+                            _ => return f(Value::Null),
+                        };
+                        let scale = match unit {
+                            "" => 1.0,
+                            "human" => return f(Value::Owned(format_size(bytes as u64))),
+                            "mb" => 1e6,
+                            "gb" => 1e9,
+                            "tb" => 1e12,
+                            "mib" => 1048576.0,
+                            "gib" => 1073741824.0,
+                            "tib" => 1099511627776.0,
+                            _ => return f(Value::Null),
+                        };
+                        f(Value::Float(bytes / scale))
                     }
-                    _ => f(Value::Null),
                 }
             }
             Module::Eval { expr, vars } => {
@@ -1564,17 +1880,27 @@ impl Module {
                 }
             }
             Module::ExecJson { command, value, .. } => {
-                let value = value.take_in_some(|v| v.clone()).unwrap();
+                let value = match value.take_in_some(|v| v.clone()) {
+                    Some(value) => value,
+                    None => return f(Value::Null),
+                };
+                value.1.take_in(|i| i.add(rt));
                 let v = value.0.replace(JsonValue::Null);
-                let rv = f(Value::Borrow(v[key].as_str().unwrap_or_else(|| {
-                    debug!(
-                        "Could not find {}.{} in the output of {}",
-                        name, key, command
-                    );
-                    ""
-                })));
+                // keys present in the command's output take precedence over the diagnostics
+                let rv = match key {
+                    "error" if !v.has_key(key) => value
+                        .2
+                        .take_in(|e| f(e.as_deref().map_or(Value::Null, Value::Borrow))),
+                    "ok" if !v.has_key(key) => value.2.take_in(|e| f(Value::Bool(e.is_none()))),
+                    _ => f(Value::Borrow(v[key].as_str().unwrap_or_else(|| {
+                        debug!(
+                            "Could not find {}.{} in the output of {}",
+                            name, key, command
+                        );
+                        ""
+                    }))),
+                };
                 value.0.set(v);
-                value.1.take_in(|i| i.add(rt));
                 rv
             }
             Module::Formatted { format, tooltip } => match key {
@@ -1584,10 +1910,17 @@ impl Module {
                 },
                 _ => f(rt.format_or(&format, &name)),
             },
+            #[cfg(feature = "http")]
+            Module::Http(http) => http.read_in(name, key, rt, f),
             Module::Icon { tooltip, .. } => match key {
                 "tooltip" => f(rt.format_or(&tooltip, &name)),
                 _ => f(Value::Null),
             },
+            Module::Labeled { icon, format, .. } => match key {
+                "icon" => f(rt.format_or(&icon, &name)),
+                "" | "text" => f(rt.format_or(&format, &name)),
+                _ => f(Value::Null),
+            },
             Module::Item { value } => value.take_in(|item| match item.as_ref() {
                 #[cfg(feature = "dbus")]
                 Some(IterationItem::MediaPlayer2 { target }) => {
@@ -1605,8 +1938,10 @@ impl Module {
                 Some(item) => item.data.read_in(name, key, rt, f),
                 None => f(Value::Null),
             }),
+            Module::Load(load) => load.read_in(name, key, rt, f),
             #[cfg(feature = "dbus")]
             Module::MediaPlayer2 { target } => mpris::read_in(name, target, key, rt, f),
+            Module::Memory(mem) => mem.read_in(name, key, rt, f),
             Module::Meter {
                 min,
                 max,
@@ -1628,6 +1963,7 @@ impl Module {
                 };
                 f(rt.format_or(&expr, &name))
             }
+            Module::Network(net) => net.read_in(name, key, rt, f),
             Module::ParseError { .. } => f(Value::Null),
             #[cfg(feature = "pulse")]
             Module::Pulse { target } => pulse::read_in(name, target, key, rt, f),
@@ -1685,6 +2021,8 @@ impl Module {
                     }
                 }
             }
+            Module::Socket(socket) => socket.read_in(name, key, rt, f),
+            Module::Sparkline(s) => s.read_in(name, key, rt, f),
             Module::SwayMode(mode) => mode.read_in(name, key, rt, f),
             Module::SwayTree(tree) => tree.read_in(name, key, rt, f),
             Module::SwayWorkspace(ws) => ws.read_in(name, key, rt, f),
@@ -1699,6 +2037,7 @@ impl Module {
                 let res = rt.format_or(case, &name);
                 f(res)
             }
+            Module::Temperature(t) => t.read_in(name, key, rt, f),
             Module::Thermal { poll, label } => {
                 match key {
                     "label" => return f(label.as_deref().map_or(Value::Null, Value::Borrow)),
@@ -1725,6 +2064,9 @@ impl Module {
                 let (_, value) = poll.data();
                 f(Value::Float(value.get() as f64 / 1000.0))
             }
+            #[cfg(feature = "dbus")]
+            Module::Upower { device, sysfs } => upower::read_in(name, device, sysfs, key, rt, f),
+            Module::Uptime(uptime) => uptime.read_in(name, key, rt, f),
             Module::Value { value, interested } => {
                 interested.take_in(|i| i.add(rt));
                 value.take_in(|s| f(s.as_ref()))
@@ -1781,6 +2123,22 @@ impl Module {
             Module::MediaPlayer2 { target } => mpris::write(name, target, key, value, rt),
             #[cfg(feature = "pulse")]
             Module::Pulse { target } => pulse::do_write(name, target, key, value, rt),
+            Module::Group { expanded, .. } if key == "" => {
+                let state = match value.as_str_fast() {
+                    "toggle" => !expanded.get(),
+                    "expand" => true,
+                    "collapse" => false,
+                    _ => match value.parse_bool() {
+                        Some(b) => b,
+                        None => {
+                            error!("Ignoring write of '{}' to group {}", value, name);
+                            return;
+                        }
+                    },
+                };
+                expanded.set(state);
+                NotifierList::active(rt).notify_data("group");
+            }
             Module::SwayMode(_) => sway::write(value, rt),
             Module::SwayTree(_) => sway::write(value, rt),
             Module::SwayWorkspace(ws) => ws.write(name, key, value, rt),
@@ -1807,9 +2165,23 @@ impl Module {
         }
     }
 
+    /// True if this module provides a list of items (see [Self::read_focus_list])
+    fn is_list(&self) -> bool {
+        match self {
+            #[cfg(feature = "dbus")]
+            Module::MediaPlayer2 { .. } | Module::Tray { .. } => true,
+            #[cfg(feature = "pulse")]
+            Module::Pulse { .. } => true,
+            Module::SwayWorkspace(_) => true,
+            _ => false,
+        }
+    }
+
     /// Use this module as the source of a focus-list item
     pub fn read_focus_list<F: FnMut(bool, IterationItem)>(&self, rt: &Runtime, f: F) {
         match self {
+            #[cfg(feature = "dbus")]
+            Module::Tray { sort, .. } => tray::read_focus_list(rt, sort, f),
             #[cfg(feature = "dbus")]
             Module::MediaPlayer2 { .. } => mpris::read_focus_list(rt, f),
             Module::SwayWorkspace(ws) => ws.read_focus_list(rt, f),
@@ -1832,7 +2204,7 @@ use std::error::Error;
 async fn do_exec_json(
     fd: i32,
     name: String,
-    value: Rc<(Cell<JsonValue>, Cell<NotifierList>)>,
+    value: Rc<ExecJsonData>,
 ) -> Result<(), Box<dyn Error>> {
     let afd = AsyncFd::new(Fd(fd)).expect("Invalid FD from ChildStdin");
     let mut buffer: Vec<u8> = Vec::with_capacity(1024);
@@ -1861,6 +2233,11 @@ async fn do_exec_json(
                 match rv {
                     0 => {
                         libc::close(fd);
+                        // The exit status is not available, as children are reaped automatically
+                        value.2.take_in(|e| {
+                            e.get_or_insert_with(|| "Command exited".into());
+                        });
+                        value.1.take().notify_data("exec-json");
                         return Ok(());
                     }
                     len if rv > 0 && rv <= max_len as _ => {
@@ -1876,6 +2253,8 @@ async fn do_exec_json(
                             }
                             _ => {
                                 warn!("Got {} on child read; discontinuing", e);
+                                value.2.set(Some(format!("Read error: {}", e)));
+                                value.1.take().notify_data("exec-json");
                                 return Ok(());
                             }
                         }
@@ -1885,14 +2264,20 @@ async fn do_exec_json(
             while let Some(eol) = buffer.iter().position(|&c| c == b'\n') {
                 let mut json = None;
                 match std::str::from_utf8(&buffer[..eol]) {
-                    Err(_) => info!("Ignoring bad UTF8 from '{}'", name),
+                    Err(_) => {
+                        info!("Ignoring bad UTF8 from '{}'", name);
+                        value.2.set(Some("Bad UTF-8 in output".into()));
+                    }
                     Ok(v) => {
                         debug!("'{}': {}", name, v);
                         match json::parse(v) {
                             Ok(v) => {
                                 json = Some(v);
                             }
-                            Err(e) => info!("Ignoring bad JSON from '{}': {}", name, e),
+                            Err(e) => {
+                                info!("Ignoring bad JSON from '{}': {}", name, e);
+                                value.2.set(Some(format!("Bad JSON in output: {}", e)));
+                            }
                         }
                     }
                 }
@@ -1901,9 +2286,26 @@ async fn do_exec_json(
                 buffer.drain(..eol + 1);
                 if let Some(json) = json {
                     value.0.set(json);
-                    value.1.take().notify_data("exec-json");
+                    value.2.set(None);
                 }
+                value.1.take().notify_data("exec-json");
             }
         }
     }
 }
+
+/// Log the stderr of an exec-json command, keeping the last line as its error
+async fn read_exec_stderr(
+    pipe: ChildStderr,
+    name: String,
+    value: Rc<ExecJsonData>,
+) -> Result<(), Box<dyn Error>> {
+    let pipe = tokio::net::unix::pipe::Receiver::from_file(fs::File::from(OwnedFd::from(pipe)))?;
+    let mut lines = BufReader::new(pipe).lines();
+    while let Some(line) = lines.next_line().await? {
+        warn!("'{}': {}", name, line);
+        value.2.set(Some(line));
+        value.1.take().notify_data("exec-json");
+    }
+    Ok(())
+}
@@ -505,6 +505,19 @@ impl DbusValue {
         Ok(rc)
     }
 
+    /// Keep the last value of the same query across a config reload
+    pub fn carry_over(&self, old: &Self) {
+        let same_query = self.bus_name == old.bus_name
+            && self.path == old.path
+            && self.interface == old.interface
+            && self.member == old.member
+            && self.args == old.args;
+        if Rc::ptr_eq(&self.bus, &old.bus) && same_query {
+            self.sig.set(old.sig.take());
+            *self.value.borrow_mut() = old.value.borrow_mut().take();
+        }
+    }
+
     fn call_now(self: Rc<Self>) {
         spawn_noerr(async move {
             self.do_call().await;
@@ -1,9 +1,14 @@
 //! Event handling (click, scroll)
-use crate::data::IterationItem;
-use crate::item::PopupDesc;
-use crate::state::Runtime;
+use crate::data::{IterationItem, Value};
+use crate::item::{Item, PopupDesc};
+#[cfg(feature = "dbus")]
+use crate::mpris;
+#[cfg(feature = "pulse")]
+use crate::pulse;
+use crate::state::{NotifierList, Runtime};
 #[cfg(feature = "dbus")]
 use crate::tray;
+use crate::util::Cell;
 use crate::wayland::Button;
 use log::{error, info};
 use std::process::Command;
@@ -16,14 +21,33 @@ struct EventListener {
     x_max: f32,
     buttons: u32,
     item: Option<IterationItem>,
+    /// The z-index of the layer this was drawn on, if it was drawn on one
+    z: Option<i32>,
     target: Action,
 }
 
+/// Identifies one rendering of an item that has an `on-hover` style
+#[derive(Debug, Clone, PartialEq)]
+pub struct HoverKey {
+    item: usize,
+    iter: Option<IterationItem>,
+}
+
+impl HoverKey {
+    pub fn new(item: &Rc<Item>, iter: Option<IterationItem>) -> Self {
+        HoverKey {
+            item: Rc::as_ptr(item) as usize,
+            iter,
+        }
+    }
+}
+
 /// A list of [EventListener]s
 #[derive(Debug, Default, Clone)]
 pub struct EventSink {
     handlers: Vec<EventListener>,
     hovers: Vec<(f32, f32, PopupDesc)>,
+    styles: Vec<(f32, f32, HoverKey)>,
 }
 
 impl EventSink {
@@ -54,24 +78,85 @@ impl EventSink {
                 x_max: 1e20,
                 buttons,
                 item: None,
+                z: None,
                 target: Action::from_toml(value),
             })
         }
     }
 
     #[cfg(feature = "dbus")]
-    pub fn from_tray(item: Rc<tray::TrayItem>) -> Self {
+    pub fn from_tray(item: Rc<tray::TrayItem>, scroll_threshold: i32) -> Self {
         let mut sink = EventSink::default();
         sink.handlers.push(EventListener {
             x_min: -1e20,
             x_max: 1e20,
             buttons: 7 | (15 << 5),
             item: None,
-            target: Action::from_tray(item),
+            z: None,
+            target: Action::from_tray(item, scroll_threshold),
         });
         sink
     }
 
+    /// Add actions for the given buttons, unless other actions were configured
+    #[cfg_attr(not(any(feature = "dbus", feature = "pulse")), allow(unused))]
+    fn add_defaults(&mut self, defaults: impl IntoIterator<Item = (u32, Action)>) {
+        if !self.handlers.is_empty() {
+            return;
+        }
+        for (buttons, target) in defaults {
+            self.handlers.push(EventListener {
+                x_min: 0.0,
+                x_max: 1e20,
+                buttons,
+                item: None,
+                z: None,
+                target,
+            });
+        }
+    }
+
+    /// Play or pause a media player when it is clicked, and seek when it is scrolled
+    #[cfg(feature = "dbus")]
+    pub fn add_mpris_default(&mut self, target: &str) {
+        let action = |command| Action::Mpris {
+            target: target.into(),
+            command,
+        };
+        self.add_defaults([
+            (1 << 0 | 1 << 9, action("PlayPause")),
+            (1 << 5, action("Seek 5")),
+            (1 << 6, action("Seek -5")),
+        ]);
+    }
+
+    /// Mute or unmute a volume when it is clicked, and change it when it is scrolled
+    #[cfg(feature = "pulse")]
+    pub fn add_pulse_default(&mut self, target: &str) {
+        let action = |key, value| Action::Pulse {
+            target: target.into(),
+            key,
+            value,
+        };
+        self.add_defaults([
+            (1 << 0 | 1 << 9, action("mute", "toggle")),
+            (1 << 5, action("volume", "+5%")),
+            (1 << 6, action("volume", "-5%")),
+        ]);
+    }
+
+    /// Flip the given state (and redraw) when clicked or tapped
+    pub fn add_toggle(&mut self, state: Rc<Cell<bool>>) {
+        self.handlers.push(EventListener {
+            x_min: 0.0,
+            x_max: 1e20,
+            buttons: 1 << 0 | 1 << 9,
+            item: None,
+            z: None,
+            target: Action::Toggle(state),
+        });
+    }
+
     pub fn add_tooltip(&mut self, desc: PopupDesc) {
         self.hovers.push((0.0, 1e20, desc));
     }
@@ -82,9 +167,17 @@ impl EventSink {
         }
     }
 
+    /// Mark the handlers as drawn on a layer with the given z-index
+    pub fn set_z(&mut self, z: i32) {
+        for h in &mut self.handlers {
+            h.z.get_or_insert(z);
+        }
+    }
+
     pub fn merge(&mut self, sink: Self) {
         self.handlers.extend(sink.handlers);
         self.hovers.extend(sink.hovers);
+        self.styles.extend(sink.styles);
     }
 
     pub fn offset_clamp(&mut self, offset: f32, min: f32, max: f32) {
@@ -102,7 +195,9 @@ impl EventSink {
                 h.x_max = max;
             }
         }
-        for (x_min, x_max, _) in &mut self.hovers {
+        let ranges = self.hovers.iter_mut().map(|(x_min, x_max, _)| (x_min, x_max));
+        let ranges = ranges.chain(self.styles.iter_mut().map(|(x_min, x_max, _)| (x_min, x_max)));
+        for (x_min, x_max) in ranges {
             *x_min += offset;
             *x_max += offset;
             if *x_min < min {
@@ -118,16 +213,26 @@ impl EventSink {
         }
     }
 
-    pub fn button(&self, x: f32, y: f32, button: Button, runtime: &mut Runtime) {
+    /// Find the handlers for a button at this position.
+    ///
+    /// Where items on layers overlap, only the topmost one is used; handlers not on a layer (such
+    /// as those of the group containing the layers) are always used.
+    fn hits(&self, x: f32, button: Button) -> impl Iterator<Item = &EventListener> {
         let button = button as u32;
+        let hit = move |h: &&EventListener| {
+            x >= h.x_min && x <= h.x_max && (h.buttons & (1 << button)) != 0
+        };
+        let top = self.handlers.iter().filter(hit).filter_map(|h| h.z).max();
+        self.handlers
+            .iter()
+            .filter(hit)
+            .filter(move |h| h.z.map_or(true, |z| Some(z) == top))
+    }
+
+    pub fn button(&self, x: f32, y: f32, button: Button, runtime: &mut Runtime) {
         let _ = y;
-        for h in &self.handlers {
-            if x < h.x_min || x > h.x_max {
-                continue;
-            }
-            if (h.buttons & (1 << button)) == 0 {
-                continue;
-            }
+        for h in self.hits(x, button) {
+            let button = button as u32;
             if h.item.is_none() {
                 h.target.invoke(runtime, button);
             } else {
@@ -139,11 +244,38 @@ impl EventSink {
         }
     }
 
+    /// Find the extent of the area that handles a given button at this position
+    pub fn get_click_region(&self, x: f32, button: Button) -> Option<(f32, f32)> {
+        self.hits(x, button)
+            .map(|h| (h.x_min, h.x_max))
+            .reduce(|(a_min, a_max), (b_min, b_max)| (a_min.min(b_min), a_max.max(b_max)))
+    }
+
+    /// True if a click (not a scroll) at this position does anything
+    pub fn clickable(&self, x: f32) -> bool {
+        [Button::Left, Button::Right, Button::Middle]
+            .into_iter()
+            .any(|button| self.hits(x, button).next().is_some())
+    }
+
     #[cfg_attr(not(feature = "dbus"), allow(unused))]
     pub fn add_hover(&mut self, min: f32, max: f32, desc: PopupDesc) {
         self.hovers.push((min, max, desc));
     }
 
+    pub fn add_hover_style(&mut self, min: f32, max: f32, key: HoverKey) {
+        self.styles.push((min, max, key));
+    }
+
+    /// Find the item whose `on-hover` style applies at this position
+    pub fn get_hover_style(&self, x: f32) -> Option<&HoverKey> {
+        // children are added before their parents, so this finds the innermost item
+        self.styles
+            .iter()
+            .find(|&&(min, max, _)| x >= min && x < max)
+            .map(|(_, _, key)| key)
+    }
+
     pub fn get_hover(&mut self, x: f32, y: f32) -> Option<(f32, f32, &mut PopupDesc)> {
         let _ = y;
         for &mut (min, max, ref mut text) in &mut self.hovers {
@@ -156,32 +288,28 @@ impl EventSink {
     }
 
     pub fn for_active_regions(&self, mut f: impl FnMut(f32, f32)) {
-        let mut ha = self.handlers.iter().peekable();
-        let mut ho = self.hovers.iter().peekable();
-        loop {
-            let a_min = ha.peek().map_or(f32::NAN, |e| e.x_min);
-            let o_min = ho.peek().map_or(f32::NAN, |e| e.0);
-            let min = f32::min(a_min, o_min);
-            let mut max;
-            if min == a_min {
-                max = ha.next().unwrap().x_max;
-            } else if min == o_min {
-                max = ho.next().unwrap().1;
+        let mut regions: Vec<_> = self
+            .handlers
+            .iter()
+            .map(|h| (h.x_min, h.x_max))
+            .chain(self.hovers.iter().map(|&(min, max, _)| (min, max)))
+            .chain(self.styles.iter().map(|&(min, max, _)| (min, max)))
+            .collect();
+        regions.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut regions = regions.into_iter();
+        let (mut min, mut max) = match regions.next() {
+            Some(r) => r,
+            None => return,
+        };
+        for (lo, hi) in regions {
+            if lo <= max + 1.0 {
+                max = max.max(hi);
             } else {
-                // NaN
-                return;
+                f(min, max);
+                (min, max) = (lo, hi);
             }
-            loop {
-                if ha.peek().map_or(f32::NAN, |e| e.x_min) <= max + 1.0 {
-                    max = f32::max(max, ha.next().map_or(f32::NAN, |e| e.x_max));
-                } else if ho.peek().map_or(f32::NAN, |e| e.0) <= max + 1.0 {
-                    max = f32::max(max, ho.next().map_or(f32::NAN, |e| e.1));
-                } else {
-                    break;
-                }
-            }
-            f(min, max);
         }
+        f(min, max);
     }
 }
 
@@ -195,9 +323,27 @@ pub enum Action {
         target: String,
         format: String,
     },
+    Adjust {
+        target: String,
+        step: f64,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
     List(Vec<Action>),
     #[cfg(feature = "dbus")]
-    Tray(Rc<tray::TrayItem>),
+    Tray(Rc<tray::TrayItem>, i32),
+    #[cfg(feature = "dbus")]
+    Mpris {
+        target: Box<str>,
+        command: &'static str,
+    },
+    #[cfg(feature = "pulse")]
+    Pulse {
+        target: Box<str>,
+        key: &'static str,
+        value: &'static str,
+    },
+    Toggle(Rc<Cell<bool>>),
     None,
 }
 
@@ -225,13 +371,25 @@ impl Action {
         if let Some(cmd) = value.get("exec").and_then(|v| v.as_str()) {
             return Action::Exec { format: cmd.into() };
         }
+        if let Some(dest) = value.get("adjust").and_then(|v| v.as_str()) {
+            let num = |key| {
+                let v = value.get(key)?;
+                v.as_float().or_else(|| v.as_integer().map(|i| i as f64))
+            };
+            return Action::Adjust {
+                target: dest.into(),
+                step: num("step").unwrap_or(1.0),
+                min: num("min"),
+                max: num("max"),
+            };
+        }
         error!("Unknown action: {}", value);
         Action::None
     }
 
     #[cfg(feature = "dbus")]
-    pub fn from_tray(item: Rc<tray::TrayItem>) -> Self {
-        Action::Tray(item)
+    pub fn from_tray(item: Rc<tray::TrayItem>, scroll_threshold: i32) -> Self {
+        Action::Tray(item, scroll_threshold)
     }
 
     pub fn invoke(&self, runtime: &Runtime, how: u32) {
@@ -262,6 +420,35 @@ impl Action {
                     None => error!("Could not find variable {}", target),
                 }
             }
+            Action::Adjust {
+                target,
+                step,
+                min,
+                max,
+            } => {
+                let (name, key) = match target.find('.') {
+                    Some(p) => (&target[..p], &target[p + 1..]),
+                    None => (&target[..], ""),
+                };
+
+                match runtime.items.get(name) {
+                    Some(item) => {
+                        let current = item.data.read_to_owned(name, key, &runtime).parse_f64();
+                        let mut value = current.or(*min).unwrap_or(0.0) + step;
+                        if let Some(max) = *max {
+                            value = value.min(max);
+                        }
+                        if let Some(min) = *min {
+                            value = value.max(min);
+                        }
+                        if current == Some(value) {
+                            return;
+                        }
+                        item.data.write(name, key, Value::Float(value), &runtime);
+                    }
+                    None => error!("Could not find variable {}", target),
+                }
+            }
             Action::Exec { format } => match runtime.format(&format) {
                 Ok(cmd) => {
                     let cmd = cmd.into_text();
@@ -276,8 +463,20 @@ impl Action {
                 }
             },
             #[cfg(feature = "dbus")]
-            Action::Tray(item) => {
-                tray::do_click(item, how);
+            Action::Tray(item, scroll_threshold) => {
+                tray::do_click(item, how, *scroll_threshold);
+            }
+            #[cfg(feature = "dbus")]
+            Action::Mpris { target, command } => {
+                mpris::write("", target, "", Value::Borrow(command), runtime);
+            }
+            #[cfg(feature = "pulse")]
+            Action::Pulse { target, key, value } => {
+                pulse::do_write("", target, key, Value::Borrow(value), runtime);
+            }
+            Action::Toggle(state) => {
+                state.set(!state.get());
+                NotifierList::active(runtime).notify_data("toggle");
             }
             Action::None => {
                 info!("Invoked a no-op");
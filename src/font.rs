@@ -4,6 +4,7 @@ use crate::render::Render;
 use crate::state::Runtime;
 use crate::util::UID;
 use log::info;
+use std::borrow::Cow;
 use std::fs::File;
 use std::io;
 use std::path::PathBuf;
@@ -61,6 +62,45 @@ pub struct CGlyph<'a> {
     pub color: Color,
 }
 
+/// Escape text so that it is shown as-is when included in markup
+pub fn escape_markup(text: &str) -> Cow<str> {
+    if !text.contains(&['<', '>', '&'] as &[char]) {
+        return Cow::Borrowed(text);
+    }
+    let mut rv = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '<' => rv.push_str("&lt;"),
+            '>' => rv.push_str("&gt;"),
+            '&' => rv.push_str("&amp;"),
+            c => rv.push(c),
+        }
+    }
+    Cow::Owned(rv)
+}
+
+/// Decode a character entity like `&lt;` or `&#x263A;` at the start of the text, returning the
+/// character and the length of the entity
+fn decode_entity(text: &str) -> Option<(char, usize)> {
+    let (end, _) = text.char_indices().take(10).find(|&(_, c)| c == ';')?;
+    let c = match &text[1..end] {
+        "lt" => '<',
+        "gt" => '>',
+        "amp" => '&',
+        "quot" => '"',
+        "apos" => '\'',
+        name => {
+            let num = name.strip_prefix('#')?;
+            let code = match num.strip_prefix('x') {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => num.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some((c, end + 1))
+}
+
 pub fn layout_font<'a>(
     font: &'a FontMapped,
     size_pt: f32,
@@ -124,6 +164,16 @@ pub fn layout_font<'a>(
             if skip > i {
                 return None;
             }
+            let c = match c {
+                '&' if markup => match decode_entity(&text[i..]) {
+                    Some((c, len)) => {
+                        skip = i + len;
+                        c
+                    }
+                    None => c,
+                },
+                c => c,
+            };
             let mut id = fid.as_ref().glyph_index(c).unwrap_or_default();
             if id.0 != 0 {
                 let kern = ttf_parser::Tag::from_bytes(b"kern");
@@ -254,52 +304,97 @@ static HQ_PIXMAP_PAINT: tiny_skia::PixmapPaint = tiny_skia::PixmapPaint {
     quality: tiny_skia::FilterQuality::Bicubic,
 };
 
+/// Render text at the current position without moving it, returning the size of the text.
+///
+/// Popups redraw the same labels every frame, so this goes through the same pixmap cache as
+/// [render_font_item]; only text that changes is laid out again.
 pub fn render_font(ctx: &mut Render, text: &str, markup: bool) -> (f32, f32) {
-    let (mut to_draw, size) = layout_font(
-        ctx.font,
-        ctx.font_size,
-        ctx.runtime,
-        ctx.font_color,
-        text,
-        markup,
-    );
-    let clip_w = ctx.render_extents.1.x - ctx.render_pos.x;
-    if size.1 > clip_w {
-        to_draw.retain(|glyph| glyph.position.0 < clip_w);
+    if text.is_empty() {
+        let (_, size) = layout_font(
+            ctx.font,
+            ctx.font_size,
+            ctx.runtime,
+            ctx.font_color,
+            text,
+            markup,
+        );
+        return size;
     }
-    let xform = ctx
-        .render_xform
-        .pre_translate(ctx.render_pos.x, ctx.render_pos.y);
-    draw_font_with(
-        ctx.canvas,
-        xform,
-        &to_draw,
-        |canvas, path, color| {
-            let paint = tiny_skia::Paint {
-                shader: tiny_skia::Shader::SolidColor(color),
-                anti_alias: true,
-                ..tiny_skia::Paint::default()
-            };
-            canvas.fill_path(
-                &path,
-                &paint,
-                tiny_skia::FillRule::EvenOdd,
-                Transform::identity(),
-                None,
-            );
-        },
-        |canvas, xform, img| {
-            canvas.draw_pixmap(0, 0, img.0.as_ref(), &HQ_PIXMAP_PAINT, xform, None);
-        },
-    );
+    let start = ctx.render_pos;
+    let flex = std::mem::replace(&mut ctx.render_flex, true);
+    render_font_item(ctx, text, markup);
+    ctx.render_flex = flex;
+    let size = (ctx.render_pos.x - start.x, ctx.render_pos.y - start.y);
+    ctx.render_pos = start;
     size
 }
 
+/// Insert line breaks between words so that no line of the text is wider than the given width.
+///
+/// Words wider than the width are left on their own line.
+pub fn wrap_text<'a>(ctx: &Render, text: &'a str, markup: bool, width: f32) -> Cow<'a, str> {
+    let measure = |s: &str| {
+        let (_, size) = layout_font(
+            ctx.font,
+            ctx.font_size,
+            ctx.runtime,
+            ctx.font_color,
+            s,
+            markup,
+        );
+        size.0
+    };
+    if measure(text) <= width {
+        return Cow::Borrowed(text);
+    }
+    let mut rv = String::with_capacity(text.len() + 16);
+    for (i, para) in text.split('\n').enumerate() {
+        if i != 0 {
+            rv.push('\n');
+        }
+        // split at spaces outside markup tags
+        let mut words = Vec::new();
+        let mut in_tag = false;
+        let mut word_start = 0;
+        for (j, c) in para.char_indices() {
+            match c {
+                '<' if markup => in_tag = true,
+                '>' if markup => in_tag = false,
+                ' ' if !in_tag => {
+                    words.push(&para[word_start..j]);
+                    word_start = j + 1;
+                }
+                _ => {}
+            }
+        }
+        words.push(&para[word_start..]);
+
+        let mut line_start = rv.len();
+        for word in words {
+            if rv.len() == line_start {
+                rv.push_str(word);
+                continue;
+            }
+            let end = rv.len();
+            rv.push(' ');
+            rv.push_str(word);
+            if measure(&rv[line_start..]) > width {
+                rv.truncate(end);
+                rv.push('\n');
+                line_start = rv.len();
+                rv.push_str(word);
+            }
+        }
+    }
+    Cow::Owned(rv)
+}
+
 #[derive(Eq, Hash, PartialEq, Debug)]
 pub struct RenderKey {
     x_offset_centipixel: u8,
     y_offset_centipixel: u8,
-    scale: u8,
+    /// In units of 1/120, to match fractional scales from the compositor
+    scale_120: u16,
 
     font: UID,
     font_size_millipt: u32,
@@ -333,8 +428,8 @@ impl RenderKey {
     fn new(ctx: &Render, xform: tiny_skia::Transform, text: &str) -> Option<Self> {
         let xi = (xform.tx * 100.0).round() as u64 % 100;
         let yi = (xform.tx * 100.0).round() as u64 % 100;
-        let scale = xform.sx as u8;
-        if scale as f32 != xform.sy || xform.sx != xform.sy {
+        let scale_120 = (xform.sx * 120.0).round() as u16;
+        if scale_120 as f32 != xform.sx * 120.0 || xform.sx != xform.sy {
             return None;
         }
         let text_stroke_size_milli = ctx
@@ -343,7 +438,7 @@ impl RenderKey {
         Some(RenderKey {
             x_offset_centipixel: xi as u8,
             y_offset_centipixel: yi as u8,
-            scale,
+            scale_120,
             font: ctx.font.uid,
             font_size_millipt: (ctx.font_size * 1000.0).round() as u32,
             font_color: to_color_u32(ctx.font_color),
@@ -0,0 +1,241 @@
+//! JSON fetched periodically from a web server, such as a weather service
+use crate::data::Value;
+use crate::state::{NotifierList, Runtime};
+use crate::util::{spawn_handle, toml_to_f64, Cell};
+use futures_util::future::RemoteHandle;
+use json::JsonValue;
+use log::{debug, error, warn};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::time::{Duration, SystemTime};
+
+/// Delay before the first retry after a failed fetch; this doubles for each failure, up to the
+/// configured interval
+const RETRY_DELAY: Duration = Duration::from_secs(15);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct HttpData {
+    value: Cell<JsonValue>,
+    /// The error from the last fetch, if it failed
+    error: Cell<Option<String>>,
+    interested: Cell<NotifierList>,
+    _task: RemoteHandle<()>,
+}
+
+#[derive(Debug)]
+pub struct Http {
+    url: Box<str>,
+    headers: Vec<(String, String)>,
+    interval: Duration,
+    cache: bool,
+    data: Cell<Option<Rc<HttpData>>>,
+}
+
+impl Http {
+    pub fn from_toml(config: &toml::Value) -> Result<Self, &'static str> {
+        let url = config
+            .get("url")
+            .and_then(|v| v.as_str())
+            .ok_or("An http url is required")?
+            .into();
+        let headers = config
+            .get("headers")
+            .and_then(|v| v.as_table())
+            .into_iter()
+            .flatten()
+            .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_owned())))
+            .collect();
+        let interval = toml_to_f64(config.get("interval"))
+            .filter(|v| !v.is_nan())
+            .unwrap_or(600.0)
+            .clamp(1.0, f64::from(u32::MAX));
+        Ok(Http {
+            url,
+            headers,
+            interval: Duration::from_secs_f64(interval),
+            cache: config
+                .get("cache")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true),
+            data: Cell::new(None),
+        })
+    }
+
+    pub fn init(&self, name: &str, rt: &Runtime, from: Option<&Self>) {
+        let same = |old: &&Self| {
+            old.url == self.url
+                && old.headers == self.headers
+                && old.interval == self.interval
+                && old.cache == self.cache
+        };
+        if let Some(old) = from.filter(same) {
+            // keep the last value across a reload instead of fetching it again
+            if let Some(data) = old.data.take() {
+                self.data.set(Some(data));
+                return;
+            }
+        }
+
+        let cache = if self.cache {
+            match rt
+                .xdg
+                .place_cache_file(format!("rwaybar/http/{}.json", name))
+            {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    error!("Could not create cache file for '{}': {}", name, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // Use the cached response if it was for the same URL; don't fetch again until it is
+        // as old as the interval
+        let mut value = JsonValue::new_object();
+        let mut delay = Duration::ZERO;
+        if let Some((cached, age)) = cache.as_ref().and_then(|path| read_cache(path, &self.url)) {
+            debug!("Using cached response for '{}' from {:?} ago", name, age);
+            value = cached;
+            delay = self.interval.saturating_sub(age);
+        }
+
+        let request = Request {
+            url: self.url.clone(),
+            headers: self.headers.clone(),
+            interval: self.interval,
+        };
+        let name = name.to_owned();
+        self.data.set(Some(Rc::new_cyclic(move |weak| HttpData {
+            value: Cell::new(value),
+            error: Cell::new(None),
+            interested: Default::default(),
+            _task: spawn_handle(
+                "HTTP",
+                fetch_loop(request, delay, cache, name, weak.clone()),
+            ),
+        })));
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        let data = match self.data.take_in_some(|data| data.clone()) {
+            Some(data) => data,
+            None => return f(Value::Null),
+        };
+        data.interested.take_in(|i| i.add(rt));
+        let value = data.value.replace(JsonValue::Null);
+        // keys present in the response take precedence over the diagnostics
+        let rv = match key {
+            "error" if !value.has_key(key) => data
+                .error
+                .take_in(|e| f(e.as_deref().map_or(Value::Null, Value::Borrow))),
+            "ok" if !value.has_key(key) => data.error.take_in(|e| f(Value::Bool(e.is_none()))),
+            "" => f(Value::Null),
+            _ => {
+                // A path into the response, like "main.temp" or "weather.0.description"
+                let mut v = &value;
+                for part in key.split('.') {
+                    v = match part.parse::<usize>() {
+                        Ok(i) if v.is_array() => &v[i],
+                        _ => &v[part],
+                    };
+                }
+                match v {
+                    JsonValue::Null => f(Value::Null),
+                    JsonValue::Boolean(b) => f(Value::Bool(*b)),
+                    JsonValue::Number(_) => f(Value::Float(v.as_f64().unwrap_or_default())),
+                    JsonValue::Object(_) | JsonValue::Array(_) => f(Value::Owned(v.dump())),
+                    v => f(Value::Borrow(v.as_str().unwrap_or(""))),
+                }
+            }
+        };
+        data.value.set(value);
+        rv
+    }
+}
+
+/// Read a cached response, returning it and its age if it was fetched from the given URL
+fn read_cache(path: &Path, url: &str) -> Option<(JsonValue, Duration)> {
+    let text = fs::read_to_string(path).ok()?;
+    let mut cached = json::parse(&text).ok()?;
+    if cached["url"].as_str() != Some(url) {
+        return None;
+    }
+    let age = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+        .unwrap_or(Duration::MAX);
+    Some((cached.remove("value"), age))
+}
+
+#[derive(Debug)]
+struct Request {
+    url: Box<str>,
+    headers: Vec<(String, String)>,
+    interval: Duration,
+}
+
+async fn fetch_loop(
+    request: Request,
+    mut delay: Duration,
+    cache: Option<PathBuf>,
+    name: String,
+    data: Weak<HttpData>,
+) -> Result<(), Box<dyn Error>> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("rwaybar/", env!("CARGO_PKG_VERSION")))
+        .timeout(TIMEOUT)
+        .build()?;
+    let mut failures = 0;
+    loop {
+        tokio::time::sleep(delay).await;
+        let result = fetch(&client, &request).await;
+        let shared = match data.upgrade() {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+        match result {
+            Ok(value) => {
+                failures = 0;
+                delay = request.interval;
+                if let Some(path) = &cache {
+                    let mut cached = JsonValue::new_object();
+                    cached["url"] = (*request.url).into();
+                    cached["value"] = value.clone();
+                    if let Err(e) = fs::write(path, cached.dump()) {
+                        debug!("Could not write {}: {}", path.display(), e);
+                    }
+                }
+                shared.value.set(value);
+                shared.error.set(None);
+            }
+            Err(e) => {
+                // keep the last good value, and try again sooner than usual
+                warn!("Could not fetch '{}' for '{}': {}", request.url, name, e);
+                failures = (failures + 1).min(8);
+                delay = (RETRY_DELAY * (1 << (failures - 1))).min(request.interval);
+                shared.error.set(Some(e.to_string()));
+            }
+        }
+        shared.interested.take().notify_data("http");
+    }
+}
+
+async fn fetch(client: &reqwest::Client, request: &Request) -> Result<JsonValue, Box<dyn Error>> {
+    let mut req = client.get(&*request.url);
+    for (k, v) in &request.headers {
+        req = req.header(k, v);
+    }
+    let text = req.send().await?.error_for_status()?.text().await?;
+    Ok(json::parse(&text)?)
+}
@@ -1,15 +1,34 @@
 use crate::render::Render;
+use crate::state::NotifierList;
+use crate::util;
+use log::debug;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io;
-use std::path::{Component, PathBuf};
-use tiny_skia::Transform;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tiny_skia::{Color, Transform};
 
 thread_local! {
-    static CACHE : RefCell<HashMap<(String, u32), Option<OwnedImage>>> = Default::default();
+    static CACHE : RefCell<HashMap<CacheKey, Option<OwnedImage>>> = Default::default();
+    static DISK_CACHE: RefCell<Option<PathBuf>> = Default::default();
+    /// Images that were provided directly instead of as files, in each size available
+    static PIXMAPS: RefCell<HashMap<String, Vec<OwnedImage>>> = Default::default();
+    /// Icon files being found and decoded on the blocking thread pool, with the bars to redraw
+    /// when they are ready
+    static LOADING: RefCell<HashMap<CacheKey, NotifierList>> = Default::default();
 }
 
+/// The name, height, and recolor of a rendered icon
+type CacheKey = (String, u32, Option<[u8; 4]>);
+
+/// Identifies the files in the disk cache (and their format version)
+const CACHE_MAGIC: &[u8; 8] = b"rwbicon2";
+/// The magic, modification time, size, and path length; the path follows, then the pixels
+const CACHE_HEADER: usize = 32;
+
 #[derive(Debug)]
 pub struct OwnedImage(pub tiny_skia::Pixmap);
 
@@ -73,7 +92,19 @@ impl OwnedImage {
         let scale = height as f32 / self.0.height() as f32;
         let xform = Transform::from_scale(scale, scale);
         let px_width = (self.0.width() as f32 * scale).ceil() as u32;
-        let mut pixmap = tiny_skia::Pixmap::new(px_width, height).unwrap();
+        let mut pixmap = match tiny_skia::Pixmap::new(px_width, height) {
+            Some(pixmap) => pixmap,
+            None => {
+                // the pixmap would be empty or too large; draw the icon at its own size instead
+                debug!(
+                    "Could not rescale a {}x{} icon to height {}",
+                    self.0.width(),
+                    self.0.height(),
+                    height
+                );
+                return self;
+            }
+        };
 
         pixmap.draw_pixmap(
             0,
@@ -91,6 +122,31 @@ impl OwnedImage {
         Self(pixmap)
     }
 
+    /// Replace the color of every pixel, keeping only the alpha (shape) of the original
+    ///
+    /// This is intended for monochrome "symbolic" icons.
+    pub fn recolor(mut self, color: tiny_skia::ColorU8) -> Self {
+        for pixel in self.0.pixels_mut() {
+            let alpha = (pixel.alpha() as u16 * color.alpha() as u16 / 255) as u8;
+            *pixel =
+                tiny_skia::ColorU8::from_rgba(color.red(), color.green(), color.blue(), alpha)
+                    .premultiply();
+        }
+        self
+    }
+
+    /// Convert ARGB32 pixels in network byte order, as used by the StatusNotifierItem spec
+    pub fn from_argb32(width: u32, height: u32, data: &[u8]) -> Option<Self> {
+        let mut pixmap = tiny_skia::Pixmap::new(width, height)?;
+        if data.len() < pixmap.pixels().len() * 4 {
+            return None;
+        }
+        for (src, pixel) in data.chunks_exact(4).zip(pixmap.pixels_mut()) {
+            *pixel = tiny_skia::ColorU8::from_rgba(src[1], src[2], src[3], src[0]).premultiply();
+        }
+        Some(Self(pixmap))
+    }
+
     pub fn from_svg(data: &[u8], height: u32) -> Option<Self> {
         use usvg::TreeParsing;
         let tree = usvg::Tree::from_data(data, &usvg::Options::default()).ok()?;
@@ -107,10 +163,177 @@ impl OwnedImage {
     }
 }
 
-fn open_icon(xdg: &xdg::BaseDirectories, name: &str, target_size: u32) -> io::Result<PathBuf> {
-    if name.contains('/') {
-        return Ok(PathBuf::from(name.to_owned()));
+/// Make images (such as a tray item's IconPixmap) available as an icon with the given name.  Any
+/// renderings of the previous images with this name are discarded.  An empty list removes them.
+pub fn set_pixmaps(name: &str, images: Vec<OwnedImage>) {
+    CACHE.with(|cache| cache.borrow_mut().retain(|(n, _, _), _| n != name));
+    PIXMAPS.with(|pixmaps| {
+        let mut pixmaps = pixmaps.borrow_mut();
+        if images.is_empty() {
+            pixmaps.remove(name);
+        } else {
+            pixmaps.insert(name.into(), images);
+        }
+    });
+}
+
+/// Scale the best of the images registered with [set_pixmaps] to the given height: the smallest
+/// one that is at least that tall, or the largest if none are.
+fn load_pixmap(name: &str, tsize: u32) -> Option<OwnedImage> {
+    PIXMAPS.with(|pixmaps| {
+        let pixmaps = pixmaps.borrow();
+        let images = pixmaps.get(name)?;
+        let best = images
+            .iter()
+            .filter(|img| img.0.height() >= tsize)
+            .min_by_key(|img| img.0.height())
+            .or_else(|| images.iter().max_by_key(|img| img.0.height()))?;
+        Some(OwnedImage(best.0.clone()).rescale_height(tsize))
+    })
+}
+
+/// Store decoded icons in this directory, or stop doing so if None
+pub fn set_disk_cache(dir: Option<PathBuf>) {
+    DISK_CACHE.with(|cache| *cache.borrow_mut() = dir);
+}
+
+/// Decode an icon file at the given height, reusing the copy in the disk cache (`dir`) if one
+/// was stored since the file was last modified.
+fn load_file(path: &Path, tsize: u32, dir: Option<&Path>) -> Option<OwnedImage> {
+    let mtime = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let cached = dir.zip(mtime).map(|(dir, mtime)| {
+        let stamp = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        (dir.join(cache_name(path, tsize)), stamp)
+    });
+    if let Some((file, stamp)) = &cached {
+        if let Some(img) = read_cached(file, path, *stamp) {
+            return Some(img);
+        }
+    }
+    let img = OwnedImage::from_file(File::open(path).ok()?, tsize, true)?;
+    if let Some((file, stamp)) = &cached {
+        write_cached(file, path, *stamp, &img);
+    }
+    Some(img)
+}
+
+/// The name of the disk cache file for an icon file at a given height.  This uses FNV-1a,
+/// which (unlike the standard library's hasher) gives the same name in every build.
+fn cache_name(path: &Path, tsize: u32) -> String {
+    let size = tsize.to_le_bytes();
+    let bytes = path.as_os_str().as_bytes().iter().chain(&size);
+    let hash = bytes.fold(0xcbf29ce484222325u64, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+fn read_cached(file: &Path, path: &Path, stamp: Duration) -> Option<OwnedImage> {
+    let data = fs::read(file).ok()?;
+    if data.len() < CACHE_HEADER || &data[..8] != CACHE_MAGIC {
+        return None;
+    }
+    let u32_at = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+    let secs = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    // an entry from before the icon file was changed
+    if secs != stamp.as_secs() || u32_at(16) != stamp.subsec_nanos() {
+        return None;
+    }
+    // or for another file whose name has the same hash
+    let pixels_at = CACHE_HEADER + u32_at(28) as usize;
+    if data.get(CACHE_HEADER..pixels_at) != Some(path.as_os_str().as_bytes()) {
+        return None;
+    }
+    let size = tiny_skia::IntSize::from_wh(u32_at(20), u32_at(24))?;
+    let pixels = data[pixels_at..].to_vec();
+    tiny_skia::Pixmap::from_vec(pixels, size).map(OwnedImage)
+}
+
+fn write_cached(file: &Path, path: &Path, stamp: Duration, img: &OwnedImage) {
+    let path = path.as_os_str().as_bytes();
+    let mut data = Vec::with_capacity(CACHE_HEADER + path.len() + img.0.data().len());
+    data.extend_from_slice(CACHE_MAGIC);
+    data.extend_from_slice(&stamp.as_secs().to_le_bytes());
+    data.extend_from_slice(&stamp.subsec_nanos().to_le_bytes());
+    data.extend_from_slice(&img.0.width().to_le_bytes());
+    data.extend_from_slice(&img.0.height().to_le_bytes());
+    data.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    data.extend_from_slice(path);
+    data.extend_from_slice(img.0.data());
+    if let Err(e) = fs::write(file, data) {
+        debug!("Could not write {}: {}", file.display(), e);
+    }
+}
+
+/// Find `name` (without an extension) in a directory of icons
+fn icon_file(mut path: PathBuf, name: &str) -> Option<PathBuf> {
+    path.push(name);
+    // We can't use set_extension here because of icon names like "org.atheme.audacious" which
+    // would turn into "org.atheme.svg" instead of "org.atheme.audacious.svg"
+    path.as_mut_os_string().push(".svg");
+    if path.is_file() {
+        return Some(path);
+    }
+    path.set_extension("png");
+    if path.is_file() {
+        return Some(path);
+    }
+    None
+}
+
+/// Find an icon in a directory provided by an application, such as a tray item's
+/// IconThemePath.  This may contain the icons directly, or be an icon theme with size
+/// subdirectories (like `22x22/apps`), or contain such themes (like `hicolor/22x22/apps`).
+fn find_in_app_path(dir: &Path, name: &str, target_size: u32) -> Option<PathBuf> {
+    if let Some(rv) = icon_file(dir.to_owned(), name) {
+        return Some(rv);
+    }
+    let f = |path| icon_file(path, name);
+    if let Ok(Some(rv)) = iter_icons(dir, target_size, f) {
+        return Some(rv);
     }
+    let mut themes: Vec<_> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .collect();
+    themes.sort();
+    themes
+        .iter()
+        .find_map(|theme| iter_icons(theme, target_size, f).ok().flatten())
+}
+
+/// Find the file for an icon.
+///
+/// A name containing a slash is a path, which may be a tray item's IconThemePath joined with
+/// its icon name.  Icons are looked up in this order:
+///  1. The path itself, with any of the usual extensions
+///  2. The application's directory (the part of the path before the icon name)
+///  3. The system pixmaps and icon theme, using only the icon name
+fn open_icon(xdg: &xdg::BaseDirectories, name: &str, target_size: u32) -> io::Result<PathBuf> {
+    let name = match name.rsplit_once('/') {
+        Some((dir, base)) => {
+            let path = PathBuf::from(name);
+            for ext in ["", ".svg", ".png"] {
+                let mut path = path.clone();
+                path.as_mut_os_string().push(ext);
+                if path.is_file() {
+                    return Ok(path);
+                }
+            }
+            if !dir.is_empty() {
+                if let Some(rv) = find_in_app_path(Path::new(dir), base, target_size) {
+                    return Ok(rv);
+                }
+            }
+            if base.is_empty() {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            base
+        }
+        None => name,
+    };
 
     // return paths in order from highest to lowest priority, unlike how the xdg crate does it
     // (sadly that crate doesn't support DoubleEndedIterator yet)
@@ -119,26 +342,7 @@ fn open_icon(xdg: &xdg::BaseDirectories, name: &str, target_size: u32) -> io::Re
         dirs.into_iter().rev()
     };
 
-    let f = |mut path: PathBuf| {
-        path.push(name);
-        // We can't use set_extension here because of icon names like "org.atheme.audacious" which
-        // would turn into "org.atheme.svg" instead of "org.atheme.audacious.svg"
-        path.as_mut_os_string().push(".svg");
-        match File::open(&path) {
-            Ok(_) => {
-                return Some(path);
-            }
-            Err(_) => {}
-        }
-        path.set_extension("png");
-        match File::open(&path) {
-            Ok(_) => {
-                return Some(path);
-            }
-            Err(_) => {}
-        }
-        None
-    };
+    let f = |path| icon_file(path, name);
 
     for path in find_data("pixmaps") {
         match f(path) {
@@ -157,7 +361,7 @@ fn open_icon(xdg: &xdg::BaseDirectories, name: &str, target_size: u32) -> io::Re
     Err(io::ErrorKind::NotFound.into())
 }
 
-fn iter_icons<F, R>(base: &PathBuf, target_size: u32, mut f: F) -> io::Result<Option<R>>
+fn iter_icons<F, R>(base: &Path, target_size: u32, mut f: F) -> io::Result<Option<R>>
 where
     F: FnMut(PathBuf) -> Option<R>,
 {
@@ -210,7 +414,66 @@ where
     Ok(None)
 }
 
-pub fn render(ctx: &mut Render, name: &str) -> Result<(), ()> {
+/// Find and decode the icon with the given name.  This reads from the disk, so it runs on the
+/// blocking thread pool.
+fn load_icon(
+    xdg: &xdg::BaseDirectories,
+    name: &str,
+    tsize: u32,
+    disk_cache: Option<&Path>,
+) -> Option<OwnedImage> {
+    let mut path = open_icon(xdg, name, tsize).ok()?;
+    if !path.is_file() {
+        path.as_mut_os_string().push(".svg");
+    }
+    if !path.is_file() {
+        path.set_extension("svg");
+    }
+    if !path.is_file() {
+        return None;
+    }
+    load_file(&path, tsize, disk_cache)
+}
+
+/// Start loading an icon from the disk, redrawing the current bar once it is in the cache
+fn start_load(ctx: &Render, key: &CacheKey, recolor: Option<tiny_skia::ColorU8>) {
+    let rt = ctx.runtime;
+    let started = LOADING.with(|loading| {
+        let mut loading = loading.borrow_mut();
+        let started = loading.contains_key(key);
+        loading.entry(key.clone()).or_default().add(rt);
+        started
+    });
+    if started {
+        return;
+    }
+    let xdg = rt.xdg.clone();
+    let disk_cache = DISK_CACHE.with(|cache| cache.borrow().clone());
+    let key = key.clone();
+    let (name, tsize) = (key.0.clone(), key.1);
+    util::spawn_noerr(async move {
+        let img = tokio::task::spawn_blocking(move || {
+            let img = load_icon(&xdg, &name, tsize, disk_cache.as_deref())?;
+            Some(match recolor {
+                Some(color) => img.recolor(color),
+                None => img,
+            })
+        })
+        .await
+        .ok()
+        .flatten();
+        let notify = LOADING.with(|loading| loading.borrow_mut().remove(&key));
+        // Images set while this was loading take priority over the file
+        if !PIXMAPS.with(|pixmaps| pixmaps.borrow().contains_key(&key.0)) {
+            CACHE.with(|cache| cache.borrow_mut().insert(key, img));
+        }
+        if let Some(mut notify) = notify {
+            notify.notify_data("icon");
+        }
+    });
+}
+
+pub fn render(ctx: &mut Render, name: &str, recolor: Option<Color>) -> Result<(), ()> {
     let xform = ctx.render_xform;
     let mut extent_points = [ctx.render_pos, ctx.render_extents.1];
     xform.map_points(&mut extent_points);
@@ -221,30 +484,29 @@ pub fn render(ctx: &mut Render, name: &str) -> Result<(), ()> {
         return Err(());
     }
 
+    let recolor = recolor.map(|c| c.to_color_u8());
+    let key_color = recolor.map(|c| [c.red(), c.green(), c.blue(), c.alpha()]);
+
+    let key = (name.into(), tsize, key_color);
     CACHE.with(|cache| {
         let mut cache = cache.borrow_mut();
-        match cache.entry((name.into(), tsize)).or_insert_with(|| {
-            open_icon(&ctx.runtime.xdg, name, tsize)
-                .ok()
-                .and_then(|mut path| {
-                    match File::open(&path) {
-                        Ok(file) => return Some(file),
-                        _ => {}
-                    }
-                    path.as_mut_os_string().push(".svg");
-                    match File::open(&path) {
-                        Ok(file) => return Some(file),
-                        _ => {}
-                    }
-                    path.set_extension("svg");
-                    match File::open(&path) {
-                        Ok(file) => return Some(file),
-                        _ => {}
-                    }
-                    None
-                })
-                .and_then(|file| OwnedImage::from_file(file, tsize, true))
-        }) {
+        if !cache.contains_key(&key) {
+            match load_pixmap(name, tsize) {
+                Some(img) => {
+                    let img = match recolor {
+                        Some(color) => img.recolor(color),
+                        None => img,
+                    };
+                    cache.insert(key.clone(), Some(img));
+                }
+                None => {
+                    // Nothing is drawn until the file is loaded
+                    start_load(ctx, &key, recolor);
+                    return Ok(());
+                }
+            }
+        }
+        match &cache[&key] {
             Some(img) => {
                 ctx.canvas.draw_pixmap(
                     extent_points[0].x.round() as i32,
@@ -263,3 +525,38 @@ pub fn render(ctx: &mut Render, name: &str) -> Result<(), ()> {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_cache_entries() {
+        assert_eq!(
+            cache_name(Path::new("/usr/share/icons/a.svg"), 16),
+            cache_name(Path::new("/usr/share/icons/a.svg"), 16)
+        );
+        assert_ne!(
+            cache_name(Path::new("/usr/share/icons/a.svg"), 16),
+            cache_name(Path::new("/usr/share/icons/a.svg"), 24)
+        );
+
+        let dir = std::env::temp_dir().join(format!("rwaybar-icon-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("entry");
+        let icon = Path::new("/usr/share/icons/a.svg");
+        let stamp = Duration::new(1700000000, 5);
+        let mut pixmap = tiny_skia::Pixmap::new(2, 3).unwrap();
+        pixmap.fill(tiny_skia::Color::from_rgba8(255, 0, 0, 255));
+        write_cached(&file, icon, stamp, &OwnedImage(pixmap.clone()));
+
+        let read = read_cached(&file, icon, stamp).unwrap();
+        assert_eq!((read.0.width(), read.0.height()), (2, 3));
+        assert_eq!(read.0.data(), pixmap.data());
+        // another file that would have been stored under the same name
+        assert!(read_cached(&file, Path::new("/usr/share/icons/b.svg"), stamp).is_none());
+        // the icon file was modified
+        assert!(read_cached(&file, icon, Duration::new(1700000000, 6)).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,22 @@
 //! Graphical rendering of an [Item]
+use crate::condition;
 use crate::data::{ItemReference, IterationItem, Module, ModuleContext, Value};
-use crate::event::EventSink;
-use crate::font::{render_font, render_font_item};
+use crate::event::{EventSink, HoverKey};
+use crate::font::{render_font, render_font_item, wrap_text};
 use crate::icon;
 use crate::render::{Align, Render, Width};
-use crate::state::Runtime;
+use crate::state::{NotifierList, Runtime};
 #[cfg(feature = "dbus")]
 use crate::tray;
+use crate::util::{spawn_handle, toml_to_f64, Cell, UID};
 use crate::wayland::Button;
+use futures_util::future::RemoteHandle;
 use log::{debug, error, warn};
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tiny_skia::{Color, Point};
 
 /// A visible item in a bar
@@ -26,7 +32,311 @@ pub struct Item {
 pub struct ItemFormat {
     markup: bool,
     oneline: bool,
+    flash: bool,
+    flash_state: Cell<Vec<FlashState>>,
+    flash_redraw: RedrawTimer,
+    cache: bool,
+    cached: Cell<Vec<CachedRender>>,
+    /// How numbers from this item are rounded when used in a text expansion
+    pub round: Option<Round>,
+    /// Escape text from this item for markup when used in a text expansion
+    pub escape_markup: bool,
+    /// Items in a group with a higher z-index are drawn over those with a lower one
+    pub z_index: i32,
+    blink: Option<Blink>,
     cfg: Option<toml::Value>,
+    /// The formatting to use while the pointer is over the item
+    hover: Option<Box<ItemFormat>>,
+}
+
+/// The last text shown by an item on one bar, for `on-change = "flash"`
+#[derive(Debug)]
+struct FlashState {
+    bar: Option<UID>,
+    text: Box<str>,
+    start: Option<Instant>,
+}
+
+const FLASH_TIME: Duration = Duration::from_millis(300);
+
+/// How often to redraw an item while it is flashing
+const FLASH_FRAME: Duration = Duration::from_millis(20);
+
+/// Redraws the bars an item was drawn on after a delay, to animate it.  Drawing the item again
+/// before the timer fires adds the current bar to it instead of starting another one.
+#[derive(Debug, Default)]
+struct RedrawTimer {
+    pending: Rc<Cell<Option<NotifierList>>>,
+    timer: Cell<Option<RemoteHandle<()>>>,
+}
+
+impl RedrawTimer {
+    fn redraw_after(&self, rt: &Runtime, delay: Duration, who: &'static str) {
+        let mut pending = self.pending.take();
+        let armed = pending.is_some();
+        pending.get_or_insert_with(NotifierList::default).add(rt);
+        self.pending.set(pending);
+        if armed {
+            return;
+        }
+        let pending = self.pending.clone();
+        let timer = spawn_handle("Redraw timer", async move {
+            tokio::time::sleep(delay).await;
+            if let Some(mut notify) = pending.take() {
+                notify.notify_data(who);
+            }
+            Ok(())
+        });
+        self.timer.set(Some(timer));
+    }
+}
+
+/// How often to redraw a blinking item
+const BLINK_FRAME: Duration = Duration::from_millis(40);
+
+/// The `blink` key: pulse the opacity of the item while a condition is true
+#[derive(Debug)]
+struct Blink {
+    condition: Box<str>,
+    /// Seconds for one full pulse
+    period: f64,
+    min: f32,
+    max: f32,
+    redraw: RedrawTimer,
+    /// The layer from the last frame, reused if the canvas is the same size
+    layer: Cell<Option<tiny_skia::Pixmap>>,
+}
+
+impl Blink {
+    fn from_toml(config: &toml::Value) -> Option<Self> {
+        let condition = match config.get("blink")? {
+            toml::Value::String(s) => s.as_str().into(),
+            _ => {
+                error!("The blink condition must be a string");
+                return None;
+            }
+        };
+        let alpha = |key| toml_to_f64(config.get(key)).map(|v| v.clamp(0.0, 1.0) as f32);
+        Some(Blink {
+            condition,
+            period: toml_to_f64(config.get("blink-period"))
+                .filter(|&p| p > 0.0)
+                .unwrap_or(1.0),
+            min: alpha("blink-min-alpha").unwrap_or(0.2),
+            max: alpha("blink-max-alpha").unwrap_or(1.0),
+            redraw: RedrawTimer::default(),
+            layer: Cell::default(),
+        })
+    }
+
+    /// The opacity to draw the item with now, or None if it is not blinking
+    fn opacity(&self, rt: &Runtime) -> Option<f32> {
+        match condition::eval(rt, &self.condition) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => {
+                warn!(
+                    "Error evaluating blink condition '{}': {}",
+                    self.condition, e
+                );
+                return None;
+            }
+        }
+        // use the wall clock so that all blinking items pulse together
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let phase = (now % self.period) / self.period;
+        let level = 0.5 + 0.5 * (phase * std::f64::consts::TAU).cos();
+
+        // redraw this bar again soon to continue the pulse; this stops once the condition is false
+        self.redraw.redraw_after(rt, BLINK_FRAME, "blink");
+        Some(self.min + (self.max - self.min) * level as f32)
+    }
+}
+
+/// The `round` key: how to drop the digits not shown by a format's precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Round {
+    Nearest,
+    Floor,
+    Ceil,
+    Trunc,
+}
+
+impl Round {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "round" | "nearest" => Some(Round::Nearest),
+            "floor" => Some(Round::Floor),
+            "ceil" => Some(Round::Ceil),
+            "trunc" | "truncate" => Some(Round::Trunc),
+            _ => None,
+        }
+    }
+
+    /// Round the value to the given number of decimal places
+    pub fn apply(self, v: f64, precision: usize) -> f64 {
+        let scale = 10f64.powi(precision.min(15) as i32);
+        // Values like 0.29 are slightly below their scaled integer (28.999...); don't let floor
+        // and trunc push those down a whole step.
+        let scaled = v * scale;
+        let nearest = scaled.round();
+        let scaled = if (scaled - nearest).abs() < 1e-9 {
+            nearest
+        } else {
+            scaled
+        };
+        let rounded = match self {
+            Round::Nearest => scaled.round(),
+            Round::Floor => scaled.floor(),
+            Round::Ceil => scaled.ceil(),
+            Round::Trunc => scaled.trunc(),
+        };
+        rounded / scale
+    }
+}
+
+/// The height of the icons in the popup of a tray with a `drawer`
+#[cfg(feature = "dbus")]
+const DRAWER_ICON_SIZE: f32 = 24.0;
+
+/// The `separator` key of a group: drawn between each pair of visible items
+#[derive(Debug)]
+pub enum Separator {
+    Item(Rc<Item>),
+    /// A line across the group with the given thickness
+    Line {
+        width: f32,
+        color: Option<Color>,
+    },
+}
+
+/// A separator rendered once per group render, then drawn in every gap
+struct SeparatorImage {
+    image: Option<tiny_skia::Pixmap>,
+    color: Option<Color>,
+    /// Size along the group's axis
+    size: f32,
+}
+
+impl Separator {
+    pub fn from_toml(value: &toml::Value) -> Self {
+        match value.get("line") {
+            Some(width) => Separator::Line {
+                width: toml_to_f64(Some(width)).unwrap_or(1.0) as f32,
+                color: Formatting::parse_rgba(
+                    value.get("color").and_then(|v| v.as_str()),
+                    toml_to_f64(value.get("alpha")).map(|a| a as f32),
+                ),
+            },
+            None => Separator::Item(Rc::new(Item::from_toml_format(value))),
+        }
+    }
+
+    fn prepare(&self, ctx: &mut Render, vertical: bool) -> SeparatorImage {
+        match self {
+            Separator::Line { width, color } => SeparatorImage {
+                image: None,
+                color: *color,
+                size: *width,
+            },
+            Separator::Item(item) => {
+                let x_max = ctx.render_extents.1.x - ctx.render_pos.x;
+                // events are discarded: the separator is not interactive
+                let (image, _, end) = ctx.with_new_canvas_x(Point::zero(), x_max, |ctx| {
+                    item.render(ctx);
+                    ctx.render_pos
+                });
+                SeparatorImage {
+                    image: Some(image),
+                    color: None,
+                    size: if vertical { end.y } else { end.x }.ceil(),
+                }
+            }
+        }
+    }
+}
+
+impl SeparatorImage {
+    /// Draw the separator at `at`, spanning the group up to `bounds` in the other direction
+    fn draw(&self, ctx: &mut Render, at: Point, bounds: Point, vertical: bool) {
+        match &self.image {
+            Some(image) => {
+                let x = at.x * ctx.render_xform.sx;
+                let y = at.y * ctx.render_xform.sy;
+                ctx.canvas.draw_pixmap(
+                    x as i32,
+                    y as i32,
+                    image.as_ref(),
+                    &Default::default(),
+                    tiny_skia::Transform::identity(),
+                    None,
+                );
+            }
+            None => {
+                let rect = if vertical {
+                    tiny_skia::Rect::from_xywh(at.x, at.y, bounds.x - at.x, self.size)
+                } else {
+                    tiny_skia::Rect::from_xywh(at.x, at.y, self.size, bounds.y - at.y)
+                };
+                if let Some(rect) = rect {
+                    let paint = tiny_skia::Paint {
+                        shader: tiny_skia::Shader::SolidColor(self.color.unwrap_or(ctx.font_color)),
+                        ..tiny_skia::Paint::default()
+                    };
+                    ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
+                }
+            }
+        }
+    }
+}
+
+/// A rendering of an item with `cache = true` on one bar, reused until its data changes
+#[derive(Debug)]
+struct CachedRender {
+    bar: UID,
+    iter: Option<IterationItem>,
+    /// Used in place of the bar's ID while rendering, so that changes to the data read by the
+    /// item invalidate this cache (see [Runtime::add_render_cache])
+    id: UID,
+    valid: Rc<Cell<bool>>,
+    key: CacheKey,
+    image: tiny_skia::Pixmap,
+    draw_at: (i32, i32),
+    end: Point,
+    events: EventSink,
+}
+
+/// Everything from the parent context that influences how an item renders
+#[derive(Debug, PartialEq)]
+struct CacheKey {
+    pos: Point,
+    extents: (Point, Point),
+    scale: (f32, f32),
+    font: usize,
+    font_size: f32,
+    font_color: Color,
+    text_stroke: Option<(Color, Option<f32>)>,
+    align: Align,
+    hovered: Option<HoverKey>,
+}
+
+impl CacheKey {
+    fn new(ctx: &Render) -> Self {
+        CacheKey {
+            pos: ctx.render_pos,
+            extents: ctx.render_extents,
+            scale: (ctx.render_xform.sx, ctx.render_xform.sy),
+            font: ctx.font as *const _ as usize,
+            font_size: ctx.font_size,
+            font_color: ctx.font_color,
+            text_stroke: ctx.text_stroke.map(|c| (c, ctx.text_stroke_size)),
+            align: ctx.align,
+            hovered: ctx.runtime.hovered(),
+        }
+    }
 }
 
 impl ItemFormat {
@@ -40,6 +350,38 @@ impl ItemFormat {
             .get("oneline")
             .and_then(|v| v.as_bool())
             .unwrap_or(false);
+        rv.flash = match config.get("on-change").and_then(|v| v.as_str()) {
+            None => false,
+            Some("flash") => true,
+            Some(v) => {
+                error!("Unknown on-change action '{}'", v);
+                false
+            }
+        };
+        rv.cache = config
+            .get("cache")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        rv.escape_markup = config
+            .get("escape-markup")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        rv.z_index = config
+            .get("z-index")
+            .and_then(|v| v.as_integer())
+            .and_then(|v| v.try_into().ok())
+            .unwrap_or(0);
+        rv.blink = Blink::from_toml(config);
+        rv.round = match config.get("round").and_then(|v| v.as_str()) {
+            None => None,
+            Some(v) => {
+                let round = Round::parse(v);
+                if round.is_none() {
+                    error!("Unknown rounding mode '{}'", v);
+                }
+                round
+            }
+        };
 
         rv.cfg = config
             .as_table()
@@ -48,8 +390,10 @@ impl ItemFormat {
                     .filter(|(k, _)| match &***k {
                         "align" | "bg" | "bg-alpha" | "border" | "border-alpha"
                         | "border-color" | "fg" | "fg-alpha" | "font" | "halign" | "margin"
-                        | "max-width" | "min-width" | "padding" | "text-outline"
-                        | "text-outline-alpha" | "text-outline-width" | "valign" => true,
+                        | "max-width" | "min-width" | "overline" | "overline-alpha"
+                        | "overline-width" | "padding" | "text-outline" | "text-outline-alpha"
+                        | "text-outline-width" | "underline" | "underline-alpha"
+                        | "underline-width" | "valign" => true,
                         _ => false,
                     })
                     .map(|(k, v)| (k.clone(), v.clone()))
@@ -58,6 +402,19 @@ impl ItemFormat {
             .filter(|m| !m.is_empty())
             .map(toml::Value::Table);
 
+        if let Some(hover) = config.get("on-hover").and_then(|v| v.as_table()) {
+            // the hover style only needs to specify what changes
+            let mut cfg = match &rv.cfg {
+                Some(toml::Value::Table(t)) => t.clone(),
+                _ => Default::default(),
+            };
+            cfg.extend(hover.iter().map(|(k, v)| (k.clone(), v.clone())));
+            let mut hover = ItemFormat::from_toml(&toml::Value::Table(cfg));
+            hover.markup = rv.markup;
+            hover.oneline = rv.oneline;
+            rv.hover = Some(Box::new(hover));
+        }
+
         rv
     }
 
@@ -65,6 +422,60 @@ impl ItemFormat {
         self.cfg.is_none()
     }
 
+    /// Compare the text with the text from the last render on this bar, returning the strength
+    /// (1.0 fading to 0.0) of the flash to draw, if any.
+    fn flash_level(&self, rt: &Runtime, text: &str) -> Option<f32> {
+        if !self.flash {
+            return None;
+        }
+        let bar = rt.render_bar();
+        let now = Instant::now();
+        let level = self.flash_state.take_in(|states| {
+            let state = match states.iter_mut().find(|s| s.bar == bar) {
+                Some(state) => state,
+                None => {
+                    // first render; nothing to compare against.  This is also when earlier
+                    // bars may have been closed, such as when an output is unplugged.
+                    states.retain(|s| s.bar.map_or(true, |id| rt.bar_exists(id)));
+                    states.push(FlashState {
+                        bar,
+                        text: text.into(),
+                        start: None,
+                    });
+                    return None;
+                }
+            };
+            if *state.text != *text {
+                state.text = text.into();
+                state.start = Some(now);
+            }
+            let elapsed = now.duration_since(state.start?);
+            if elapsed >= FLASH_TIME {
+                state.start = None;
+                return None;
+            }
+            Some(1.0 - elapsed.as_secs_f32() / FLASH_TIME.as_secs_f32())
+        });
+        if level.is_some() {
+            // redraw this bar again soon to continue fading out
+            self.flash_redraw.redraw_after(rt, FLASH_FRAME, "flash");
+        }
+        level
+    }
+
+    /// True if the item's background (and border, if any) is drawn fully opaque, so that
+    /// everything drawn over it is too.  This is false if it is translucent or missing, or if it
+    /// may change because the item blinks or has a hover format.
+    pub fn has_opaque_bg(&self, runtime: &Runtime) -> bool {
+        let config = match &self.cfg {
+            Some(config) if self.blink.is_none() && self.hover.is_none() => config,
+            _ => return false,
+        };
+        let fmt = Formatting::expand(config, runtime);
+        let opaque = |rgba: Option<Color>| rgba.map_or(false, |c| c.is_opaque());
+        opaque(fmt.bg_rgba) && (fmt.border.is_none() || opaque(fmt.border_rgba))
+    }
+
     pub fn setup_ctx<'a, 'p: 'a, 'c>(
         &self,
         ctx: &'a mut Render<'p, 'c>,
@@ -146,6 +557,18 @@ impl ItemFormat {
     }
 }
 
+/// Named colors from the `[theme]` section
+#[derive(Debug, Default)]
+struct Theme {
+    colors: HashMap<Box<str>, Rc<str>>,
+    /// Unknown color names that have already been reported
+    warned: HashSet<Box<str>>,
+}
+
+thread_local! {
+    static THEME: RefCell<Theme> = Default::default();
+}
+
 /// Formatting that must be applied after rendering an item
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Formatting {
@@ -156,6 +579,9 @@ pub struct Formatting {
     max_width: Option<Width>,
     margin: Option<(f32, f32, f32, f32)>,
     padding: Option<(f32, f32, f32, f32)>,
+    /// Color and width of accent lines along the bottom and top of the box
+    underline: Option<(Color, f32)>,
+    overline: Option<(Color, f32)>,
 }
 
 impl Formatting {
@@ -199,6 +625,16 @@ impl Formatting {
 
         let bg_rgba = Formatting::parse_rgba(get("bg"), get_f32("bg-alpha"));
         let border_rgba = Formatting::parse_rgba(get("border-color"), get_f32("border-alpha"));
+        let underline = Formatting::parse_line(
+            get("underline"),
+            get_f32("underline-alpha"),
+            get_f32("underline-width"),
+        );
+        let overline = Formatting::parse_line(
+            get("overline"),
+            get_f32("overline-alpha"),
+            get_f32("overline-width"),
+        );
 
         Self {
             bg_rgba,
@@ -208,10 +644,26 @@ impl Formatting {
             max_width,
             margin,
             padding,
+            underline,
+            overline,
         }
     }
 
-    fn parse_trbl(v: Cow<str>) -> Option<(f32, f32, f32, f32)> {
+    fn parse_line(
+        color: Option<Cow<str>>,
+        alpha: Option<f32>,
+        width: Option<f32>,
+    ) -> Option<(Color, f32)> {
+        let color = color.filter(|c| !c.is_empty())?;
+        let width = width.unwrap_or(2.0);
+        if width <= 0.0 {
+            return None;
+        }
+        Some((Formatting::parse_rgba(Some(color), alpha)?, width))
+    }
+
+    /// Parse CSS-style top, right, bottom, and left sizes, where omitted values are copied
+    pub fn parse_trbl(v: Cow<str>) -> Option<(f32, f32, f32, f32)> {
         let mut rv = (0.0, 0.0, 0.0, 0.0);
         for (i, x) in v.split_whitespace().enumerate() {
             match (i, x.parse()) {
@@ -239,6 +691,8 @@ impl Formatting {
             return None;
         }
         let color = color.as_ref().map_or("black", |v| v.as_ref());
+        let theme_color = THEME.with(|theme| theme.borrow().colors.get(color).cloned());
+        let color = theme_color.as_deref().unwrap_or(color);
         let (r, g, b, mut a);
         let alpha_f = alpha.unwrap_or(1.0) * 65535.0;
         a = f32::min(65535.0, f32::max(0.0, alpha_f)) as u64;
@@ -323,10 +777,12 @@ impl Formatting {
                     b = 0xFFFF;
                 }
                 _ => {
-                    debug!("Unknown color '{}'", color);
-                    r = 0;
-                    g = 0;
-                    b = 0;
+                    THEME.with(|theme| {
+                        if theme.borrow_mut().warned.insert(color.into()) {
+                            warn!("Unknown color '{}' (add it to the [theme] section)", color);
+                        }
+                    });
+                    return None;
                 }
             }
         }
@@ -338,6 +794,25 @@ impl Formatting {
         )
     }
 
+    /// Replace the named colors available to [Self::parse_rgba]
+    pub fn set_theme(config: Option<&toml::value::Table>) {
+        THEME.with(|theme| {
+            let mut theme = theme.borrow_mut();
+            theme.warned.clear();
+            theme.colors = config
+                .into_iter()
+                .flatten()
+                .filter_map(|(name, value)| match value.as_str() {
+                    Some(color) => Some((name.as_str().into(), color.into())),
+                    None => {
+                        error!("Theme color '{}' must be a string", name);
+                        None
+                    }
+                })
+                .collect();
+        });
+    }
+
     fn get_shrink(&self) -> Option<(f32, f32, f32, f32)> {
         let mut rv = (0.0, 0.0, 0.0, 0.0);
         if self.padding == None && self.margin == None {
@@ -413,36 +888,31 @@ impl Formatting {
 
         let inner_x_offset;
         if child_render_width < min_width {
-            // child is smaller than the box; align it
+            // child is smaller than the box; reserve the whole box and align the child within it
             let expand = min_width - child_render_width;
-            match ctx.align.horiz {
-                Some(f) => {
-                    inner_x_offset = expand * f;
-                    let x0 = start_pos.x.floor() as usize;
-                    let x1 = end_pos.x.ceil() as usize;
-                    let ilen = x1 - x0;
-                    let wlen = min_width.ceil() as usize;
-                    if wlen > ilen {
-                        // Align by rotating the pixels of the inner clip region to the right.  The
-                        // right part of the clip region should just be blank pixels at this point,
-                        // which is what we want to put on the left.
-                        let rlen = (wlen - ilen) * 4;
-                        let stride = ctx.canvas.width() as usize * 4;
-                        let h = ctx.canvas.height() as usize;
-                        for y in 0..h {
-                            let x0 = y * stride + x0 * 4;
-                            let x2 = y * stride + (x0 + wlen) * 4;
-                            if let Some(buf) = ctx.canvas.data_mut().get_mut(x0..x2) {
-                                buf.rotate_right(rlen);
-                            }
-                        }
+            inner_x_offset = expand * ctx.align.horiz.unwrap_or(0.0).clamp(0.0, 1.0);
+            let xform = ctx.render_xform;
+            let px = |x: f32| (x * xform.sx + xform.tx).round().max(0.0) as usize;
+            let py = |y: f32| (y * xform.sy + xform.ty).round().max(0.0) as usize;
+            let width = ctx.canvas.width() as usize;
+            let height = ctx.canvas.height() as usize;
+            let x0 = px(start_pos.x).min(width);
+            let x1 = px(start_pos.x + min_width).min(width);
+            let shift = px(start_pos.x + inner_x_offset).saturating_sub(x0) * 4;
+            if shift > 0 {
+                // Align by rotating the pixels of the box to the right.  The right part of the
+                // box should just be blank pixels at this point, which is what we want to put on
+                // the left.
+                let stride = width * 4;
+                for y in py(inner_clip.0.y).min(height)..py(inner_clip.1.y).min(height) {
+                    let row = y * stride;
+                    if let Some(buf) = ctx.canvas.data_mut().get_mut(row + x0 * 4..row + x1 * 4) {
+                        let len = buf.len();
+                        buf.rotate_right(shift.min(len));
                     }
                 }
-                _ => {
-                    // defaults to left align
-                    inner_x_offset = 0.0;
-                }
             }
+            end_pos.x = start_pos.x + min_width;
         } else {
             inner_x_offset = 0.0;
         }
@@ -525,6 +995,36 @@ impl Formatting {
             }
         }
 
+        if format.underline.is_some() || format.overline.is_some() {
+            // Lines span the background box (inside any border), snapped to device pixels
+            let (mut x0, mut y0, mut x1, mut y1) = (start_pos.x, start_pos.y, end_pos.x, end_pos.y);
+            if let Some((t, r, b, l)) = format.padding {
+                x0 -= l;
+                y0 -= t;
+                x1 += r;
+                y1 += b;
+            }
+            let xform = ctx.render_xform;
+            let snap_x = |x: f32| ((x * xform.sx + xform.tx).round() - xform.tx) / xform.sx;
+            let snap_y = |y: f32| ((y * xform.sy + xform.ty).round() - xform.ty) / xform.sy;
+            let lines = [
+                format.underline.map(|(rgba, w)| (rgba, y1 - w, y1)),
+                format.overline.map(|(rgba, w)| (rgba, y0, y0 + w)),
+            ];
+            for (rgba, top, bottom) in lines.into_iter().flatten() {
+                let paint = tiny_skia::Paint {
+                    shader: tiny_skia::Shader::SolidColor(rgba),
+                    anti_alias: false,
+                    ..tiny_skia::Paint::default()
+                };
+                let rect =
+                    tiny_skia::Rect::from_ltrb(snap_x(x0), snap_y(top), snap_x(x1), snap_y(bottom));
+                if let Some(rect) = rect {
+                    ctx.canvas.fill_rect(rect, &paint, xform, None);
+                }
+            }
+        }
+
         (outer_pos, inner_x_offset, start_pos.x, end_pos.x)
     }
 }
@@ -549,15 +1049,22 @@ impl Item {
     }
 
     pub fn new_bar(cfg: toml::Value) -> Self {
-        let left = Rc::new(cfg.get("left").map_or_else(Item::none, Item::from_toml_ref));
-        let right = Rc::new(
-            cfg.get("right")
-                .map_or_else(Item::none, Item::from_toml_ref),
-        );
-        let center = Rc::new(
-            cfg.get("center")
-                .map_or_else(Item::none, Item::from_toml_ref),
-        );
+        // The bar's separator is used by the left, center, and right lists unless they are
+        // groups that set their own
+        let section = |key| {
+            let mut item = cfg.get(key).map_or_else(Item::none, Item::from_toml_ref);
+            if let (Module::Group { separator, .. }, Some(value)) =
+                (&mut item.data, cfg.get("separator"))
+            {
+                if separator.is_none() {
+                    *separator = Some(Separator::from_toml(value));
+                }
+            }
+            Rc::new(item)
+        };
+        let left = section("left");
+        let right = section("right");
+        let center = section("center");
         let mut tooltips = cfg
             .get("tooltips")
             .map_or_else(ItemFormat::default, ItemFormat::from_toml);
@@ -576,9 +1083,15 @@ impl Item {
             }));
         }
 
+        // The bar's margin places it on the output instead of insetting its contents
+        let mut format = cfg.clone();
+        if let Some(table) = format.as_table_mut() {
+            table.remove("margin");
+        }
+
         Item {
             events: EventSink::from_toml(&cfg),
-            format: ItemFormat::from_toml(&cfg),
+            format: ItemFormat::from_toml(&format),
             data: Module::Bar {
                 left,
                 center,
@@ -608,7 +1121,11 @@ impl Item {
                 condition: None,
                 tooltip: None,
                 spacing: "".into(),
+                separator: None,
                 vertical: false,
+                reverse: false,
+                collapsed: None,
+                expanded: Default::default(),
             }
             .into();
         }
@@ -617,8 +1134,17 @@ impl Item {
         if let Module::ParseError { msg } = &data {
             error!("Error parsing {key}: {msg}");
         }
+        #[cfg_attr(not(any(feature = "dbus", feature = "pulse")), allow(unused_mut))]
+        let mut events = EventSink::from_toml(value);
+        match &data {
+            #[cfg(feature = "dbus")]
+            Module::MediaPlayer2 { target } => events.add_mpris_default(target),
+            #[cfg(feature = "pulse")]
+            Module::Pulse { target } => events.add_pulse_default(target),
+            _ => {}
+        }
         Item {
-            events: EventSink::from_toml(value),
+            events,
             format: ItemFormat::from_toml(value),
             data,
         }
@@ -629,15 +1155,147 @@ impl Item {
         if !parent_ctx.render_flex && parent_ctx.render_pos.x > parent_ctx.render_extents.1.x {
             return EventSink::default();
         }
+        let count = &parent_ctx.runtime.render_count;
+        count.set(count.get() + 1);
+
+        let blink = self.format.blink.as_ref().and_then(|blink| {
+            let opacity = blink.opacity(parent_ctx.runtime)?;
+            Some((blink, opacity))
+        });
+        if let Some((blink, opacity)) = blink {
+            let mut layer = parent_ctx.reuse_layer(blink.layer.take());
+            let rv = parent_ctx.with_layer(&mut layer, |ctx| self.render_contents(ctx));
+            parent_ctx.canvas.draw_pixmap(
+                0,
+                0,
+                layer.as_ref(),
+                &tiny_skia::PixmapPaint {
+                    opacity,
+                    ..Default::default()
+                },
+                tiny_skia::Transform::identity(),
+                None,
+            );
+            blink.layer.set(Some(layer));
+            return rv;
+        }
+        self.render_contents(parent_ctx)
+    }
+
+    fn render_contents(self: &Rc<Self>, ctx: &mut Render) -> EventSink {
+        if self.format.cache && !ctx.render_flex {
+            if let Some(rv) = self.render_cached(ctx) {
+                return rv;
+            }
+        }
+        self.render_styled(ctx)
+    }
+
+    /// Render an item with `cache = true`, reusing the previous image if nothing has changed.
+    ///
+    /// Returns None if the item cannot be cached in this context.
+    fn render_cached(self: &Rc<Self>, ctx: &mut Render) -> Option<EventSink> {
+        let rt = ctx.runtime;
+        let bar = rt.render_bar()?;
+        let iter = rt.copy_item_var();
+        let key = CacheKey::new(ctx);
+        let mut id = None;
+        let hit = self.format.cached.take_in(|cached| {
+            let i = cached.iter().position(|c| c.bar == bar && c.iter == iter)?;
+            let c = &cached[i];
+            if !c.valid.get() || c.key != key {
+                id = Some(c.id);
+                cached.swap_remove(i);
+                return None;
+            }
+            ctx.canvas.draw_pixmap(
+                c.draw_at.0,
+                c.draw_at.1,
+                c.image.as_ref(),
+                &Default::default(),
+                tiny_skia::Transform::identity(),
+                None,
+            );
+            ctx.render_pos = c.end;
+            Some(c.events.clone())
+        });
+        if hit.is_some() {
+            return hit;
+        }
+
+        let id = id.unwrap_or_else(UID::new);
+        let origin = ctx.render_pos;
+        let (image, (x, y), (mut events, end)) =
+            ctx.with_new_canvas_x(origin, ctx.render_extents.1.x, |ctx| {
+                rt.set_render_bar(Some(id));
+                let rv = self.render_styled(ctx);
+                rt.set_render_bar(Some(bar));
+                (rv, ctx.render_pos)
+            });
+        let dx = origin.x.floor();
+        let end = Point {
+            x: end.x + dx,
+            y: end.y + ctx.render_extents.0.y.floor(),
+        };
+        events.offset_clamp(dx, origin.x, end.x);
+        let draw_at = (x as i32, y as i32);
+        ctx.canvas.draw_pixmap(
+            draw_at.0,
+            draw_at.1,
+            image.as_ref(),
+            &Default::default(),
+            tiny_skia::Transform::identity(),
+            None,
+        );
+        ctx.render_pos = end;
+
+        let valid = Rc::new(Cell::new(true));
+        rt.add_render_cache(id, bar, &valid);
+        self.format.cached.take_in(|cached| {
+            cached.retain(|c| c.valid.get());
+            cached.push(CachedRender {
+                bar,
+                iter,
+                id,
+                valid,
+                key,
+                image,
+                draw_at,
+                end,
+                events: events.clone(),
+            });
+        });
+        Some(events)
+    }
 
+    fn render_styled(self: &Rc<Self>, parent_ctx: &mut Render) -> EventSink {
+        let hover_key = self
+            .format
+            .hover
+            .as_ref()
+            .map(|_| HoverKey::new(self, parent_ctx.runtime.copy_item_var()));
+        let format = match (&self.format.hover, &hover_key) {
+            (Some(hover), Some(key)) if parent_ctx.runtime.is_hovered(key) => hover,
+            _ => &self.format,
+        };
+
+        let x0 = parent_ctx.render_pos.x;
+        let mut rv = self.render_format(format, parent_ctx);
+        if let Some(key) = hover_key {
+            rv.add_hover_style(x0, parent_ctx.render_pos.x, key);
+        }
+        rv
+    }
+
+    fn render_format(self: &Rc<Self>, format: &ItemFormat, parent_ctx: &mut Render) -> EventSink {
         let mut rv = self.events.clone();
 
-        if self.format.is_trivial() {
+        if format.is_trivial() {
             self.render_inner(parent_ctx, &mut rv);
             return rv;
         }
 
-        let (format, mut ctx) = self.format.setup_ctx(parent_ctx);
+        let (format, mut ctx) = format.setup_ctx(parent_ctx);
         if format.is_boring() {
             self.render_inner(&mut ctx, &mut rv);
             let pos = ctx.render_pos;
@@ -663,6 +1321,58 @@ impl Item {
         ev.merge(rv);
     }
 
+    /// Render an item in a group.  A collapsed group's summary also toggles the group's
+    /// expanded state when clicked.
+    fn render_entry(
+        self: &Rc<Self>,
+        ctx: &mut Render,
+        ev: &mut EventSink,
+        toggle: Option<&Rc<Cell<bool>>>,
+    ) {
+        match toggle {
+            Some(expanded) => {
+                let x0 = ctx.render_pos.x;
+                let mut rv = self.render(ctx);
+                rv.add_toggle(expanded.clone());
+                rv.offset_clamp(0.0, x0, ctx.render_pos.x);
+                ev.merge(rv);
+            }
+            None => self.render_clamped(ctx, ev),
+        }
+    }
+
+    /// Render the icons of a tray module
+    #[cfg(feature = "dbus")]
+    fn render_tray(&self, ctx: &mut Render, rv: &mut EventSink) {
+        if let Module::Tray {
+            passive,
+            active,
+            urgent,
+            spacing,
+            reverse,
+            sort,
+            scroll_threshold,
+            ..
+        } = &self.data
+        {
+            let spacing = ctx
+                .runtime
+                .format(spacing)
+                .ok()
+                .and_then(|s| s.parse_f32())
+                .unwrap_or(0.0);
+            tray::show(
+                ctx,
+                rv,
+                [passive, active, urgent],
+                spacing,
+                *reverse,
+                sort,
+                *scroll_threshold,
+            )
+        }
+    }
+
     pub fn render_clamped_item(
         self: &Rc<Self>,
         ctx: &mut Render,
@@ -705,13 +1415,17 @@ impl Item {
                 items,
                 tooltip,
                 spacing,
+                separator,
                 vertical,
+                reverse,
+                collapsed,
+                expanded,
             } => {
                 if let Some(cond) = condition {
                     if !cond.is_empty() {
-                        match ctx.runtime.format(cond) {
-                            Ok(v) if v.as_bool() => {}
-                            Ok(_) => return,
+                        match condition::eval(&ctx.runtime, cond) {
+                            Ok(true) => {}
+                            Ok(false) => return,
                             Err(e) => {
                                 warn!("Error evaluating condition '{}': {}", cond, e);
                             }
@@ -725,22 +1439,99 @@ impl Item {
                     .ok()
                     .and_then(|s| s.parse_f32())
                     .unwrap_or(0.0);
-                for item in items {
-                    item.render_clamped(ctx, rv);
+                // The summary of a collapsed group is always shown; clicking it shows or hides
+                // the items
+                let mut entries: Vec<_> = collapsed.iter().map(|s| (s, true)).collect();
+                if collapsed.is_none() || expanded.get() {
+                    entries.extend(items.iter().map(|i| (i, false)));
+                }
+                if *reverse {
+                    entries.reverse();
+                }
+                // If any item sets a z-index, each z-index is drawn on its own layer so that the
+                // drawing order can differ from the layout order
+                let layered = entries.iter().any(|(item, _)| item.format.z_index != 0);
+                let mut layers: Vec<(i32, tiny_skia::Pixmap, EventSink)> = Vec::new();
+                let separator = separator.as_ref().map(|s| s.prepare(ctx, *vertical));
+                // Separators are only drawn once the item after them is known to be visible
+                let mut gaps = Vec::new();
+                let mut shown = false;
+                for (i, (item, is_summary)) in entries.into_iter().enumerate() {
+                    if i != 0 && spacing > 0.0 {
+                        if *vertical {
+                            ctx.render_pos.y = (ctx.render_pos.y + spacing).ceil();
+                        } else {
+                            ctx.render_pos.x = (ctx.render_pos.x + spacing).ceil();
+                        }
+                    }
+                    let gap = ctx.render_pos;
+                    if let Some(sep) = separator.as_ref().filter(|_| shown) {
+                        if *vertical {
+                            ctx.render_pos.y = (ctx.render_pos.y + sep.size + spacing).ceil();
+                        } else {
+                            ctx.render_pos.x = (ctx.render_pos.x + sep.size + spacing).ceil();
+                        }
+                    }
+                    let start = ctx.render_pos;
+                    let toggle = is_summary.then_some(expanded);
+                    if layered {
+                        let z = item.format.z_index;
+                        let pos = match layers.iter().position(|l| l.0 == z) {
+                            Some(pos) => pos,
+                            None => {
+                                layers.push((z, ctx.new_layer(), EventSink::default()));
+                                layers.len() - 1
+                            }
+                        };
+                        let (_, layer, ev) = &mut layers[pos];
+                        let mut item_ev = EventSink::default();
+                        ctx.with_layer(layer, |ctx| item.render_entry(ctx, &mut item_ev, toggle));
+                        item_ev.set_z(z);
+                        ev.merge(item_ev);
+                    } else {
+                        item.render_entry(ctx, rv, toggle);
+                    }
+
+                    let visible = if *vertical {
+                        ctx.render_pos.y > start.y
+                    } else {
+                        ctx.render_pos.x > start.x
+                    };
+                    if !visible {
+                        // hidden items don't get a separator
+                        ctx.render_pos = gap;
+                    } else if shown && separator.is_some() {
+                        gaps.push(gap);
+                    }
+                    shown |= visible;
 
                     if *vertical {
                         group.next_v(ctx);
-                        if spacing > 0.0 {
-                            ctx.render_pos.y = (ctx.render_pos.y + spacing).ceil();
-                        }
                     } else {
                         group.next_h(ctx);
-                        if spacing > 0.0 {
-                            ctx.render_pos.x = (ctx.render_pos.x + spacing).ceil();
-                        }
                     }
                 }
                 ctx.render_pos = group.bounds;
+                if let Some(sep) = &separator {
+                    for gap in gaps {
+                        sep.draw(ctx, gap, group.bounds, *vertical);
+                    }
+                }
+                layers.sort_by_key(|l| l.0);
+                for (_, layer, _) in &layers {
+                    ctx.canvas.draw_pixmap(
+                        0,
+                        0,
+                        layer.as_ref(),
+                        &Default::default(),
+                        tiny_skia::Transform::identity(),
+                        None,
+                    );
+                }
+                // hover lookups use the first match, so add the topmost layer first
+                for (_, _, ev) in layers.into_iter().rev() {
+                    rv.merge(ev);
+                }
                 if let Some(item) = tooltip {
                     rv.add_tooltip(PopupDesc::RenderItem {
                         item: item.clone(),
@@ -817,7 +1608,8 @@ impl Item {
                 rv.merge(right_ev);
 
                 let max_center_width = width - left_size - right_size;
-                ctx.render_pos.x = clip.1.x;
+                // the bar's box is all of it, so that its background fills the bar
+                ctx.render_pos = clip.1;
 
                 if max_center_width <= 0.0 {
                     // don't render the center if there's no room at all
@@ -858,6 +1650,78 @@ impl Item {
                 cent_ev.offset_clamp(cent_offset, cent_offset, cent_offset + cent_size);
                 rv.merge(cent_ev);
             }
+            Module::Arc {
+                value,
+                radius,
+                thickness,
+                start,
+                track,
+                label,
+            } => {
+                let value = value.read_in(ctx.err_name, "", &ctx.runtime, |v| {
+                    v.parse_f32().unwrap_or(0.0)
+                });
+                let value = if value.is_nan() {
+                    0.0
+                } else {
+                    value.clamp(0.0, 1.0)
+                };
+                let get_f32 = |v: &str| ctx.runtime.format(v).ok().and_then(|v| v.parse_f32());
+
+                let origin = ctx.render_pos;
+                let height = ctx.render_extents.1.y - origin.y;
+                let radius = get_f32(radius).unwrap_or(height / 2.0).max(0.0);
+                let thickness = get_f32(thickness)
+                    .unwrap_or(radius / 3.0)
+                    .clamp(0.0, radius);
+                let start = get_f32(start).unwrap_or(0.0);
+                let track = if track.is_empty() {
+                    let mut rgba = ctx.font_color;
+                    rgba.apply_opacity(0.25);
+                    Some(rgba)
+                } else {
+                    let color = ctx.runtime.format_or(track, ctx.err_name).into_text();
+                    Formatting::parse_rgba(Some(color), None)
+                };
+
+                // The stroke is centered on the path, so the ring's outer edge is at radius
+                let center = (origin.x + radius, origin.y + height / 2.0);
+                let r = radius - thickness / 2.0;
+                if value < 1.0 {
+                    if let Some(rgba) = track {
+                        let filled = 360.0 * value;
+                        stroke_arc(ctx, center, r, thickness, start + filled, 360.0 - filled, rgba);
+                    }
+                }
+                if value > 0.0 {
+                    let rgba = ctx.font_color;
+                    stroke_arc(ctx, center, r, thickness, start, 360.0 * value, rgba);
+                }
+
+                if let Some(label) = label {
+                    let x_max = ctx.render_extents.1.x;
+                    let (canvas, (draw_x, draw_y), (mut ev, width)) =
+                        ctx.with_new_canvas_x(origin, x_max, |ctx| {
+                            let x0 = ctx.render_pos.x;
+                            let ev = label.render(ctx);
+                            (ev, ctx.render_pos.x - x0)
+                        });
+                    let shift = radius - width / 2.0;
+                    ctx.canvas.draw_pixmap(
+                        (draw_x + shift * ctx.render_xform.sx).round() as i32,
+                        draw_y as i32,
+                        canvas.as_ref(),
+                        &tiny_skia::PixmapPaint::default(),
+                        tiny_skia::Transform::identity(),
+                        None,
+                    );
+                    ev.offset_clamp(shift, origin.x, origin.x + 2.0 * radius);
+                    rv.merge(ev);
+                }
+
+                ctx.render_pos.x = origin.x + 2.0 * radius;
+                ctx.render_pos.y = center.1 + radius;
+            }
             Module::Fade {
                 items,
                 value,
@@ -944,8 +1808,13 @@ impl Item {
                 let mut p = [Point { x: bb_l, y: bb_t }, Point { x: bb_r, y: bb_b }];
                 ctx.render_xform.map_points(&mut p);
 
+                // an empty meter has nothing to draw
+                let rect = match tiny_skia::Rect::from_ltrb(p[0].x, p[0].y, p[1].x, p[1].y) {
+                    Some(rect) => rect,
+                    None => return,
+                };
                 ctx.canvas.fill_rect(
-                    tiny_skia::Rect::from_ltrb(p[0].x, p[0].y, p[1].x, p[1].y).unwrap(),
+                    rect,
                     &tiny_skia::Paint {
                         shader: tiny_skia::Pattern::new(
                             canvas.as_ref(),
@@ -966,13 +1835,17 @@ impl Item {
                 name,
                 fallback,
                 tooltip,
+                recolor,
             } => {
                 let markup = self.format.markup;
                 let name = ctx.runtime.format_or(name, ctx.err_name).into_text();
-                match icon::render(ctx, &name) {
+                let recolor = icon_recolor(ctx, recolor);
+                match icon::render(ctx, &name, recolor) {
                     Ok(()) => {}
                     Err(()) => {
-                        let value = ctx.runtime.format_or(fallback, ctx.err_name).into_owned();
+                        let value = ctx.runtime.read_shown(markup, || {
+                            ctx.runtime.format_or(fallback, ctx.err_name).into_owned()
+                        });
                         let mut item: Item = Module::new_value(value).into();
                         item.format.markup = markup;
                         Rc::new(item).render(ctx);
@@ -985,30 +1858,101 @@ impl Item {
                     });
                 }
             }
+            Module::Labeled {
+                icon,
+                fallback,
+                recolor,
+                format,
+                gap,
+            } => {
+                let markup = self.format.markup;
+                let name = ctx.runtime.format_or(icon, ctx.err_name).into_text();
+                let recolor = icon_recolor(ctx, recolor);
+                let x0 = ctx.render_pos.x;
+                let (runtime, err_name) = (ctx.runtime, ctx.err_name);
+                if name.is_empty() || icon::render(ctx, &name, recolor).is_err() {
+                    let fallback = runtime.read_shown(markup, move || {
+                        runtime.format_or(fallback, err_name).into_text()
+                    });
+                    render_font_item(ctx, &fallback, markup);
+                }
+                let text = runtime.read_shown(markup, move || {
+                    runtime.format_or(format, err_name).into_text()
+                });
+                if !text.is_empty() {
+                    if ctx.render_pos.x > x0 {
+                        let gap = ctx
+                            .runtime
+                            .format(gap)
+                            .ok()
+                            .and_then(|s| s.parse_f32())
+                            .unwrap_or(0.0);
+                        ctx.render_pos.x = (ctx.render_pos.x + gap).ceil();
+                    }
+                    render_font_item(ctx, &text, markup);
+                }
+            }
+            Module::Sparkline(sparkline) => sparkline.render(ctx),
             Module::SwayTree(tree) => {
                 tree.render(ctx, rv);
             }
             #[cfg(feature = "dbus")]
             Module::Tray {
-                passive,
-                active,
-                urgent,
-            } => tray::show(ctx, rv, [passive, active, urgent]),
+                drawer: Some(drawer),
+                ..
+            } => {
+                let x0 = ctx.render_pos.x;
+                drawer.render_clamped(ctx, rv);
+                let x1 = ctx.render_pos.x;
+                if x1 > x0 {
+                    rv.add_hover(
+                        x0,
+                        x1,
+                        PopupDesc::TrayDrawer {
+                            tray: self.clone(),
+                            events: EventSink::default(),
+                        },
+                    );
+                }
+            }
+            #[cfg(feature = "dbus")]
+            Module::Tray { .. } => self.render_tray(ctx, rv),
 
             // All other modules are rendered as text
             _ => {
                 let markup = self.format.markup;
                 let oneline = self.format.oneline;
-                let mut text = self
-                    .data
-                    .read_to_owned(ctx.err_name, "text", &ctx.runtime)
-                    .into_text();
+                let mut text = ctx.runtime.read_shown(markup, || {
+                    self.data
+                        .read_to_owned(ctx.err_name, "text", &ctx.runtime)
+                        .into_text()
+                });
                 if oneline && text.contains('\n') {
                     text = text.replace('\n', " ").into();
                 }
 
+                let x0 = ctx.render_pos.x;
                 render_font_item(ctx, &text, markup);
 
+                if let Some(level) = self.format.flash_level(&ctx.runtime, &text) {
+                    let mut rgba = ctx.font_color;
+                    rgba.apply_opacity(level * 0.5);
+                    if let Some(rect) = tiny_skia::Rect::from_ltrb(
+                        x0,
+                        ctx.render_extents.0.y,
+                        ctx.render_pos.x,
+                        ctx.render_extents.1.y,
+                    ) {
+                        let paint = tiny_skia::Paint {
+                            shader: tiny_skia::Shader::SolidColor(rgba),
+                            anti_alias: true,
+                            blend_mode: tiny_skia::BlendMode::DestinationOver,
+                            ..tiny_skia::Paint::default()
+                        };
+                        ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
+                    }
+                }
+
                 match &self.data {
                     Module::Formatted {
                         tooltip: Some(item),
@@ -1038,6 +1982,69 @@ impl Item {
     }
 }
 
+/// The color to paint a symbolic icon: an empty `recolor` uses the foreground color
+fn icon_recolor(ctx: &Render, recolor: &Option<Box<str>>) -> Option<Color> {
+    recolor.as_ref().and_then(|color| {
+        if color.is_empty() {
+            Some(ctx.font_color)
+        } else {
+            let color = ctx.runtime.format_or(color, ctx.err_name).into_text();
+            Formatting::parse_rgba(Some(color), None)
+        }
+    })
+}
+
+/// Stroke a circular arc, with angles in degrees clockwise from the top
+fn stroke_arc(
+    ctx: &mut Render,
+    (cx, cy): (f32, f32),
+    r: f32,
+    width: f32,
+    start: f32,
+    sweep: f32,
+    rgba: Color,
+) {
+    if r <= 0.0 || width <= 0.0 || sweep <= 0.0 {
+        return;
+    }
+    let mut pb = tiny_skia::PathBuilder::new();
+    if sweep >= 360.0 {
+        pb.push_circle(cx, cy, r);
+    } else {
+        // approximate the arc using one cubic per (at most) quarter-circle
+        let segments = (sweep / 90.0).ceil();
+        let step = sweep.to_radians() / segments;
+        let k = 4.0 / 3.0 * (step / 4.0).tan() * r;
+        let mut a = start.to_radians();
+        pb.move_to(cx + r * a.sin(), cy - r * a.cos());
+        for _ in 0..segments as usize {
+            let b = a + step;
+            pb.cubic_to(
+                cx + r * a.sin() + k * a.cos(),
+                cy - r * a.cos() + k * a.sin(),
+                cx + r * b.sin() - k * b.cos(),
+                cy - r * b.cos() - k * b.sin(),
+                cx + r * b.sin(),
+                cy - r * b.cos(),
+            );
+            a = b;
+        }
+    }
+    if let Some(path) = pb.finish() {
+        let paint = tiny_skia::Paint {
+            shader: tiny_skia::Shader::SolidColor(rgba),
+            anti_alias: true,
+            ..tiny_skia::Paint::default()
+        };
+        let stroke = tiny_skia::Stroke {
+            width,
+            ..tiny_skia::Stroke::default()
+        };
+        ctx.canvas
+            .stroke_path(&path, &paint, &stroke, ctx.render_xform, None);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum PopupDesc {
     RenderItem {
@@ -1050,6 +2057,9 @@ pub enum PopupDesc {
     },
     #[cfg(feature = "dbus")]
     Tray(tray::TrayPopup),
+    /// The icons of a tray with a `drawer`, and the click handlers from their last rendering
+    #[cfg(feature = "dbus")]
+    TrayDrawer { tray: Rc<Item>, events: EventSink },
 }
 
 impl PartialEq for PopupDesc {
@@ -1071,6 +2081,10 @@ impl PartialEq for PopupDesc {
             ) => Rc::ptr_eq(a, b) && ai == bi,
             #[cfg(feature = "dbus")]
             (PopupDesc::Tray(a), PopupDesc::Tray(b)) => a == b,
+            #[cfg(feature = "dbus")]
+            (PopupDesc::TrayDrawer { tray: a, .. }, PopupDesc::TrayDrawer { tray: b, .. }) => {
+                Rc::ptr_eq(a, b)
+            }
             _ => false,
         }
     }
@@ -1096,14 +2110,19 @@ impl PopupDesc {
         };
 
         let (format, mut ctx) = format.setup_ctx(ctx);
+        // Text in popups is wrapped instead of being cut off at the maximum width
+        let wrap = match format.max_width {
+            Some(Width::Pixels(n)) => Some(n),
+            _ => None,
+        };
         let (pos, _, _, _) = format.render(&mut ctx, |ctx| {
-            self.render(ctx);
+            self.render(ctx, wrap);
         });
 
         (pos.x as i32, pos.y as i32)
     }
 
-    fn render(&mut self, ctx: &mut Render) {
+    fn render(&mut self, ctx: &mut Render, wrap: Option<f32>) {
         match self {
             PopupDesc::RenderItem { item, iter } => {
                 let item_var = ctx.runtime.get_item_var();
@@ -1112,26 +2131,66 @@ impl PopupDesc {
                 item_var.set(None);
             }
             PopupDesc::TextItem { source, iter } => {
+                let markup = source.format.markup;
                 let item_var = ctx.runtime.get_item_var();
                 item_var.set(iter.clone());
-                let value = source
-                    .data
-                    .read_to_owned("tooltip", "tooltip", ctx.runtime)
-                    .into_text();
+                let value = ctx.runtime.read_shown(markup, || {
+                    source
+                        .data
+                        .read_to_owned("tooltip", "tooltip", ctx.runtime)
+                        .into_text()
+                });
                 item_var.set(None);
 
                 if value.is_empty() {
                     return;
                 }
 
-                let markup = source.format.markup;
+                let value = match wrap {
+                    Some(width) => wrap_text(ctx, &value, markup, width).into_owned().into(),
+                    None => value,
+                };
 
                 let (width, height) = render_font(ctx, &value, markup);
                 ctx.render_pos.x = width + 4.0;
                 ctx.render_pos.y = height + 4.0;
             }
             #[cfg(feature = "dbus")]
-            PopupDesc::Tray(tray) => tray.render(ctx),
+            PopupDesc::Tray(tray) => tray.render(ctx, wrap),
+            #[cfg(feature = "dbus")]
+            PopupDesc::TrayDrawer { tray, events } => {
+                // icons are sized to the extents, which are only as tall as the text here
+                let extents = ctx.render_extents;
+                ctx.render_extents.1 = Point {
+                    x: f32::MAX,
+                    y: ctx.render_pos.y + DRAWER_ICON_SIZE,
+                };
+                *events = EventSink::default();
+                tray.render_tray(ctx, events);
+                ctx.render_extents = extents;
+            }
+        }
+    }
+
+    /// True for popups that open when their item is clicked instead of when it is hovered
+    pub fn opens_on_click(&self) -> bool {
+        #[cfg(feature = "dbus")]
+        if let PopupDesc::TrayDrawer { .. } = self {
+            return true;
+        }
+        false
+    }
+
+    /// The pointer moved to this position in the popup, or left it.  Returns true if the popup
+    /// needs to be redrawn.
+    pub fn hover(&mut self, pos: Option<(f64, f64)>) -> bool {
+        match self {
+            #[cfg(feature = "dbus")]
+            PopupDesc::Tray(tray) => tray.hover(pos.map(|(_, y)| y)),
+            _ => {
+                let _ = pos;
+                false
+            }
         }
     }
 
@@ -1149,6 +2208,10 @@ impl PopupDesc {
             PopupDesc::TextItem { .. } => {}
             #[cfg(feature = "dbus")]
             PopupDesc::Tray(tray) => tray.button(x, y, button, runtime),
+            #[cfg(feature = "dbus")]
+            PopupDesc::TrayDrawer { events, .. } => {
+                events.button(x as f32, y as f32, button, runtime)
+            }
         }
     }
 }
@@ -0,0 +1,101 @@
+//! System load averages from /proc/loadavg
+use crate::data::{Periodic, Value};
+use crate::state::Runtime;
+use crate::util::{toml_to_f64, Cell};
+use log::debug;
+use std::fs;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct LoadAvg {
+    avg: [f64; 3],
+    running: u32,
+    total: u32,
+}
+
+impl LoadAvg {
+    fn read() -> Option<Self> {
+        let text = match fs::read_to_string("/proc/loadavg") {
+            Ok(text) => text,
+            Err(e) => {
+                debug!("Could not read /proc/loadavg: {}", e);
+                return None;
+            }
+        };
+        // "0.52 0.58 0.59 2/1234 56789"
+        let mut fields = text.split_ascii_whitespace();
+        let mut avg = [0.0; 3];
+        for v in &mut avg {
+            *v = fields.next()?.parse().ok()?;
+        }
+        let (running, total) = fields.next()?.split_once('/')?;
+        Some(LoadAvg {
+            avg,
+            running: running.parse().ok()?,
+            total: total.parse().ok()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Load {
+    poll: Periodic<Cell<Option<LoadAvg>>>,
+    cores: u32,
+    /// Thresholds for the state, as load per core
+    high: Option<f64>,
+    critical: Option<f64>,
+}
+
+impl Load {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get() as u32);
+        Load {
+            poll: Periodic::new(
+                toml_to_f64(config.get("poll")).unwrap_or(10.0),
+                Cell::default(),
+            ),
+            cores,
+            high: toml_to_f64(config.get("high")),
+            critical: toml_to_f64(config.get("critical")),
+        }
+    }
+
+    pub fn carry_over(&self, old: &Self) {
+        self.poll.carry_over(&old.poll);
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        self.poll.read_refresh(rt, |load| {
+            load.set(LoadAvg::read());
+            Some("load")
+        });
+        let load = match self.poll.data().get() {
+            Some(load) => load,
+            None => return f(Value::Null),
+        };
+        match key {
+            "" | "1" => f(Value::Float(load.avg[0])),
+            "5" => f(Value::Float(load.avg[1])),
+            "15" => f(Value::Float(load.avg[2])),
+            "running" => f(Value::Float(load.running as f64)),
+            "total" => f(Value::Float(load.total as f64)),
+            "cores" => f(Value::Float(self.cores as f64)),
+            "per-core" => f(Value::Float(load.avg[0] / self.cores as f64)),
+            "state" => {
+                let per_core = load.avg[0] / self.cores as f64;
+                let state = match (self.high, self.critical) {
+                    (_, Some(crit)) if per_core >= crit => "critical",
+                    (Some(high), _) if per_core >= high => "high",
+                    _ => "normal",
+                };
+                f(Value::Borrow(state))
+            }
+            _ => f(Value::Null),
+        }
+    }
+}
@@ -1,31 +1,94 @@
 use std::error::Error;
 
 mod bar;
+mod battery;
+mod condition;
+mod cpu;
 mod data;
 #[cfg(feature = "dbus")]
 mod dbus;
 mod event;
 mod font;
+#[cfg(feature = "http")]
+mod http;
 mod icon;
 mod item;
+mod load;
+mod memory;
 #[cfg(feature = "dbus")]
 mod mpris;
+mod network;
 #[cfg(feature = "pulse")]
 mod pulse;
 mod render;
+mod socket;
+mod sparkline;
 mod state;
 mod sway;
+mod temperature;
 #[cfg(feature = "dbus")]
 mod tray;
+#[cfg(feature = "dbus")]
+mod upower;
+mod uptime;
 mod util;
 mod wayland;
 mod wlr;
 
-use state::State;
+use state::{ConfigSource, State};
 use wayland::WaylandClient;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("warn")).init();
+    let mut log = env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("warn"));
+    let mut config = ConfigSource::Default;
+    #[cfg(feature = "dbus")]
+    let mut list_tray = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let (arg, mut inline) = match arg.split_once('=') {
+            Some((arg, value)) => (arg.to_owned(), Some(value.to_owned())),
+            None => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{} requires a value", arg))
+        };
+        match arg.as_str() {
+            "--config" => {
+                let path = value()?;
+                config = if path == "-" {
+                    let mut cfg = String::new();
+                    std::io::Read::read_to_string(&mut std::io::stdin(), &mut cfg)?;
+                    ConfigSource::Stdin(cfg)
+                } else {
+                    ConfigSource::File(path.into())
+                };
+            }
+            "--log-level" => {
+                // same syntax as RUST_LOG, which this overrides
+                log.parse_filters(&value()?);
+            }
+            "--log-file" => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(value()?)?;
+                log.target(env_logger::Target::Pipe(Box::new(file)));
+            }
+            #[cfg(feature = "dbus")]
+            "--list-tray" => list_tray = true,
+            "-h" | "--help" => {
+                println!(
+                    "Usage: rwaybar [--config <path>|-] [--log-level <filter>] [--log-file <path>] [--list-tray]"
+                );
+                return Ok(());
+            }
+            _ => Err(format!("Unknown argument '{}'", arg))?,
+        }
+    }
+    log.init();
 
     // Avoid producing zombies.  We don't need exit status, and can detect end-of-file on pipes to
     // handle any respawning required.
@@ -37,10 +100,15 @@ fn main() -> Result<(), Box<dyn Error>> {
         .enable_all()
         .build()?;
 
+    #[cfg(feature = "dbus")]
+    if list_tray {
+        return rt.block_on(tray::print_items());
+    }
+
     tokio::task::LocalSet::new().block_on(&rt, async move {
         let (client, wl_queue) = WaylandClient::new()?;
 
-        let state = State::new(client)?;
+        let state = State::new(client, config)?;
 
         match wayland::run_queue(wl_queue, state).await? {}
     })
@@ -0,0 +1,116 @@
+//! Memory and swap usage from /proc/meminfo
+use crate::data::{Periodic, Value};
+use crate::state::Runtime;
+use crate::util::{format_size, toml_to_f64, Cell};
+use log::debug;
+use std::fs;
+
+/// Sizes in bytes
+#[derive(Debug, Default, Clone, Copy)]
+struct MemInfo {
+    total: u64,
+    available: u64,
+    swap_total: u64,
+    swap_free: u64,
+}
+
+impl MemInfo {
+    fn read() -> Option<Self> {
+        let text = match fs::read_to_string("/proc/meminfo") {
+            Ok(text) => text,
+            Err(e) => {
+                debug!("Could not read /proc/meminfo: {}", e);
+                return None;
+            }
+        };
+        // "MemTotal:       16318412 kB"
+        let get = |name: &str| {
+            text.lines().find_map(|line| {
+                let value = line.strip_prefix(name)?.strip_prefix(':')?;
+                let kb: u64 = value.split_ascii_whitespace().next()?.parse().ok()?;
+                Some(kb * 1024)
+            })
+        };
+        // MemAvailable was added in Linux 3.14; this is the estimate it replaced
+        let available = get("MemAvailable").or_else(|| {
+            Some(get("MemFree")? + get("Buffers").unwrap_or(0) + get("Cached").unwrap_or(0))
+        })?;
+        Some(MemInfo {
+            total: get("MemTotal")?,
+            available,
+            swap_total: get("SwapTotal").unwrap_or(0),
+            swap_free: get("SwapFree").unwrap_or(0),
+        })
+    }
+}
+
+fn percent(part: u64, whole: u64) -> Value<'static> {
+    if whole == 0 {
+        Value::Null
+    } else {
+        Value::Float(part as f64 * 100.0 / whole as f64)
+    }
+}
+
+#[derive(Debug)]
+pub struct Memory {
+    poll: Periodic<Cell<Option<MemInfo>>>,
+    /// Show sizes like "3.2 GiB" instead of as a number of bytes
+    human: bool,
+}
+
+impl Memory {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        Memory {
+            poll: Periodic::new(
+                toml_to_f64(config.get("poll")).unwrap_or(10.0),
+                Cell::default(),
+            ),
+            human: config
+                .get("human")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(true),
+        }
+    }
+
+    pub fn carry_over(&self, old: &Self) {
+        self.poll.carry_over(&old.poll);
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        self.poll.read_refresh(rt, |mem| {
+            mem.set(MemInfo::read());
+            Some("memory")
+        });
+        let mem = match self.poll.data().get() {
+            Some(mem) => mem,
+            None => return f(Value::Null),
+        };
+        let size = |bytes: u64| {
+            if self.human {
+                Value::Owned(format_size(bytes))
+            } else {
+                Value::Float(bytes as f64)
+            }
+        };
+        let used = mem.total.saturating_sub(mem.available);
+        let swap_used = mem.swap_total.saturating_sub(mem.swap_free);
+        match key {
+            "" | "percent" => f(percent(used, mem.total)),
+            "used" => f(size(used)),
+            "total" => f(size(mem.total)),
+            "available" => f(size(mem.available)),
+            "swap-percent" => f(percent(swap_used, mem.swap_total)),
+            "swap-used" => f(size(swap_used)),
+            "swap-total" => f(size(mem.swap_total)),
+            "swap-free" => f(size(mem.swap_free)),
+            _ => f(Value::Null),
+        }
+    }
+}
@@ -149,14 +149,32 @@ struct Player {
     proxy: PlayerProxy<'static>,
     playing: Option<PlayState>,
     meta: Dict<'static, 'static>,
+    /// The value of [MediaPlayer2::activity] when this player last started playing, or 0
+    active: u64,
 }
 
 #[derive(Debug, Default)]
 struct MediaPlayer2 {
     players: Cell<Vec<Player>>,
+    /// Counts the times that any player started playing
+    activity: Cell<u64>,
     interested: Cell<NotifierList>,
 }
 
+/// The player to use when none is named: the most recently active of the players that are
+/// playing, then of the paused ones, then of any
+fn default_player(players: &[Player]) -> Option<&Player> {
+    // max_by_key returns the last maximum, so reverse to prefer the oldest player on a tie
+    players.iter().rev().max_by_key(|p| {
+        let rank = match p.playing {
+            Some(PlayState::Playing) => 2,
+            Some(PlayState::Paused) => 1,
+            _ => 0,
+        };
+        (rank, p.active)
+    })
+}
+
 async fn initial_query(
     target: Rc<MediaPlayer2>,
     bus_name: BusName<'static>,
@@ -180,6 +198,11 @@ async fn initial_query(
 
     let playing = PlayState::parse(&proxy.playback_status().await?);
     let meta = proxy.metadata().await?;
+    let active = if playing == Some(PlayState::Playing) {
+        target.next_activity()
+    } else {
+        0
+    };
 
     target.players.take_in(|players| {
         players.push(Player {
@@ -187,6 +210,7 @@ async fn initial_query(
             proxy,
             playing,
             meta,
+            active,
         });
     });
 
@@ -259,6 +283,12 @@ impl MediaPlayer2 {
         rv
     }
 
+    fn next_activity(&self) -> u64 {
+        let rv = self.activity.get() + 1;
+        self.activity.set(rv);
+        rv
+    }
+
     fn handle_mpris_update(
         &self,
         hdr: &zbus::MessageHeader,
@@ -285,7 +315,13 @@ impl MediaPlayer2 {
                 match prop {
                     "PlaybackStatus" => {
                         if let Ok(status) = value.try_into() {
-                            player.playing = PlayState::parse(status);
+                            let playing = PlayState::parse(status);
+                            if playing == Some(PlayState::Playing)
+                                && player.playing != Some(PlayState::Playing)
+                            {
+                                player.active = self.next_activity();
+                            }
+                            player.playing = playing;
                         }
                     }
                     "Metadata" => {
@@ -326,21 +362,10 @@ pub fn read_in<F: FnOnce(Value) -> R, R>(
                 player = players.iter().find(|p| &*p.name_tail == name);
             } else {
                 field = key;
-                // Prefer playing players, then paused, then any
-                //
-                player = players
-                    .iter()
-                    .filter(|p| p.playing == Some(PlayState::Playing))
-                    .chain(
-                        players
-                            .iter()
-                            .filter(|p| p.playing == Some(PlayState::Paused)),
-                    )
-                    .chain(players.iter())
-                    .next();
+                player = default_player(players);
             }
 
-            if field == "state" {
+            if field == "state" || field == "status" {
                 return match player.and_then(|p| p.playing) {
                     Some(PlayState::Playing) => f(Value::Borrow("Playing")),
                     Some(PlayState::Paused) => f(Value::Borrow("Paused")),
@@ -425,17 +450,7 @@ pub fn write(_name: &str, target: &str, key: &str, command: Value, _rt: &Runtime
             } else if !key.is_empty() {
                 player = players.iter().find(|p| &*p.name_tail == key);
             } else {
-                // Prefer playing players, then paused, then any
-                player = players
-                    .iter()
-                    .filter(|p| p.playing == Some(PlayState::Playing))
-                    .chain(
-                        players
-                            .iter()
-                            .filter(|p| p.playing == Some(PlayState::Paused)),
-                    )
-                    .chain(players.iter())
-                    .next();
+                player = default_player(players);
             }
 
             let player = match player {
@@ -463,7 +478,23 @@ pub fn write(_name: &str, target: &str, key: &str, command: Value, _rt: &Runtime
                         .unwrap(),
                     );
                 }
-                // TODO seek, volume?
+                cmd if cmd.starts_with("Seek ") => match cmd[5..].trim().parse::<f64>() {
+                    Ok(secs) => {
+                        dbus.send(
+                            zbus::Message::method(
+                                None::<&str>,
+                                Some(player.proxy.destination().clone()),
+                                "/org/mpris/MediaPlayer2",
+                                Some("org.mpris.MediaPlayer2.Player"),
+                                "Seek",
+                                &((secs * 1_000_000.0) as i64),
+                            )
+                            .unwrap(),
+                        );
+                    }
+                    Err(_) => error!("Invalid offset in {}", cmd),
+                },
+                // TODO volume?
                 "Raise" | "Quit" => {
                     dbus.send(
                         zbus::Message::method(
@@ -0,0 +1,152 @@
+//! Network throughput from /proc/net/dev
+use crate::data::{Periodic, Value};
+use crate::state::Runtime;
+use crate::util::{toml_to_f64, toml_to_string, Cell};
+use log::debug;
+use std::fs;
+use std::time::Instant;
+
+#[derive(Debug, Default)]
+struct Traffic {
+    iface: String,
+    /// The rx and tx byte counters of the last sample, and when it was taken
+    last: Option<(u64, u64, Instant)>,
+    /// Bytes per second received and sent between the last two samples
+    rates: Option<(f64, f64)>,
+}
+
+impl Traffic {
+    fn update(&mut self, config: &str) {
+        let iface = if config.is_empty() {
+            default_iface()
+        } else {
+            Some(config.to_owned())
+        };
+        let iface = match iface {
+            Some(iface) => iface,
+            None => {
+                *self = Traffic::default();
+                return;
+            }
+        };
+        let now = Instant::now();
+        let counters = read_counters(&iface);
+        if iface != self.iface || counters.is_none() {
+            // The interface changed or is gone (such as a VPN that went down); start over so that
+            // the first sample after it comes back does not count all of its traffic as new
+            self.iface = iface;
+            self.last = None;
+            self.rates = None;
+        }
+        let (rx, tx) = match counters {
+            Some(counters) => counters,
+            None => return,
+        };
+        self.rates = match self.last {
+            // counters that went backwards were reset, so there is nothing to compare to
+            Some((old_rx, old_tx, when)) if rx >= old_rx && tx >= old_tx => {
+                let secs = now.duration_since(when).as_secs_f64();
+                if secs > 0.0 {
+                    Some(((rx - old_rx) as f64 / secs, (tx - old_tx) as f64 / secs))
+                } else {
+                    self.rates
+                }
+            }
+            _ => Some((0.0, 0.0)),
+        };
+        self.last = Some((rx, tx, now));
+    }
+}
+
+/// Find the interface used by the default route
+fn default_iface() -> Option<String> {
+    let text = match fs::read_to_string("/proc/net/route") {
+        Ok(text) => text,
+        Err(e) => {
+            debug!("Could not read /proc/net/route: {}", e);
+            return None;
+        }
+    };
+    // "Iface Destination Gateway Flags RefCnt Use Metric Mask ..."; the lowest metric wins
+    text.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_ascii_whitespace().collect();
+            if fields.get(1) != Some(&"00000000") || fields.get(7) != Some(&"00000000") {
+                return None;
+            }
+            let metric: u32 = fields.get(6)?.parse().ok()?;
+            Some((metric, fields[0]))
+        })
+        .min()
+        .map(|(_, iface)| iface.to_owned())
+}
+
+/// Read the received and sent byte counters of an interface
+fn read_counters(iface: &str) -> Option<(u64, u64)> {
+    let text = match fs::read_to_string("/proc/net/dev") {
+        Ok(text) => text,
+        Err(e) => {
+            debug!("Could not read /proc/net/dev: {}", e);
+            return None;
+        }
+    };
+    // "  eth0: 1234 56 0 0 0 0 0 0 7890 12 ..." - rx bytes is the first field, tx bytes the ninth
+    text.lines().find_map(|line| {
+        let (name, stats) = line.split_once(':')?;
+        if name.trim() != iface {
+            return None;
+        }
+        let mut fields = stats.split_ascii_whitespace();
+        let rx = fields.next()?.parse().ok()?;
+        let tx = fields.nth(7)?.parse().ok()?;
+        Some((rx, tx))
+    })
+}
+
+#[derive(Debug)]
+pub struct Network {
+    iface: Box<str>,
+    poll: Periodic<Cell<Traffic>>,
+}
+
+impl Network {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        Network {
+            iface: toml_to_string(config.get("interface"))
+                .unwrap_or_default()
+                .into(),
+            poll: Periodic::new(
+                toml_to_f64(config.get("poll")).unwrap_or(2.0),
+                Cell::default(),
+            ),
+        }
+    }
+
+    /// Keep the last sample across a reload so the rate is not reset
+    pub fn carry_over(&self, old: &Self) {
+        if self.iface == old.iface {
+            self.poll.carry_over(&old.poll);
+        }
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        let iface = self.iface.clone();
+        self.poll.read_refresh(rt, move |traffic| {
+            traffic.take_in(|traffic| traffic.update(&iface));
+            Some("network")
+        });
+        let traffic = self.poll.data();
+        traffic.take_in(|traffic| match (key, traffic.rates) {
+            ("iface", _) if !traffic.iface.is_empty() => f(Value::Borrow(&traffic.iface)),
+            ("" | "rx", Some((rate, _))) | ("tx", Some((_, rate))) => f(Value::Float(rate)),
+            _ => f(Value::Null),
+        })
+    }
+}
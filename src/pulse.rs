@@ -4,7 +4,7 @@ use crate::state::Runtime;
 use crate::util::{self, Cell};
 use libpulse_binding::callbacks::ListResult;
 use libpulse_binding::context::introspect::{
-    ClientInfo, Introspector, SinkInfo, SinkInputInfo, SourceInfo, SourceOutputInfo,
+    ClientInfo, Introspector, ServerInfo, SinkInfo, SinkInputInfo, SourceInfo, SourceOutputInfo,
 };
 use libpulse_binding::context::subscribe::{Facility, Operation};
 use libpulse_binding::context::{self, Context};
@@ -131,12 +131,7 @@ impl PulseData {
 
         let data = self.clone();
         inspect.get_server_info(move |info| {
-            if let Some(name) = &info.default_sink_name {
-                data.default_sink.set((**name).to_owned());
-            }
-            if let Some(name) = &info.default_source_name {
-                data.default_source.set((**name).to_owned());
-            }
+            data.set_server_info(info);
         });
 
         let data = self.clone();
@@ -177,6 +172,13 @@ impl PulseData {
         idx: u32,
     ) {
         match (facility, op) {
+            // for example, the default sink changes when headphones are plugged in
+            (Some(Facility::Server), Some(Operation::Changed)) => {
+                let data = self.clone();
+                inspect.get_server_info(move |info| {
+                    data.set_server_info(info);
+                });
+            }
             (Some(Facility::Sink), Some(Operation::New))
             | (Some(Facility::Sink), Some(Operation::Changed)) => {
                 let data = self.clone();
@@ -246,6 +248,17 @@ impl PulseData {
         }
     }
 
+    fn set_server_info(&self, info: &ServerInfo) {
+        let sink = info.default_sink_name.as_deref().unwrap_or("").to_owned();
+        let source = info.default_source_name.as_deref().unwrap_or("").to_owned();
+        let old_sink = self.default_sink.replace(sink.clone());
+        let old_source = self.default_source.replace(source.clone());
+        if old_sink != sink || old_source != source {
+            debug!("Default sink is '{}', source is '{}'", sink, source);
+            self.interested.take().notify_data("pulse:default");
+        }
+    }
+
     fn add_sink(&self, item: ListResult<&SinkInfo>) {
         self.interested.take().notify_data("pulse");
         match item {
@@ -572,6 +585,12 @@ pub fn read_in<F: FnOnce(Value) -> R, R>(
                         f(Value::Null)
                     }
                 }
+                "percent" => {
+                    let volume = port.volume.avg();
+                    f(Value::Float(
+                        (volume.0 as f64 * 100.0 / Volume::NORMAL.0 as f64).round(),
+                    ))
+                }
                 "mute" => f(Value::Bool(port.mute)),
                 _ => {
                     info!("Unknown key '{}' in '{}'", key, cfg_name);
@@ -1,21 +1,40 @@
 use crate::font::{FontMapped, RenderKey, TextImage};
-use crate::state::Runtime;
+use crate::state::{NotifierList, Runtime};
+use crate::util::spawn_noerr;
 use crate::wayland::{SurfaceData, WaylandClient};
 use log::error;
-use smithay_client_toolkit::shm::slot::SlotPool;
+use smithay_client_toolkit::shm::slot::{Buffer, SlotPool};
 use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
 use std::time;
 use tiny_skia::PixmapMut;
 use wayland_client::protocol::wl_pointer::WlPointer;
 use wayland_client::protocol::wl_shm::Format;
 use wayland_client::protocol::wl_surface::WlSurface;
 
+/// How long to wait before drawing again after failing to allocate a buffer
+const RETRY_DELAY: time::Duration = time::Duration::from_secs(1);
+
+/// Copy an RGBA image of the given width into one a quarter turn clockwise from it
+fn rotate_clockwise(src: &[u8], dst: &mut [u8], width: usize) {
+    let height = src.len() / 4 / width;
+    for (y, row) in src.chunks_exact(width * 4).enumerate() {
+        let dst_x = height - 1 - y;
+        for (x, px) in row.chunks_exact(4).enumerate() {
+            let i = (x * height + dst_x) * 4;
+            dst[i..i + 4].copy_from_slice(px);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Renderer {
     shm: Option<SlotPool>,
-    cursor_surf: Option<WlSurface>,
-    cursor_spot: (i32, i32),
+    /// Cursor surfaces and their hotspots, by cursor name
+    cursors: Vec<(Box<str>, Option<(WlSurface, (i32, i32))>)>,
+    cursor_shown: Option<Box<str>>,
     has_be_rgba: Option<bool>,
 }
 
@@ -23,8 +42,8 @@ impl Renderer {
     pub fn new() -> Self {
         Renderer {
             shm: None,
-            cursor_surf: None,
-            cursor_spot: (0, 0),
+            cursors: Vec::new(),
+            cursor_shown: None,
             has_be_rgba: None,
         }
     }
@@ -57,33 +76,59 @@ impl Renderer {
         render(&mut ctx)
     }
 
+    /// Draw a new frame on a surface.  If `vertical` is set, the contents are laid out as if the
+    /// surface was turned a quarter turn counterclockwise, and then turned back into place.
     pub fn render<R>(
         &mut self,
         rt: &mut Runtime,
         surface: &WlSurface,
+        vertical: bool,
+        skip_unchanged: bool,
         render: impl FnOnce(&mut Render) -> R,
     ) -> Option<R> {
         let surface_data = SurfaceData::from_wl(surface);
-        let (canvas, finalize) = self.render_be_rgba(&mut rt.wayland, surface);
-        let mut canvas = match tiny_skia::PixmapMut::from_bytes(
-            canvas,
-            surface_data.pixel_width() as u32,
-            surface_data.pixel_height() as u32,
-        ) {
+        let (buffer, canvas, finalize) = match self.render_be_rgba(&mut rt.wayland, surface) {
+            Some(rv) => rv,
+            None => {
+                // Skip this frame and try again later instead of giving up on the surface
+                surface_data.render_failed();
+                let mut notify = NotifierList::active(rt);
+                spawn_noerr(async move {
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    notify.notify_data("render retry");
+                });
+                return None;
+            }
+        };
+        let pixel_width = surface_data.pixel_width() as u32;
+        let pixel_height = surface_data.pixel_height() as u32;
+        let mut canvas = match tiny_skia::PixmapMut::from_bytes(canvas, pixel_width, pixel_height) {
             Some(canvas) => canvas,
             None => return None,
         };
         canvas.fill(tiny_skia::Color::TRANSPARENT);
+        let mut sideways = match vertical {
+            true => Some(tiny_skia::Pixmap::new(pixel_height, pixel_width)?),
+            false => None,
+        };
+        let mut sideways = sideways.as_mut().map(|pixmap| pixmap.as_mut());
+        let (width, height) = match vertical {
+            true => (surface_data.height(), surface_data.width()),
+            false => (surface_data.width(), surface_data.height()),
+        };
         let font = &rt.fonts[0];
 
         let mut ctx = Render {
-            canvas: &mut canvas,
+            canvas: match &mut sideways {
+                Some(sideways) => sideways,
+                None => &mut canvas,
+            },
             cache: &rt.cache,
             render_extents: (
                 tiny_skia::Point::zero(),
                 tiny_skia::Point {
-                    x: surface_data.width() as f32,
-                    y: surface_data.height() as f32,
+                    x: width as f32,
+                    y: height as f32,
                 },
             ),
             render_pos: tiny_skia::Point::zero(),
@@ -100,8 +145,36 @@ impl Renderer {
             runtime: rt,
         };
         let rv = render(&mut ctx);
-        finalize(ctx.canvas.data_mut());
-        surface.frame(&ctx.runtime.wayland.queue, surface.clone());
+        drop(ctx);
+        if let Some(sideways) = &mut sideways {
+            rotate_clockwise(
+                sideways.data_mut(),
+                canvas.data_mut(),
+                pixel_height as usize,
+            );
+        }
+        finalize(canvas.data_mut());
+
+        let (width, height) = (canvas.width(), canvas.height());
+        if skip_unchanged {
+            let mut hasher = DefaultHasher::new();
+            (width, height).hash(&mut hasher);
+            hasher.write(canvas.data_mut());
+            if !surface_data.set_last_frame(hasher.finish()) {
+                // Leave the buffer already on screen alone; committing an identical one only
+                // costs the compositor a redraw, which can show up as flicker.
+                if surface_data.render_skipped() {
+                    NotifierList::active(rt).notify_data("render");
+                }
+                return Some(rv);
+            }
+        }
+        surface_data.set_viewport();
+        buffer
+            .attach_to(surface)
+            .expect("New buffers are not already attached");
+        surface.damage_buffer(0, 0, width as i32, height as i32);
+        surface.frame(&rt.wayland.queue, surface.clone());
         surface.commit();
         Some(rv)
     }
@@ -110,7 +183,7 @@ impl Renderer {
         &mut self,
         wl: &WaylandClient,
         target: &WlSurface,
-    ) -> (&mut [u8], impl FnOnce(&mut [u8])) {
+    ) -> Option<(Buffer, &mut [u8], impl FnOnce(&mut [u8]))> {
         let data = SurfaceData::from_wl(target);
         let width = data.pixel_width();
         let height = data.pixel_height();
@@ -120,10 +193,13 @@ impl Renderer {
 
         let shm = match &mut self.shm {
             Some(shm) => shm,
-            v @ None => {
-                let shm = SlotPool::new(len * 2, &wl.shm).unwrap();
-                v.get_or_insert(shm)
-            }
+            v @ None => match SlotPool::new(len * 2, &wl.shm) {
+                Ok(shm) => v.insert(shm),
+                Err(e) => {
+                    error!("Could not create shm pool: {}", e);
+                    return None;
+                }
+            },
         };
 
         let has_be_rgba = *self
@@ -135,24 +211,26 @@ impl Renderer {
             // wayland always supports this format, so we convert to it as a fallback
             Format::Argb8888
         };
-        let (buffer, canvas) = shm.create_buffer(width, height, stride, fmt).expect("OOM");
-
-        buffer
-            .attach_to(&target)
-            .expect("New buffers are not already attached");
-        target.damage_buffer(0, 0, width, height);
+        let (buffer, canvas) = match shm.create_buffer(width, height, stride, fmt) {
+            Ok(rv) => rv,
+            Err(e) => {
+                error!("Could not allocate a {}x{} buffer: {}", width, height, e);
+                return None;
+            }
+        };
 
-        (canvas, move |buf| {
+        Some((buffer, canvas, move |buf: &mut [u8]| {
             if !has_be_rgba {
                 for pixel in buf.chunks_mut(4) {
                     let [r, g, b, a]: [u8; 4] = (&*pixel).try_into().expect("partial pixel");
                     pixel.copy_from_slice(&[b, g, r, a]);
                 }
             }
-        })
+        }))
     }
 
-    fn setup_cursor(&mut self, wl: &WaylandClient) {
+    /// Load a cursor from the theme, returning its surface and hotspot
+    fn load_cursor(wl: &WaylandClient, name: &str) -> Option<(WlSurface, (i32, i32))> {
         let mut scale = 1;
 
         for output in wl.output.outputs() {
@@ -169,38 +247,70 @@ impl Renderer {
             .and_then(|s| s.parse().ok())
             .unwrap_or(24u32);
 
-        let mut cursor_theme = wayland_cursor::CursorTheme::load_from_name(
+        // Without a cursor, the compositor leaves whatever cursor was shown before, which is
+        // better than exiting
+        let mut cursor_theme = match wayland_cursor::CursorTheme::load_from_name(
             &wl.conn,
             wl.shm.wl_shm().clone(),
             &base_theme,
             base_size * scale as u32,
-        )
-        .unwrap();
-        let cursor = cursor_theme
-            .get_cursor("default")
-            .expect("Could not load cursor, check XCURSOR_THEME")
-            .clone();
+        ) {
+            Ok(theme) => theme,
+            Err(e) => {
+                error!("Could not load cursor theme '{}': {}", base_theme, e);
+                return None;
+            }
+        };
+        // "pointer" is the freedesktop name; older themes only have the X11 name
+        let fallback = match name {
+            "pointer" => Some("hand2"),
+            _ => None,
+        };
+        let cursor = match cursor_theme.get_cursor(name) {
+            Some(cursor) => cursor.clone(),
+            None => match fallback.and_then(|name| cursor_theme.get_cursor(name)) {
+                Some(cursor) => cursor.clone(),
+                None => {
+                    error!("Could not load cursor '{}', check XCURSOR_THEME", name);
+                    return None;
+                }
+            },
+        };
 
         let cursor_surf = wl.compositor.create_surface(&wl.queue);
         let cursor_img = &cursor[0];
         let (w, h) = cursor_img.dimensions();
         let (x, y) = cursor[0].hotspot();
-        self.cursor_spot = (x as i32 / scale, y as i32 / scale);
         cursor_surf.set_buffer_scale(scale);
         cursor_surf.attach(Some(&cursor_img), 0, 0);
         cursor_surf.damage_buffer(0, 0, w as _, h as _);
         cursor_surf.commit();
-        self.cursor_surf = Some(cursor_surf);
+        Some((cursor_surf, (x as i32 / scale, y as i32 / scale)))
     }
 
-    pub fn set_cursor(&mut self, wl: &WaylandClient, mouse: &WlPointer, serial: u32) {
-        if self.cursor_surf.is_none() {
-            self.setup_cursor(wl);
+    /// Show the named cursor, if it is not already shown
+    pub fn set_cursor(&mut self, wl: &WaylandClient, mouse: &WlPointer, serial: u32, name: &str) {
+        if self.cursor_shown.as_deref() == Some(name) {
+            return;
         }
-        if self.cursor_surf.is_some() {
-            let (x, y) = self.cursor_spot;
-            mouse.set_cursor(serial, self.cursor_surf.as_ref(), x, y);
+        // Cursors that could not be loaded are kept as None to avoid retrying every time
+        let pos = match self.cursors.iter().position(|c| *c.0 == *name) {
+            Some(pos) => pos,
+            None => {
+                let cursor = Self::load_cursor(wl, name);
+                self.cursors.push((name.into(), cursor));
+                self.cursors.len() - 1
+            }
+        };
+        if let Some((surf, (x, y))) = &self.cursors[pos].1 {
+            mouse.set_cursor(serial, Some(surf), *x, *y);
         }
+        self.cursor_shown = Some(name.into());
+    }
+
+    /// The pointer left our surfaces; the compositor will need a cursor again on entry
+    pub fn cursor_left(&mut self) {
+        self.cursor_shown = None;
     }
 }
 
@@ -297,6 +407,56 @@ impl Render<'_, '_> {
         }
     }
 
+    /// Create a transparent canvas the same size as this one, for use with [Self::with_layer]
+    pub fn new_layer(&self) -> tiny_skia::Pixmap {
+        tiny_skia::Pixmap::new(self.canvas.width(), self.canvas.height())
+            .unwrap_or_else(|| tiny_skia::Pixmap::new(1, 1).unwrap())
+    }
+
+    /// Clear a layer from an earlier frame for reuse, or create a new one if it is missing or
+    /// the wrong size
+    pub fn reuse_layer(&self, layer: Option<tiny_skia::Pixmap>) -> tiny_skia::Pixmap {
+        let size = (self.canvas.width(), self.canvas.height());
+        match layer {
+            Some(mut layer) if (layer.width(), layer.height()) == size => {
+                layer.fill(tiny_skia::Color::TRANSPARENT);
+                layer
+            }
+            _ => self.new_layer(),
+        }
+    }
+
+    /// Render onto a layer from [Self::new_layer], using the same coordinates as this canvas.
+    ///
+    /// The pen position is updated as if the rendering had been done on this canvas.
+    pub fn with_layer<R>(
+        &mut self,
+        layer: &mut tiny_skia::Pixmap,
+        f: impl FnOnce(&mut Render) -> R,
+    ) -> R {
+        let mut ctx = Render {
+            canvas: &mut layer.as_mut(),
+            cache: self.cache,
+            render_extents: self.render_extents,
+            render_xform: self.render_xform,
+            render_pos: self.render_pos,
+            render_flex: self.render_flex,
+
+            font: self.font,
+            font_size: self.font_size,
+            font_color: self.font_color,
+            text_stroke: self.text_stroke,
+            text_stroke_size: self.text_stroke_size,
+
+            align: self.align,
+            err_name: self.err_name,
+            runtime: self.runtime,
+        };
+        let rv = f(&mut ctx);
+        self.render_pos = ctx.render_pos;
+        rv
+    }
+
     /// Create a canvas for rendering `(origin.x .. x_max)`, returning the pixmap and the
     /// coordinates (under an identity transform) that should be used for draw_pixmap.
     pub fn with_new_canvas_x<R>(
@@ -392,6 +552,12 @@ impl Align {
     }
 
     pub fn parse_hv(value: Cow<str>) -> Option<f32> {
+        match &*value {
+            "left" | "top" => return Some(0.0),
+            "center" | "middle" => return Some(MIDDLE),
+            "right" | "bottom" => return Some(1.0),
+            _ => {}
+        }
         if value.ends_with('%') {
             let value = &value[..value.len() - 1];
             let pct = value.parse::<f32>().ok()?;
@@ -432,6 +598,8 @@ impl Align {
                     vert: Some(MIDDLE),
                 }
             }
+            Some("left") => self.horiz = Some(0.0),
+            Some("right") => self.horiz = Some(1.0),
             Some("") | None => {}
             Some(x) => {
                 error!("Unknown alignment {}", x);
@@ -0,0 +1,250 @@
+//! Values pushed into the bar by external programs over a unix socket
+use crate::data::Value;
+use crate::state::{NotifierList, Runtime, State};
+use crate::util::{spawn, spawn_handle, spawn_noerr, Cell};
+use futures_util::future::RemoteHandle;
+use json::JsonValue;
+use log::{debug, error, info};
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// The socket in `$XDG_RUNTIME_DIR` that accepts commands like `load-config <name>`
+const CONTROL_SOCKET: &str = "rwaybar-control";
+
+thread_local! {
+    /// Sockets we have bound (and their inode numbers), for removal on shutdown
+    static BOUND: RefCell<Vec<(PathBuf, u64)>> = RefCell::new(Vec::new());
+}
+
+/// Remove a socket file, but only if it is still the one we created
+fn unlink_if_ours(path: &Path, ino: u64) {
+    if fs::symlink_metadata(path).map_or(false, |m| m.ino() == ino) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Remove all socket files we created; called prior to exit
+pub fn cleanup() {
+    BOUND.with(|bound| {
+        for (path, ino) in bound.borrow_mut().drain(..) {
+            unlink_if_ours(&path, ino);
+        }
+    });
+}
+
+/// Ownership of a bound socket path; the file is removed when this is dropped
+#[derive(Debug)]
+struct Bound {
+    path: PathBuf,
+    ino: u64,
+}
+
+impl Bound {
+    fn bind(path: PathBuf) -> io::Result<(UnixListener, Self)> {
+        let listener = match UnixListener::bind(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                // A socket file from a previous run may have been left behind; only replace it if
+                // nobody is listening on it.
+                if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                    return Err(e);
+                }
+                fs::remove_file(&path)?;
+                UnixListener::bind(&path)?
+            }
+            rv => rv?,
+        };
+        let ino = fs::symlink_metadata(&path)?.ino();
+        BOUND.with(|bound| bound.borrow_mut().push((path.clone(), ino)));
+        Ok((listener, Bound { path, ino }))
+    }
+}
+
+impl Drop for Bound {
+    fn drop(&mut self) {
+        unlink_if_ours(&self.path, self.ino);
+        BOUND.with(|bound| {
+            bound
+                .borrow_mut()
+                .retain(|(path, ino)| *ino != self.ino || *path != self.path)
+        });
+    }
+}
+
+#[derive(Debug)]
+struct SocketData {
+    value: Cell<JsonValue>,
+    interested: Cell<NotifierList>,
+    _listener: RemoteHandle<()>,
+}
+
+#[derive(Debug)]
+pub struct Socket {
+    path: Box<str>,
+    data: Cell<Option<Rc<SocketData>>>,
+}
+
+impl Socket {
+    pub fn from_toml(config: &toml::Value) -> Result<Self, &'static str> {
+        let path = config
+            .get("path")
+            .and_then(|v| v.as_str())
+            .ok_or("A socket path is required")?
+            .into();
+        Ok(Socket {
+            path,
+            data: Cell::new(None),
+        })
+    }
+
+    pub fn init(&self, name: &str, rt: &Runtime, from: Option<&Self>) {
+        if let Some(old) = from.filter(|old| old.path == self.path) {
+            // keep the existing listener (and the last value received) across a reload
+            if let Some(data) = old.data.take() {
+                self.data.set(Some(data));
+                return;
+            }
+        }
+
+        let path = if Path::new(&*self.path).is_absolute() {
+            PathBuf::from(&*self.path)
+        } else {
+            match rt.xdg.place_runtime_file(&*self.path) {
+                Ok(path) => path,
+                Err(e) => {
+                    error!("Could not create socket '{}': {}", self.path, e);
+                    return;
+                }
+            }
+        };
+        let (listener, bound) = match Bound::bind(path) {
+            Ok(rv) => rv,
+            Err(e) => {
+                error!("Could not listen on '{}': {}", self.path, e);
+                return;
+            }
+        };
+        let name = name.to_owned();
+        self.data.set(Some(Rc::new_cyclic(|weak| SocketData {
+            value: Cell::new(JsonValue::new_object()),
+            interested: Default::default(),
+            _listener: spawn_handle("Socket", accept(listener, bound, name, weak.clone())),
+        })));
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        let data = match self.data.take_in_some(|data| data.clone()) {
+            Some(data) => data,
+            None => return f(Value::Null),
+        };
+        data.interested.take_in(|i| i.add(rt));
+        let key = if key.is_empty() { "text" } else { key };
+        let value = data.value.replace(JsonValue::Null);
+        let rv = match &value[key] {
+            v if v.is_number() => f(Value::Float(v.as_f64().unwrap_or_default())),
+            v if v.is_boolean() => f(Value::Bool(v.as_bool().unwrap_or_default())),
+            v => f(Value::Borrow(v.as_str().unwrap_or(""))),
+        };
+        data.value.set(value);
+        rv
+    }
+}
+
+async fn accept(
+    listener: UnixListener,
+    bound: Bound,
+    name: String,
+    data: Weak<SocketData>,
+) -> Result<(), Box<dyn Error>> {
+    let _bound = bound;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_noerr(read_lines(stream, name.clone(), data.clone()));
+    }
+}
+
+async fn read_lines(stream: UnixStream, name: String, data: Weak<SocketData>) {
+    let mut lines = BufReader::new(stream).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("Error reading from socket '{}': {}", name, e);
+                return;
+            }
+        };
+        let Some(data) = data.upgrade() else { return };
+        let value = if line.trim_start().starts_with('{') {
+            match json::parse(&line) {
+                Ok(v) if v.is_object() => v,
+                _ => {
+                    info!("Ignoring bad JSON from socket '{}'", name);
+                    continue;
+                }
+            }
+        } else {
+            let mut v = JsonValue::new_object();
+            v["text"] = line.into();
+            v
+        };
+        debug!("'{}': {}", name, value);
+        data.value.set(value);
+        data.interested.take().notify_data("socket");
+    }
+}
+
+/// Listen for commands on the control socket for as long as the bar is running
+pub fn listen_control(xdg: &xdg::BaseDirectories, state: Weak<RefCell<State>>) {
+    let path = match xdg.place_runtime_file(CONTROL_SOCKET) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("Could not create the control socket: {}", e);
+            return;
+        }
+    };
+    spawn("Control socket", async move {
+        let (listener, _bound) = Bound::bind(path)?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            spawn_noerr(read_commands(stream, state.clone()));
+        }
+    });
+}
+
+/// Run each line as a command, replying with "ok" or the error
+async fn read_commands(stream: UnixStream, state: Weak<RefCell<State>>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = match state.upgrade() {
+            Some(state) => state.borrow_mut().run_command(&line),
+            None => return,
+        };
+        let reply = match result {
+            Ok(()) => "ok\n".to_owned(),
+            Err(e) => {
+                error!("Command '{}' failed: {}", line, e);
+                format!("error: {}\n", e)
+            }
+        };
+        if write.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
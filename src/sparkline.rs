@@ -0,0 +1,211 @@
+//! A chart of the recent history of a value
+use crate::data::{Module, ModuleContext, Value};
+use crate::item::Formatting;
+use crate::render::Render;
+use crate::state::{NotifierList, Runtime};
+use crate::util::{spawn_handle, toml_to_f64, toml_to_string, Cell};
+use futures_util::future::RemoteHandle;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Sparkline {
+    value: Box<Module>,
+    /// The number of samples kept and shown
+    length: usize,
+    /// Seconds between samples, or 0 to sample each time the value changes
+    interval: f64,
+    /// Width of each sample in pixels
+    width: Box<str>,
+    min: Box<str>,
+    max: Box<str>,
+    line: bool,
+    line_width: Box<str>,
+    color: Box<str>,
+    samples: Cell<VecDeque<f64>>,
+    last_sample: Cell<Option<Instant>>,
+    timer: Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Sparkline {
+    pub fn from_toml(config: &toml::Value) -> Result<Self, &'static str> {
+        let value = if config.get("expr").is_some() {
+            Box::new(Module::new_eval(config))
+        } else if let Some(item) = config.get("value") {
+            Box::new(Module::from_toml_in(item, ModuleContext::Source))
+        } else {
+            return Err("'value' or 'expr' is required");
+        };
+        let line = match config.get("style").and_then(|v| v.as_str()) {
+            None | Some("bars") => false,
+            Some("line") => true,
+            Some(_) => return Err("'style' must be either bars or line"),
+        };
+        let get = |key| toml_to_string(config.get(key)).unwrap_or_default().into();
+        Ok(Sparkline {
+            value,
+            length: config
+                .get("length")
+                .and_then(|v| v.as_integer())
+                .map_or(20, |v| v.clamp(2, 1000) as usize),
+            interval: toml_to_f64(config.get("interval"))
+                .filter(|v| !v.is_nan())
+                .unwrap_or(1.0)
+                .clamp(0.0, f64::from(u32::MAX)),
+            width: get("width"),
+            min: get("min"),
+            max: get("max"),
+            line,
+            line_width: get("line-width"),
+            color: get("color"),
+            samples: Cell::default(),
+            last_sample: Cell::new(None),
+            timer: Cell::default(),
+        })
+    }
+
+    /// Keep the history across a reload
+    pub fn carry_over(&self, old: &Self) {
+        let mut samples = old.samples.take();
+        while samples.len() > self.length {
+            samples.pop_front();
+        }
+        self.samples.set(samples);
+        self.last_sample.set(old.last_sample.get());
+    }
+
+    /// Read the value, and add it to the history if it is time for a new sample
+    fn sample(&self, name: &str, rt: &Runtime) {
+        let value = self
+            .value
+            .read_in(name, "", rt, |v| v.parse_f64())
+            .filter(|v| v.is_finite());
+        let now = Instant::now();
+        let due = match self.last_sample.get() {
+            _ if self.interval == 0.0 => {
+                let last = self.samples.take_in(|s| s.back().copied());
+                value.is_some() && value != last
+            }
+            // as with polled values, allow a bit of slack to avoid a double wakeup
+            Some(last) => now >= last + Duration::from_secs_f64(self.interval * 0.9),
+            None => true,
+        };
+        if due {
+            self.last_sample.set(Some(now));
+            self.samples.take_in(|samples| {
+                samples.push_back(value.unwrap_or(0.0));
+                while samples.len() > self.length {
+                    samples.pop_front();
+                }
+            });
+        }
+        if self.interval > 0.0 && (due || self.timer.take_in(|t| t.is_none())) {
+            let wake = Duration::from_secs_f64(self.interval);
+            let mut notify = NotifierList::active(rt);
+            self.timer
+                .set(Some(spawn_handle("Sparkline tick", async move {
+                    tokio::time::sleep(wake).await;
+                    notify.notify_data("sparkline");
+                    Ok(())
+                })));
+        }
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        _rt: &Runtime,
+        f: F,
+    ) -> R {
+        self.samples.take_in(|samples| {
+            let value = match key {
+                "" | "last" => samples.back().copied(),
+                "min" => samples.iter().copied().reduce(f64::min),
+                "max" => samples.iter().copied().reduce(f64::max),
+                "avg" if !samples.is_empty() => {
+                    Some(samples.iter().sum::<f64>() / samples.len() as f64)
+                }
+                _ => None,
+            };
+            f(value.map_or(Value::Null, Value::Float))
+        })
+    }
+
+    pub fn render(&self, ctx: &mut Render) {
+        self.sample(ctx.err_name, &ctx.runtime);
+        let get_f32 = |v: &str| ctx.runtime.format(v).ok().and_then(|v| v.parse_f32());
+
+        let origin = ctx.render_pos;
+        let height = ctx.render_extents.1.y - origin.y;
+        let step = get_f32(&self.width).unwrap_or(3.0).max(1.0);
+        let line_width = get_f32(&self.line_width).unwrap_or(1.0).max(0.5);
+        let rgba = if self.color.is_empty() {
+            Some(ctx.font_color)
+        } else {
+            let color = ctx.runtime.format_or(&self.color, ctx.err_name).into_text();
+            Formatting::parse_rgba(Some(color), None)
+        };
+
+        let samples = self.samples.take();
+        let min = get_f32(&self.min).map_or(0.0, |v| v as f64);
+        let max = get_f32(&self.max)
+            .map_or_else(|| samples.iter().copied().fold(min, f64::max), |v| v as f64);
+        let range = max - min;
+        // The newest sample is at the right edge, so the chart scrolls left as samples are added
+        let x0 = origin.x + (self.length - samples.len()) as f32 * step;
+        let frac = |v: f64| {
+            if range > 0.0 {
+                ((v - min) / range).clamp(0.0, 1.0) as f32
+            } else {
+                0.0
+            }
+        };
+
+        if let Some(rgba) = rgba.filter(|_| height > 0.0) {
+            let paint = tiny_skia::Paint {
+                shader: tiny_skia::Shader::SolidColor(rgba),
+                anti_alias: self.line,
+                ..tiny_skia::Paint::default()
+            };
+            let bottom = origin.y + height;
+            if self.line {
+                // keep the whole stroke inside the box
+                let usable = (height - line_width).max(0.0);
+                let mut pb = tiny_skia::PathBuilder::new();
+                for (i, &v) in samples.iter().enumerate() {
+                    let x = x0 + (i as f32 + 0.5) * step;
+                    let y = bottom - line_width / 2.0 - frac(v) * usable;
+                    if i == 0 {
+                        pb.move_to(x, y);
+                    } else {
+                        pb.line_to(x, y);
+                    }
+                }
+                if let Some(path) = pb.finish() {
+                    let stroke = tiny_skia::Stroke {
+                        width: line_width,
+                        ..tiny_skia::Stroke::default()
+                    };
+                    ctx.canvas
+                        .stroke_path(&path, &paint, &stroke, ctx.render_xform, None);
+                }
+            } else {
+                for (i, &v) in samples.iter().enumerate() {
+                    // leave a pixel between bars when they are wide enough
+                    let left = x0 + i as f32 * step;
+                    let right = (left + step - 1.0).max(left + 1.0);
+                    let top = bottom - frac(v) * height;
+                    let rect = tiny_skia::Rect::from_ltrb(left, top, right, bottom);
+                    if let Some(rect) = rect {
+                        ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
+                    }
+                }
+            }
+        }
+        self.samples.set(samples);
+
+        ctx.render_pos.x = origin.x + self.length as f32 * step;
+        ctx.render_pos.y = origin.y + height.max(0.0);
+    }
+}
@@ -1,23 +1,27 @@
-use futures_util::future::poll_fn;
+use futures_util::future::{poll_fn, select};
 use log::{debug, error, info, warn};
+use smithay_client_toolkit::output::OutputInfo;
 use smithay_client_toolkit::shell::WaylandSurface;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::error::Error;
+use std::path::PathBuf;
 use std::rc::{self, Rc};
 use std::task;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use wayland_client::protocol::wl_callback;
 use wayland_client::protocol::wl_output::WlOutput;
 use wayland_client::Connection;
 
 use crate::bar::Bar;
 use crate::data::{IterationItem, Module, Value};
-use crate::font::FontMapped;
+use crate::event::HoverKey;
+use crate::font::{escape_markup, FontMapped};
+use crate::icon;
 use crate::item::*;
 use crate::render::{RenderCache, Renderer};
-use crate::util::{spawn, spawn_noerr, Cell};
-use crate::wayland::{SurfaceData, WaylandClient};
+use crate::util::{spawn, spawn_noerr, toml_to_f64, toml_to_string, Cell, UID};
+use crate::wayland::{SurfaceData, Wayland, WaylandClient};
 
 #[derive(Debug, Clone)]
 struct Notifier {
@@ -36,11 +40,50 @@ struct NotifierInner {
     waker: Cell<Option<task::Waker>>,
     state: Cell<NotifyState>,
     data_update_seq: Cell<u64>,
+    /// Incremented by [Runtime::refresh_all] to make polled values read again
+    refresh_seq: Cell<u64>,
+    /// Bars that need to read new data on the next draw
+    dirty: Cell<Vec<UID>>,
+    /// True if all bars need to read new data
+    dirty_all: Cell<bool>,
 }
 
 impl Notifier {
+    /// True if the draw task was woken and has not yet looked at the state
+    fn woken(&self) -> bool {
+        self.inner.waker.take_in(|waker| waker.is_none())
+    }
+
+    /// Notify all bars of new data
     pub fn notify_data(&self, who: &str) {
+        if self.inner.dirty_all.get()
+            && self.inner.state.get() == NotifyState::NewData
+            && self.woken()
+        {
+            // a full redraw is already pending, and the draw task has already been woken
+            return;
+        }
         debug!("{} triggered refresh", who);
+        self.inner.dirty_all.set(true);
+        self.inner.state.set(NotifyState::NewData);
+        self.inner.waker.take().map(|w| w.wake());
+    }
+
+    /// Notify only the given bars of new data
+    pub fn notify_bars(&self, who: &str, bars: &[UID]) {
+        let added = self.inner.dirty.take_in(|dirty| {
+            let len = dirty.len();
+            for id in bars {
+                if !dirty.contains(id) {
+                    dirty.push(*id);
+                }
+            }
+            dirty.len() != len
+        });
+        if !added && self.inner.state.get() == NotifyState::NewData && self.woken() {
+            return;
+        }
+        debug!("{} triggered refresh of {} bar(s)", who, bars.len());
         self.inner.state.set(NotifyState::NewData);
         self.inner.waker.take().map(|w| w.wake());
     }
@@ -53,18 +96,29 @@ impl Notifier {
     }
 }
 
+/// A list of bars that are interested in a value
 #[derive(Debug, Default, Clone)]
-pub struct NotifierList(Option<Notifier>);
+pub struct NotifierList {
+    notify: Option<Notifier>,
+    bars: Vec<UID>,
+    /// Set if the value was read outside of a bar's render, so everything needs to be redrawn
+    all: bool,
+}
 
 impl NotifierList {
+    /// A list that will notify all bars
     pub fn active(rt: &Runtime) -> Self {
-        NotifierList(Some(Notifier {
-            inner: rt.notify.inner.clone(),
-        }))
+        NotifierList {
+            notify: Some(Notifier {
+                inner: rt.notify.inner.clone(),
+            }),
+            bars: Vec::new(),
+            all: true,
+        }
     }
 
     pub fn data_update_seq(&self) -> u64 {
-        self.0
+        self.notify
             .as_ref()
             .map(|n| n.inner.data_update_seq.get())
             .unwrap_or_default()
@@ -74,21 +128,40 @@ impl NotifierList {
     ///
     /// The next call to notify_data will redraw the bar that was rendering when this was called.
     pub fn add(&mut self, rt: &Runtime) {
-        self.0 = Some(Notifier {
-            inner: rt.notify.inner.clone(),
-        });
+        if self.notify.is_none() {
+            self.notify = Some(Notifier {
+                inner: rt.notify.inner.clone(),
+            });
+        }
+        match rt.render_bar.get() {
+            _ if self.all => {}
+            Some(id) if !self.bars.contains(&id) => self.bars.push(id),
+            Some(_) => {}
+            None => self.all = true,
+        }
     }
 
     pub fn merge(&mut self, other: &Self) {
-        if self.0.is_none() {
-            self.clone_from(other);
+        if self.notify.is_none() {
+            self.notify.clone_from(&other.notify);
+        }
+        self.all |= other.all;
+        for id in &other.bars {
+            if !self.bars.contains(id) {
+                self.bars.push(*id);
+            }
         }
     }
 
-    /// Notify the bars in the list, and then remove them until they  Future calls to notify_data
-    /// will do nothing until you add() bars again.
+    /// Notify the bars in the list, and then remove them from the list.  Future calls to
+    /// notify_data will do nothing until you add() bars again.
     pub fn notify_data(&mut self, who: &str) {
-        self.0.take().map(|n| n.notify_data(who));
+        let list = std::mem::take(self);
+        match list.notify {
+            Some(n) if list.all => n.notify_data(who),
+            Some(n) => n.notify_bars(who, &list.bars),
+            None => {}
+        }
     }
 }
 
@@ -99,13 +172,77 @@ pub struct Runtime {
     pub fonts: Vec<FontMapped>,
     pub items: HashMap<String, Rc<Item>>,
     pub cache: RenderCache,
-    pub wayland: WaylandClient,
+    pub wayland: Wayland,
     item_var: Rc<Item>,
     notify: Notifier,
     read_depth: Cell<u8>,
+    /// When the pointer last left a bar or popup, or None if it is currently over one
+    pointer_left: Cell<Option<Instant>>,
+    /// The bar currently being rendered (or handling input), for [NotifierList::add]
+    render_bar: Cell<Option<UID>>,
+    /// The bars that exist, so items can drop what they keep for bars that were closed
+    bars: Cell<Vec<UID>>,
+    /// The item under the pointer on the bar currently being rendered, for `on-hover`
+    hovered: Cell<Option<HoverKey>>,
+    /// Cached item renders: the ID used while rendering the item, the bar showing it, and a flag
+    /// to clear when any data the item read changes
+    render_caches: Cell<Vec<(UID, UID, rc::Weak<Cell<bool>>)>>,
+    /// The current and previous distinct values of each `{name.key~prev}` expansion, updated by
+    /// [Self::update_prev_values]
+    prev_values: Cell<HashMap<Box<str>, (Value<'static>, Value<'static>)>>,
+    /// Set while reading text that will be shown on a bar, see [Self::read_shown]
+    shown: Cell<bool>,
+    /// Set while reading text that will be shown as markup
+    markup: Cell<bool>,
+    /// Number of items rendered in the current bar, for diagnosing slow renders
+    pub render_count: Cell<u32>,
+}
+
+/// Split the `~prev` suffix off of a variable, see [Runtime::previous_value]
+fn split_prev(var: &str) -> (&str, bool) {
+    match var.strip_suffix("~prev") {
+        Some(var) => (var, true),
+        None => (var, false),
+    }
 }
 
 impl Runtime {
+    fn new(wayland: Wayland, notify: Rc<NotifierInner>) -> Result<Self, Box<dyn Error>> {
+        Ok(Runtime {
+            xdg: xdg::BaseDirectories::new()?,
+            fonts: Vec::new(),
+            cache: RenderCache::new(),
+            items: Default::default(),
+            item_var: Rc::new(Module::new_current_item().into()),
+            notify: Notifier { inner: notify },
+            read_depth: Cell::new(0),
+            pointer_left: Cell::new(Some(Instant::now())),
+            render_bar: Cell::new(None),
+            bars: Default::default(),
+            hovered: Cell::new(None),
+            render_caches: Default::default(),
+            prev_values: Default::default(),
+            shown: Cell::new(false),
+            markup: Cell::new(false),
+            render_count: Cell::new(0),
+            wayland,
+        })
+    }
+
+    /// A runtime without a Wayland connection or configuration, for unit tests
+    #[cfg(test)]
+    pub fn new_test() -> Self {
+        let notify = Rc::new(NotifierInner {
+            waker: Cell::new(None),
+            state: Cell::new(NotifyState::Idle),
+            data_update_seq: Cell::new(1),
+            refresh_seq: Cell::new(0),
+            dirty: Default::default(),
+            dirty_all: Cell::new(false),
+        });
+        Self::new(Wayland::none(), notify).unwrap()
+    }
+
     pub fn get_recursion_handle(&self) -> Option<impl Sized + '_> {
         let depth = self.read_depth.get();
         if depth > 80 {
@@ -130,37 +267,75 @@ impl Runtime {
             && fmt.ends_with("}")
             && !fmt[1..fmt.len() - 1].contains(&['{', ':'] as &[char])
         {
-            let q = &fmt[1..fmt.len() - 1];
+            let (q, prev) = split_prev(&fmt[1..fmt.len() - 1]);
             let (name, key) = match q.find('.') {
                 Some(p) => (&q[..p], &q[p + 1..]),
                 None => (&q[..], ""),
             };
             if let Some(item) = self.items.get(name) {
-                return Ok(item.data.read_to_owned(name, key, self));
+                let value = if prev {
+                    self.previous_value(name, key, item)
+                } else {
+                    item.data.read_to_owned(name, key, self)
+                };
+                return Ok(match (value, item.format.round) {
+                    (Value::Float(f), Some(round)) if self.shown.get() => {
+                        Value::Float(round.apply(f, 0))
+                    }
+                    (value @ (Value::Borrow(_) | Value::Owned(_)), _)
+                        if item.format.escape_markup && self.markup.get() =>
+                    {
+                        Value::Owned(escape_markup(value.as_str_fast()).into_owned())
+                    }
+                    (value, _) => value,
+                });
             } else {
                 return Err(strfmt::FmtError::KeyError(name.to_string()));
             }
         }
+        let (shown, markup) = (self.shown.get(), self.markup.get());
         strfmt::strfmt_map(fmt, |mut q| {
-            let (name, key) = match q.key.find('.') {
-                Some(p) => (&q.key[..p], &q.key[p + 1..]),
-                None => (&q.key[..], ""),
+            let (var, prev) = split_prev(q.key);
+            let (name, key) = match var.find('.') {
+                Some(p) => (&var[..p], &var[p + 1..]),
+                None => (var, ""),
+            };
+            let mut write = |item: &Item, s: Value| match s {
+                Value::Borrow(s) if markup && item.format.escape_markup => q.str(&escape_markup(s)),
+                Value::Owned(s) if markup && item.format.escape_markup => q.str(&escape_markup(&s)),
+                Value::Borrow(s) => q.str(s),
+                Value::Owned(s) => q.str(&s),
+                Value::Float(f) => match item.format.round {
+                    Some(round) if shown => {
+                        let precision = q.precision().unwrap_or(0);
+                        q.f64(round.apply(f, precision))
+                    }
+                    _ => q.f64(f),
+                },
+                Value::Bool(true) => q.str("1"),
+                Value::Bool(false) => q.str("0"),
+                Value::Null => q.str(""),
             };
             match self.items.get(name) {
-                Some(item) => item.data.read_in(name, key, self, |s| match s {
-                    Value::Borrow(s) => q.str(s),
-                    Value::Owned(s) => q.str(&s),
-                    Value::Float(f) => q.f64(f),
-                    Value::Bool(true) => q.str("1"),
-                    Value::Bool(false) => q.str("0"),
-                    Value::Null => q.str(""),
-                }),
+                Some(item) if prev => write(item, self.previous_value(name, key, item)),
+                Some(item) => item.data.read_in(name, key, self, |s| write(item, s)),
                 None => Err(strfmt::FmtError::KeyError(name.to_string())),
             }
         })
         .map(Value::Owned)
     }
 
+    /// Read text that is shown on a bar, as markup if `markup` is set.  Numbers from items with
+    /// `round` are rounded, and values from items with `escape-markup` are escaped.  Other reads,
+    /// such as for meters, conditions, or actions, see the values as they are.
+    pub fn read_shown<R>(&self, markup: bool, f: impl FnOnce() -> R) -> R {
+        let prev = (self.shown.replace(true), self.markup.replace(markup));
+        let rv = f();
+        self.shown.set(prev.0);
+        self.markup.set(prev.1);
+        rv
+    }
+
     pub fn format_or<'a>(&'a self, fmt: &'a str, context: &str) -> Value<'a> {
         match self.format(fmt) {
             Ok(v) => v,
@@ -171,6 +346,110 @@ impl Runtime {
         }
     }
 
+    /// Read all values again on the next draw, including polled values whose period has not
+    /// yet expired
+    pub fn refresh_all(&self) {
+        let inner = &self.notify.inner;
+        inner.refresh_seq.set(inner.refresh_seq.get() + 1);
+        self.notify.notify_data("refresh");
+    }
+
+    pub fn refresh_seq(&self) -> u64 {
+        self.notify.inner.refresh_seq.get()
+    }
+
+    /// Set the bar that will be registered as interested in any values read
+    pub fn set_render_bar(&self, bar: Option<UID>) {
+        self.render_bar.set(bar);
+    }
+
+    /// The bar currently being rendered, if any
+    pub fn render_bar(&self) -> Option<UID> {
+        self.render_bar.get()
+    }
+
+    pub fn bar_exists(&self, id: UID) -> bool {
+        self.bars.take_in(|bars| bars.contains(&id))
+    }
+
+    /// Set the item that should be rendered using its `on-hover` style
+    pub fn set_hovered(&self, key: Option<HoverKey>) {
+        self.hovered.set(key);
+    }
+
+    pub fn is_hovered(&self, key: &HoverKey) -> bool {
+        self.hovered.take_in(|h| h.as_ref() == Some(key))
+    }
+
+    pub fn hovered(&self) -> Option<HoverKey> {
+        self.hovered.take_in(|h| h.clone())
+    }
+
+    /// Track a cached render of an item on the given bar.
+    ///
+    /// Values read while the item was rendered with `id` as the render bar will mark `id` dirty
+    /// when they change; this clears `valid` and redraws `bar`.
+    pub fn add_render_cache(&self, id: UID, bar: UID, valid: &Rc<Cell<bool>>) {
+        self.render_caches
+            .take_in(|caches| caches.push((id, bar, Rc::downgrade(valid))));
+    }
+
+    /// Get the value that `key` of `item` had before it last changed.
+    ///
+    /// The first read starts tracking the value and returns Null; after that, the value is
+    /// checked for changes on each data update by [Self::update_prev_values].
+    fn previous_value(&self, name: &str, key: &str, item: &Item) -> Value<'static> {
+        let var = format!("{}.{}", name, key);
+        let prev = self.prev_values.take_in(|values| {
+            values
+                .get(&*var)
+                .map(|(_, prev)| prev.as_ref().into_owned())
+        });
+        prev.unwrap_or_else(|| {
+            let current = item.data.read_to_owned(name, key, self);
+            self.prev_values
+                .take_in(|values| values.insert(var.into(), (current, Value::Null)));
+            Value::Null
+        })
+    }
+
+    /// Read each value used with `~prev` again, keeping the old one if it changed.  This is done
+    /// when any data source has an update, so changes are seen even while they are not shown.
+    /// Repeated readings of the same value do not clear the previous one.
+    fn update_prev_values(&self) {
+        let vars: Vec<Box<str>> = self.prev_values.take_in(|v| v.keys().cloned().collect());
+        for var in vars {
+            let (name, key) = var.split_once('.').unwrap();
+            let current = match self.items.get(name) {
+                Some(item) => item.data.read_to_owned(name, key, self),
+                None => continue,
+            };
+            self.prev_values.take_in(|values| {
+                let (last, prev) = values.get_mut(&var).unwrap();
+                if last.to_string() != current.to_string() {
+                    *prev = std::mem::replace(last, current);
+                }
+            });
+        }
+    }
+
+    /// Record the pointer entering or leaving one of our surfaces
+    pub fn set_pointer_over(&self, over: bool) {
+        if over {
+            if self.pointer_left.take().is_some() {
+                // anything that slowed down while idle should refresh now
+                self.notify.notify_data("pointer-enter");
+            }
+        } else {
+            self.pointer_left.set(Some(Instant::now()));
+        }
+    }
+
+    /// How long it has been since the pointer was over any bar or popup
+    pub fn pointer_idle_time(&self) -> Option<std::time::Duration> {
+        self.pointer_left.get().map(|t| t.elapsed())
+    }
+
     pub fn copy_item_var(&self) -> Option<IterationItem> {
         self.get_item_var().take_in_some(|v| v.clone())
     }
@@ -188,41 +467,183 @@ impl Runtime {
     }
 }
 
+/// Where the configuration is loaded from
+#[derive(Debug, Clone)]
+pub enum ConfigSource {
+    /// rwaybar.toml in the XDG config directories
+    Default,
+    File(PathBuf),
+    /// The contents of stdin, read once at startup
+    Stdin(String),
+}
+
 /// The singleton global state object
 #[derive(Debug)]
 pub struct State {
     pub bars: Vec<Bar>,
     bar_config: Vec<toml::Value>,
+    /// The `[[profile]]` sections, in order of preference
+    profiles: Vec<toml::Value>,
+    /// The shortest time between draws, from `max-fps`
+    frame_interval: Option<Duration>,
+    config_source: ConfigSource,
+    /// The configuration given on the command line, which `load-config` returns to
+    default_config: ConfigSource,
     pub renderer: Renderer,
     pub runtime: Runtime,
     this: rc::Weak<RefCell<State>>,
 }
 
+/// Top-level keys that configure rwaybar itself instead of defining an item, unless they have a
+/// `type` or `format` like an item would
+const RESERVED_KEYS: &[&str] = &[
+    "bar",
+    "consts",
+    "fonts",
+    "icon-cache",
+    "max-fps",
+    "on-startup",
+    "profile",
+    "theme",
+];
+
+/// The setting a top-level key configures, or None if it defines an item.  A table with a `type`
+/// or `format` is an item even if its name is reserved, so that an item named (for example)
+/// "theme" in an older configuration keeps working.
+fn setting_name<'a>(key: &'a str, value: &toml::Value) -> Option<&'a str> {
+    let is_item = value.as_table().map_or(false, |t| {
+        t.contains_key("type") || t.contains_key("format")
+    });
+    if RESERVED_KEYS.contains(&key) && !is_item {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/// Warn about items named "output", which are hidden by the output of each bar
+fn check_reserved_keys(config: &toml::value::Table) {
+    if config.contains_key("output") {
+        // replaced by the output of the bar being drawn, see [Bar::set_items]
+        warn!("'output' is a reserved name and cannot be used for an item");
+    }
+}
+
+/// Replace each `{const.NAME}` in the strings of the configuration with the value from the
+/// `consts` table.  A string that is only a reference to a constant takes on its type, so that
+/// numbers can be used where a string is not accepted.
+fn expand_consts(value: &mut toml::Value, consts: &toml::value::Table) {
+    const PREFIX: &str = "{const.";
+    match value {
+        toml::Value::String(s) if s.contains(PREFIX) => {
+            let whole = s
+                .strip_prefix(PREFIX)
+                .and_then(|rest| rest.strip_suffix('}'))
+                .filter(|name| !name.contains('}'))
+                .and_then(|name| consts.get(name));
+            if let Some(v) = whole {
+                *value = v.clone();
+                return;
+            }
+            let mut rv = String::with_capacity(s.len());
+            let mut rest = &s[..];
+            while let Some(start) = rest.find(PREFIX) {
+                let tail = &rest[start + PREFIX.len()..];
+                let end = match tail.find('}') {
+                    Some(end) => end,
+                    None => break,
+                };
+                rv.push_str(&rest[..start]);
+                let name = &tail[..end];
+                match toml_to_string(consts.get(name)) {
+                    Some(v) => rv.push_str(&v),
+                    None => {
+                        error!("Undefined constant '{}'", name);
+                        rv.push_str(&rest[start..start + PREFIX.len() + end + 1]);
+                    }
+                }
+                rest = &tail[end + 1..];
+            }
+            rv.push_str(rest);
+            *s = rv;
+        }
+        toml::Value::Array(list) => {
+            for v in list {
+                expand_consts(v, consts);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, v) in table.iter_mut() {
+                expand_consts(v, consts);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Run the `on-startup` commands (only those marked with `reload = true` on a reload)
+fn run_startup(config: &toml::Value, reload: bool) {
+    let list = match config {
+        toml::Value::Array(list) => &list[..],
+        v => std::slice::from_ref(v),
+    };
+    for entry in list {
+        let (cmd, on_reload) = match entry {
+            toml::Value::String(cmd) => (cmd.as_str(), false),
+            v => match v.get("exec").and_then(|v| v.as_str()) {
+                Some(cmd) => (
+                    cmd,
+                    v.get("reload").and_then(|v| v.as_bool()).unwrap_or(false),
+                ),
+                None => {
+                    error!("Invalid on-startup entry: {}", v);
+                    continue;
+                }
+            },
+        };
+        if reload && !on_reload {
+            continue;
+        }
+        info!("Executing '{}'", cmd);
+        // spawn does not wait for the command to complete, and SIGCHLD is ignored
+        match std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(cmd)
+            .spawn()
+        {
+            Ok(child) => drop(child),
+            Err(e) => error!("Could not execute {}: {}", cmd, e),
+        }
+    }
+}
+
+/// Renders that take longer than this (one frame at 60Hz) are logged
+const SLOW_RENDER: std::time::Duration = std::time::Duration::from_millis(16);
+
 impl State {
-    pub fn new(wayland: WaylandClient) -> Result<Rc<RefCell<Self>>, Box<dyn Error>> {
+    pub fn new(
+        wayland: WaylandClient,
+        config_source: ConfigSource,
+    ) -> Result<Rc<RefCell<Self>>, Box<dyn Error>> {
         let notify_inner = Rc::new(NotifierInner {
             waker: Cell::new(None),
             state: Cell::new(NotifyState::NewData),
             data_update_seq: Cell::new(1),
+            refresh_seq: Cell::new(0),
+            dirty: Default::default(),
+            dirty_all: Cell::new(true),
         });
         log::debug!("State::new");
 
         let mut state = Self {
             bars: Vec::new(),
             bar_config: Vec::new(),
+            profiles: Vec::new(),
+            frame_interval: None,
+            default_config: config_source.clone(),
+            config_source,
             renderer: Renderer::new(),
-            runtime: Runtime {
-                xdg: xdg::BaseDirectories::new()?,
-                fonts: Vec::new(),
-                cache: RenderCache::new(),
-                items: Default::default(),
-                item_var: Rc::new(Module::new_current_item().into()),
-                notify: Notifier {
-                    inner: notify_inner.clone(),
-                },
-                read_depth: Cell::new(0),
-                wayland,
-            },
+            runtime: Runtime::new(Wayland::new(wayland), notify_inner.clone())?,
             this: rc::Weak::new(),
         };
 
@@ -233,6 +654,7 @@ impl State {
 
         let state = rv.clone();
         spawn_noerr(async move {
+            let mut last_draw: Option<Instant> = None;
             loop {
                 poll_fn(|ctx| {
                     notify_inner.waker.set(Some(ctx.waker().clone()));
@@ -242,6 +664,12 @@ impl State {
                     }
                 })
                 .await;
+                // Anything that changes before the next allowed frame is drawn along with it
+                let interval = state.borrow().frame_interval;
+                if let (Some(interval), Some(last)) = (interval, last_draw) {
+                    tokio::time::sleep_until((last + interval).into()).await;
+                }
+                last_draw = Some(Instant::now());
                 let mut state = state.borrow_mut();
                 state.draw_now();
             }
@@ -259,29 +687,76 @@ impl State {
             Ok(())
         });
 
+        let state = rv.clone();
+        spawn("Refresh", async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut usr1 = signal(SignalKind::user_defined1())?;
+            while let Some(()) = usr1.recv().await {
+                state.borrow().runtime.refresh_all();
+            }
+            Ok(())
+        });
+
+        crate::socket::listen_control(&rv.borrow().runtime.xdg, Rc::downgrade(&rv));
+
+        spawn("Shutdown", async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut term = signal(SignalKind::terminate())?;
+            let mut int = signal(SignalKind::interrupt())?;
+            let _ = select(Box::pin(term.recv()), Box::pin(int.recv())).await;
+            crate::socket::cleanup();
+            std::process::exit(0);
+        });
+
         Ok(rv)
     }
 
     /// Note: always call from a task, not drectly from dispatch
     fn load_config(&mut self, reload: bool) -> Result<(), Box<dyn Error>> {
         let mut bar_config = Vec::new();
+        let mut profiles = Vec::new();
         let mut font_list = Vec::new();
+        let mut theme = None;
+        let mut startup = None;
+        let mut icon_cache = false;
+        let mut frame_interval = None;
 
-        let config_path = self
-            .runtime
-            .xdg
-            .find_config_file("rwaybar.toml")
-            .ok_or("Could not find configuration: create ~/.config/rwaybar.toml")?;
+        let mut config: toml::Value = match &self.config_source {
+            ConfigSource::Stdin(_) if reload => {
+                Err("The configuration was read from stdin and cannot be reloaded")?
+            }
+            ConfigSource::Stdin(cfg) => toml::from_str(cfg)?,
+            ConfigSource::File(path) => {
+                let cfg = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+                toml::from_str(&cfg)?
+            }
+            ConfigSource::Default => {
+                let config_path = self
+                    .runtime
+                    .xdg
+                    .find_config_file("rwaybar.toml")
+                    .ok_or("Could not find configuration: create ~/.config/rwaybar.toml")?;
 
-        let cfg = std::fs::read_to_string(config_path)?;
-        let config: toml::Value = toml::from_str(&cfg)?;
+                let cfg = std::fs::read_to_string(config_path)?;
+                toml::from_str(&cfg)?
+            }
+        };
 
+        check_reserved_keys(config.as_table().unwrap());
+        let cfg = config.as_table_mut().unwrap();
+        let consts = cfg.get("consts").and_then(|v| setting_name("consts", v));
+        if consts.is_some() {
+            if let Some(toml::Value::Table(consts)) = cfg.remove("consts") {
+                expand_consts(&mut config, &consts);
+            }
+        }
         let cfg = config.as_table().unwrap();
 
         let new_items = cfg
             .iter()
-            .filter_map(|(key, value)| match key.as_str() {
-                "bar" => {
+            .filter_map(|(key, value)| match setting_name(key, value) {
+                Some("bar") => {
                     if let Some(bars) = value.as_array() {
                         bar_config.extend(bars.iter().cloned());
                     } else {
@@ -289,12 +764,42 @@ impl State {
                     }
                     None
                 }
-                "fonts" => {
+                Some("profile") => {
+                    if let Some(list) = value.as_array() {
+                        profiles.extend(list.iter().cloned());
+                    } else {
+                        profiles.push(value.clone());
+                    }
+                    None
+                }
+                Some("fonts") => {
                     if let Some(list) = value.as_table() {
                         font_list = list.iter().collect();
                     }
                     None
                 }
+                Some("theme") => {
+                    theme = value.as_table();
+                    None
+                }
+                Some("on-startup") => {
+                    startup = Some(value);
+                    None
+                }
+                Some("icon-cache") => {
+                    icon_cache = value.as_bool().unwrap_or(false);
+                    None
+                }
+                Some("max-fps") => {
+                    match toml_to_f64(Some(value)) {
+                        Some(fps) if fps > 0.0 => {
+                            let fps = fps.clamp(0.01, 1000.0);
+                            frame_interval = Some(Duration::from_secs_f64(1.0 / fps));
+                        }
+                        _ => error!("Ignoring invalid max-fps {}", value),
+                    }
+                    None
+                }
                 _ => {
                     let key = key.to_owned();
                     let value = Rc::new(Item::from_item_list(&key, value));
@@ -303,6 +808,29 @@ impl State {
             })
             .collect();
 
+        // The bars of a profile are only shown while that profile is active
+        for profile in &profiles {
+            let name = match profile.get("name").and_then(|v| v.as_str()) {
+                Some(name) => name,
+                None => {
+                    error!("Ignoring a [[profile]] without a name");
+                    continue;
+                }
+            };
+            let bars = match profile.get("bar") {
+                Some(toml::Value::Array(bars)) => &bars[..],
+                Some(bar) => std::slice::from_ref(bar),
+                None => &[],
+            };
+            for bar in bars {
+                let mut bar = bar.clone();
+                if let Some(table) = bar.as_table_mut() {
+                    table.insert("profile".into(), name.into());
+                }
+                bar_config.push(bar);
+            }
+        }
+
         if bar_config.is_empty() {
             Err("At least one [[bar]] section is required")?;
         }
@@ -325,7 +853,18 @@ impl State {
 
         let mut old_items = std::mem::replace(&mut self.runtime.items, new_items);
         self.bar_config = bar_config;
+        self.profiles = profiles;
+        self.frame_interval = frame_interval;
         self.runtime.fonts = fonts;
+        Formatting::set_theme(theme);
+        let icon_cache = icon_cache.then(|| {
+            self.runtime
+                .xdg
+                .create_cache_directory("rwaybar/icons")
+                .map_err(|e| error!("Could not create the icon cache: {}", e))
+                .ok()
+        });
+        icon::set_disk_cache(icon_cache.flatten());
 
         self.runtime
             .items
@@ -339,6 +878,11 @@ impl State {
             }
         }
         self.runtime.notify.inner.state.set(NotifyState::NewData);
+        self.runtime.notify.inner.dirty_all.set(true);
+
+        if let Some(startup) = startup {
+            run_startup(startup, reload);
+        }
 
         self.bars.clear();
         for output in self.runtime.wayland.output.outputs() {
@@ -367,12 +911,51 @@ impl State {
         Ok(())
     }
 
+    /// Run a command from the control socket
+    pub fn run_command(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        let line = line.trim();
+        let (cmd, arg) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(cmd, arg)| (cmd, arg.trim()));
+        match cmd {
+            "load-config" => self.switch_config(arg),
+            _ => Err(format!("Unknown command '{}'", cmd))?,
+        }
+    }
+
+    /// Replace the configuration with `rwaybar/<name>.toml` from the XDG config directories, or
+    /// with the default one if the name is empty.  The current configuration is kept if the new
+    /// one cannot be loaded.
+    fn switch_config(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let source = if name.is_empty() {
+            self.default_config.clone()
+        } else if name.contains('/') || name.starts_with('.') {
+            Err(format!("Invalid configuration name '{}'", name))?
+        } else {
+            let file = format!("rwaybar/{}.toml", name);
+            let path = self
+                .runtime
+                .xdg
+                .find_config_file(&file)
+                .ok_or_else(|| format!("Could not find configuration {}", file))?;
+            ConfigSource::File(path)
+        };
+        let old = std::mem::replace(&mut self.config_source, source);
+        if let Err(e) = self.load_config(true) {
+            self.config_source = old;
+            return Err(e);
+        }
+        let name = if name.is_empty() { "default" } else { name };
+        info!("Switched to configuration '{}'", name);
+        Ok(())
+    }
+
     pub fn request_draw(&mut self) {
         self.runtime.notify.notify_draw_only();
     }
 
     fn set_data(&mut self) {
-        // Propagate new_data notifications to all bar dirty fields
+        // Propagate new_data notifications to the dirty fields of the bars that asked for them
         match self.runtime.notify.inner.state.replace(NotifyState::Idle) {
             NotifyState::Idle => return,
             NotifyState::DrawOnly => return,
@@ -381,9 +964,28 @@ impl State {
 
         let seq = self.runtime.notify.inner.data_update_seq.get();
         self.runtime.notify.inner.data_update_seq.set(seq + 1);
+        self.runtime.update_prev_values();
 
-        // A smarter notify could only damage some of the surfaces
+        let all = self.runtime.notify.inner.dirty_all.replace(false);
+        let mut dirty = self.runtime.notify.inner.dirty.take();
+
+        let mut cache_bars = Vec::new();
+        self.runtime.render_caches.take_in(|caches| {
+            caches.retain(|(id, bar, valid)| match valid.upgrade() {
+                Some(valid) if all || dirty.contains(id) => {
+                    valid.set(false);
+                    cache_bars.push(*bar);
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            })
+        });
+        dirty.extend(cache_bars);
         for bar in &self.bars {
+            if !all && !dirty.contains(&bar.id) {
+                continue;
+            }
             SurfaceData::from_wl(bar.ls.wl_surface()).damage_full();
             if let Some(popup) = &bar.popup {
                 SurfaceData::from_wl(&popup.wl.surf).damage_full();
@@ -395,9 +997,25 @@ impl State {
         self.set_data();
 
         let begin = Instant::now();
+        self.runtime
+            .bars
+            .set(self.bars.iter().map(|bar| bar.id).collect());
         for bar in &mut self.bars {
+            self.runtime.set_render_bar(Some(bar.id));
+            let start = Instant::now();
+            self.runtime.render_count.set(0);
             bar.render_with(&mut self.runtime, &mut self.renderer);
+            let elapsed = start.elapsed();
+            if elapsed > SLOW_RENDER {
+                warn!(
+                    "Rendering bar on '{}' took {} ms ({} items)",
+                    bar.name,
+                    elapsed.as_millis(),
+                    self.runtime.render_count.get()
+                );
+            }
         }
+        self.runtime.set_render_bar(None);
         self.runtime.cache.prune(begin);
         self.runtime.wayland.flush();
         let render_time = begin.elapsed().as_nanos();
@@ -408,7 +1026,80 @@ impl State {
         );
     }
 
+    /// Recreate the bars on an output whose size or scale changed, such as after a mode switch
+    pub fn output_changed(&mut self, output: &WlOutput) {
+        let data = match self.runtime.wayland.output.info(output) {
+            Some(info) => info,
+            None => return,
+        };
+        let mode = (data.logical_size, data.scale_factor);
+        let name = data.name.unwrap_or_default();
+        if self
+            .bars
+            .iter()
+            .any(|bar| *bar.name == *name && bar.output_mode != mode)
+        {
+            info!(
+                "Output '{}' changed size or scale; recreating its bars",
+                name
+            );
+            self.output_ready(output);
+        }
+    }
+
+    /// Add or remove the bars that depend on the number of outputs (`if-outputs`) after an
+    /// output was added or removed
+    pub fn output_count_changed(&mut self, removed: Option<&WlOutput>) {
+        let outputs: Vec<_> = self
+            .runtime
+            .wayland
+            .output
+            .outputs()
+            .filter(|o| Some(o) != removed)
+            .collect();
+        let layout = self.output_layout(&outputs);
+        let bar_config = &self.bar_config;
+        // Bars that moved to or from the primary output are recreated below
+        self.bars.retain(|bar| {
+            bar_config.get(bar.cfg_index).map_or(true, |cfg| {
+                layout.enables(cfg) && layout.is_primary(cfg, &bar.name) == bar.primary
+            })
+        });
+        for output in &outputs {
+            self.add_bars(output, &layout, true);
+        }
+        self.runtime.wayland.flush();
+    }
+
     pub fn output_ready(&mut self, output: &WlOutput) {
+        let outputs: Vec<_> = self.runtime.wayland.output.outputs().collect();
+        let layout = self.output_layout(&outputs);
+        self.add_bars(output, &layout, false);
+    }
+
+    fn output_layout(&self, outputs: &[WlOutput]) -> OutputLayout {
+        let infos: Vec<_> = outputs
+            .iter()
+            .filter_map(|o| self.runtime.wayland.output.info(o))
+            .collect();
+        let profile = self
+            .profiles
+            .iter()
+            .find(|profile| profile_matches(profile, &infos))
+            .and_then(|profile| profile.get("name")?.as_str())
+            .map(Into::into);
+        debug!("Active output profile: {:?}", profile);
+        let first = infos.first().and_then(|info| info.name.as_deref());
+        OutputLayout {
+            count: outputs.len(),
+            profile,
+            first: first.map(Into::into),
+        }
+    }
+
+    /// Create the bars for an output.  If `conditional_only` is set, only bars that are not
+    /// already present are created.
+    fn add_bars(&mut self, output: &WlOutput, layout: &OutputLayout, conditional_only: bool) {
         let data = match self.runtime.wayland.output.info(&output) {
             Some(info) => info,
             None => return,
@@ -426,6 +1117,12 @@ impl State {
                     continue;
                 }
             }
+            if let Some(outputs) = cfg.get("outputs").and_then(|v| v.as_array()) {
+                let name = data.name.as_deref();
+                if !outputs.iter().any(|o| o.as_str().is_some() && o.as_str() == name) {
+                    continue;
+                }
+            }
             if let Some(make) = cfg.get("make").and_then(|v| v.as_str()) {
                 match regex::Regex::new(make) {
                     Ok(re) => {
@@ -462,21 +1159,156 @@ impl State {
                     }
                 }
             }
-            let mut cfg = cfg.clone();
             let name = data.name.clone().unwrap_or_default();
+            if conditional_only
+                && self
+                    .bars
+                    .iter()
+                    .any(|bar| bar.cfg_index == i && *bar.name == *name)
+            {
+                continue;
+            }
+            if !layout.enables(cfg) {
+                continue;
+            }
+            let is_primary = layout.is_primary(cfg, &name);
+            if !is_primary && cfg.get("primary-only").and_then(|v| v.as_bool()) == Some(true) {
+                continue;
+            }
+            let mut cfg = cfg.clone();
             self.bars
                 .retain(|bar| bar.cfg_index != i || &*bar.name != name);
             if let Some(table) = cfg.as_table_mut() {
                 table.insert("name".into(), name.into());
+                if is_primary {
+                    table.insert("primary".into(), "1".into());
+                }
             }
 
-            let bar = Bar::new(&mut self.runtime.wayland, &output, &data, cfg, i);
+            let mut bar = Bar::new(&mut self.runtime.wayland, &output, &data, cfg, i);
+            bar.primary = is_primary;
             self.bars.push(bar);
             self.runtime.wayland.flush();
         }
     }
 }
 
+/// The connected outputs, as used by bars that are only shown for some of them
+struct OutputLayout {
+    count: usize,
+    /// The name of the first profile whose outputs are connected
+    profile: Option<Box<str>>,
+    /// The name of the first connected output
+    first: Option<Box<str>>,
+}
+
+impl OutputLayout {
+    /// Check the `if-outputs` and `profile` conditions of a bar
+    fn enables(&self, cfg: &toml::Value) -> bool {
+        if let Some(cond) = cfg.get("if-outputs") {
+            if !output_count_matches(cond, self.count) {
+                return false;
+            }
+        }
+        match cfg.get("profile").and_then(|v| v.as_str()) {
+            Some(profile) => self.profile.as_deref() == Some(profile),
+            None => true,
+        }
+    }
+
+    /// Check if the named output is the primary one for a bar.  Wayland has no notion of a
+    /// primary output, so this is the first one unless the bar sets `primary-output`.
+    fn is_primary(&self, cfg: &toml::Value, name: &str) -> bool {
+        match cfg.get("primary-output").and_then(|v| v.as_str()) {
+            Some(primary) => primary == name,
+            None => self.first.as_deref() == Some(name),
+        }
+    }
+}
+
+/// A profile matches if each entry of its `outputs` matches a different connected output, with
+/// no outputs left over
+fn profile_matches(profile: &toml::Value, infos: &[OutputInfo]) -> bool {
+    let specs = match profile.get("outputs").and_then(|v| v.as_array()) {
+        Some(specs) => specs,
+        None => return false,
+    };
+    if specs.len() != infos.len() {
+        return false;
+    }
+    let mut used = vec![false; infos.len()];
+    specs.iter().all(|spec| {
+        let found = (0..infos.len()).find(|&i| !used[i] && output_spec_matches(spec, &infos[i]));
+        match found {
+            Some(i) => {
+                used[i] = true;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Match an output against a table of `name` and `make`, `model`, or `description` regexes
+fn output_spec_matches(spec: &toml::Value, info: &OutputInfo) -> bool {
+    if let Some(name) = spec.get("name").and_then(|v| v.as_str()) {
+        if Some(name) != info.name.as_deref() {
+            return false;
+        }
+    }
+    let description = info.description.as_deref().unwrap_or_default();
+    let fields = [
+        ("make", &*info.make),
+        ("model", &*info.model),
+        ("description", description),
+    ];
+    fields.iter().all(|(key, value)| {
+        let re = spec
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(regex::Regex::new);
+        match re {
+            None => true,
+            Some(Ok(re)) => re.is_match(value),
+            Some(Err(e)) => {
+                error!("Ignoring invalid regex in profile.outputs.{}: {}", key, e);
+                true
+            }
+        }
+    })
+}
+
+/// Check the `if-outputs` condition of a bar: a number of outputs, or a comparison like ">1"
+fn output_count_matches(cond: &toml::Value, count: usize) -> bool {
+    if let Some(n) = cond.as_integer() {
+        return count as i64 == n;
+    }
+    let cond = cond.as_str().unwrap_or_default().trim();
+    let (op, n) = match cond.find(|c: char| c.is_ascii_digit()) {
+        Some(pos) => cond.split_at(pos),
+        None => (cond, ""),
+    };
+    let n = match n.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => {
+            error!("Invalid if-outputs condition '{}', ignoring it", cond);
+            return true;
+        }
+    };
+    match op.trim() {
+        "" | "=" | "==" => count == n,
+        "!=" => count != n,
+        ">" => count > n,
+        ">=" => count >= n,
+        "<" => count < n,
+        "<=" => count <= n,
+        op => {
+            error!("Invalid if-outputs comparison '{}', ignoring it", op);
+            true
+        }
+    }
+}
+
 pub struct OutputsReadyCallback;
 
 impl wayland_client::Dispatch<wl_callback::WlCallback, OutputsReadyCallback> for State {
@@ -505,3 +1337,93 @@ impl wayland_client::Dispatch<wl_callback::WlCallback, OutputsReadyCallback> for
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountWakes(AtomicUsize);
+
+    impl task::Wake for CountWakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// A notifier with the draw task waiting on it, as it is between frames
+    fn waiting_notifier() -> (Notifier, Arc<CountWakes>) {
+        let wakes = Arc::new(CountWakes(AtomicUsize::new(0)));
+        let notify = Notifier {
+            inner: Rc::new(NotifierInner {
+                waker: Cell::new(Some(wakes.clone().into())),
+                state: Cell::new(NotifyState::Idle),
+                data_update_seq: Cell::new(1),
+                refresh_seq: Cell::new(0),
+                dirty: Default::default(),
+                dirty_all: Cell::new(false),
+            }),
+        };
+        (notify, wakes)
+    }
+
+    #[test]
+    fn expand_consts_in_strings() {
+        let consts: toml::value::Table = toml::from_str("x = 'b'\nsize = 3").unwrap();
+        let expand = |s: &str| {
+            let mut value = toml::Value::String(s.into());
+            expand_consts(&mut value, &consts);
+            value
+        };
+        assert_eq!(expand("{const.size}"), toml::Value::Integer(3));
+        assert_eq!(expand("a{const.x}c"), toml::Value::String("abc".into()));
+        assert_eq!(expand("a{const.x"), toml::Value::String("a{const.x".into()));
+        assert_eq!(
+            expand("{const.x}{const.y}"),
+            toml::Value::String("b{const.y}".into())
+        );
+    }
+
+    #[test]
+    fn reserved_names_of_items() {
+        let cfg: toml::value::Table = toml::from_str(
+            "theme = { accent = '#08f' }\nmax-fps = 10\nbar = { type = 'clock' }\nclock = {}",
+        )
+        .unwrap();
+        let name = |key: &'static str| setting_name(key, &cfg[key]);
+        assert_eq!(name("theme"), Some("theme"));
+        assert_eq!(name("max-fps"), Some("max-fps"));
+        // an item keeps working even if its name is now a setting
+        assert_eq!(name("bar"), None);
+        assert_eq!(name("clock"), None);
+    }
+
+    #[test]
+    fn prev_suffix() {
+        assert_eq!(split_prev("load~prev"), ("load", true));
+        assert_eq!(split_prev("battery.state~prev"), ("battery.state", true));
+        // a key that is itself named "prev" is read as usual
+        assert_eq!(split_prev("mpris.prev"), ("mpris.prev", false));
+    }
+
+    #[test]
+    fn repeated_notify_wakes_once() {
+        let (notify, wakes) = waiting_notifier();
+        notify.notify_data("test");
+        notify.notify_data("test");
+        notify.notify_bars("test", &[]);
+        notify.notify_draw_only();
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn notify_wakes_after_reload() {
+        // load_config marks everything dirty itself before calling notify_data
+        let (notify, wakes) = waiting_notifier();
+        notify.inner.state.set(NotifyState::NewData);
+        notify.inner.dirty_all.set(true);
+        notify.notify_data("reload");
+        assert_eq!(wakes.0.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,164 @@
+//! Temperature sensors discovered from /sys/class/hwmon
+use crate::data::{Periodic, Value};
+use crate::state::Runtime;
+use crate::util::{toml_to_f64, toml_to_string, Cell};
+use log::debug;
+use std::fs;
+
+const HWMON_PATH: &str = "/sys/class/hwmon";
+
+#[derive(Debug, Clone)]
+struct Sensor {
+    /// The contents of the hwmon `name` file, such as "coretemp"
+    chip: Box<str>,
+    /// The contents of the `tempN_label` file, if present
+    label: Option<Box<str>>,
+    /// Degrees celsius
+    celsius: f64,
+}
+
+impl Sensor {
+    /// Sensors may be selected by chip name or by label; labels are compared ignoring case, and
+    /// with spaces replaced by dashes so that "Package id 0" can be written "package-id-0".
+    fn matches(&self, name: &str) -> bool {
+        *self.chip == *name
+            || self.label.as_deref().map_or(false, |label| {
+                label.len() == name.len()
+                    && label.chars().zip(name.chars()).all(|(l, n)| {
+                        l.to_ascii_lowercase() == n.to_ascii_lowercase() || (l == ' ' && n == '-')
+                    })
+            })
+    }
+}
+
+/// Read all temperature sensors.  This is done on every poll, so that hotplugged devices appear
+/// and disappear.
+fn scan() -> Vec<Sensor> {
+    let mut dirs: Vec<_> = fs::read_dir(HWMON_PATH)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .collect();
+    dirs.sort();
+    let mut rv = Vec::new();
+    for dir in dirs {
+        let chip: Box<str> = match fs::read_to_string(dir.join("name")) {
+            Ok(name) => name.trim_end().into(),
+            Err(_) => continue,
+        };
+        let mut inputs: Vec<_> = fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter_map(|n| Some(n.strip_prefix("temp")?.strip_suffix("_input")?.to_owned()))
+            .filter_map(|n| n.parse::<u32>().ok())
+            .collect();
+        inputs.sort();
+        for n in inputs {
+            let celsius = match fs::read_to_string(dir.join(format!("temp{n}_input"))) {
+                Ok(s) => match s.trim_end().parse::<i64>() {
+                    Ok(v) => v as f64 / 1000.0,
+                    Err(_) => continue,
+                },
+                Err(e) => {
+                    // sensors on a powered-down device may return an error here
+                    debug!("Could not read {}/temp{n}_input: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            let label = fs::read_to_string(dir.join(format!("temp{n}_label")))
+                .ok()
+                .map(|s| s.trim_end().into());
+            rv.push(Sensor {
+                chip: chip.clone(),
+                label,
+                celsius,
+            });
+        }
+    }
+    rv
+}
+
+#[derive(Debug)]
+pub struct Temperature {
+    poll: Periodic<Cell<Vec<Sensor>>>,
+    /// The sensor shown when no key is given; the first one found if empty
+    sensor: Box<str>,
+    fahrenheit: bool,
+    high: Option<f64>,
+    critical: Option<f64>,
+}
+
+impl Temperature {
+    pub fn from_toml(config: &toml::Value) -> Result<Self, &'static str> {
+        let fahrenheit = match config.get("units").and_then(|v| v.as_str()) {
+            None | Some("C") | Some("c") | Some("celsius") => false,
+            Some("F") | Some("f") | Some("fahrenheit") => true,
+            Some(_) => return Err("'units' must be either C or F"),
+        };
+        Ok(Temperature {
+            poll: Periodic::new(
+                toml_to_f64(config.get("poll")).unwrap_or(10.0),
+                Cell::default(),
+            ),
+            sensor: toml_to_string(config.get("sensor"))
+                .unwrap_or_default()
+                .into(),
+            fahrenheit,
+            high: toml_to_f64(config.get("high")),
+            critical: toml_to_f64(config.get("critical")),
+        })
+    }
+
+    pub fn carry_over(&self, old: &Self) {
+        self.poll.carry_over(&old.poll);
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        self.poll.read_refresh(rt, |sensors| {
+            sensors.set(scan());
+            Some("temperature")
+        });
+        let (sensor, state) = match key {
+            "" => (&*self.sensor, false),
+            "state" => (&*self.sensor, true),
+            key => match key.strip_suffix(".state") {
+                Some(sensor) => (sensor, true),
+                None => (key, false),
+            },
+        };
+        let temp = self.poll.data().take_in(|sensors| {
+            let found = match sensor {
+                "" => sensors.first(),
+                name => sensors.iter().find(|s| s.matches(name)),
+            };
+            found.map(|s| {
+                if self.fahrenheit {
+                    s.celsius * 9.0 / 5.0 + 32.0
+                } else {
+                    s.celsius
+                }
+            })
+        });
+        match (temp, state) {
+            (None, _) => f(Value::Null),
+            (Some(temp), false) => f(Value::Float(temp)),
+            (Some(temp), true) => {
+                let state = match (self.high, self.critical) {
+                    (_, Some(crit)) if temp >= crit => "critical",
+                    (Some(high), _) if temp >= high => "high",
+                    _ => "normal",
+                };
+                f(Value::Borrow(state))
+            }
+        }
+    }
+}
@@ -1,19 +1,22 @@
-use crate::data::{IterationItem, Value};
+use crate::data::{IterationItem, TraySort, Value};
 use crate::dbus::DBus;
 use crate::event::EventSink;
-use crate::font::render_font;
+use crate::font::{layout_font, render_font, wrap_text};
+use crate::icon;
 use crate::item::{Item, PopupDesc};
 use crate::render::Render;
 use crate::state::{NotifierList, Runtime};
 use crate::util::{spawn, spawn_handle, Cell};
 use crate::wayland::Button;
 use async_once_cell::OnceCell as AsyncOnceCell;
-use futures_util::future::RemoteHandle;
+use futures_util::future::{LocalBoxFuture, RemoteHandle};
 use log::{debug, warn};
 use once_cell::unsync::OnceCell;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
+use std::fmt;
 use std::mem::ManuallyDrop;
 use std::rc::Rc;
 use std::time::Instant;
@@ -51,14 +54,15 @@ trait DBusMenu {
           <arg name="id" type="i" direction="in"/>
           <arg name="property" type="s" direction="in"/>
         </method>
-        <method name="GetGroupProperties">
-          <arg type="a(ia{sv})" direction="out"/>
-          <arg name="ids" type="ai" direction="in"/>
-          <arg name="propertyNames" type="as" direction="in"/>
-        </method>
     */
     fn about_to_show(&self, id: i32) -> fdo::Result<bool>;
 
+    fn get_group_properties(
+        &self,
+        ids: &[i32],
+        property_names: &[&str],
+    ) -> fdo::Result<Vec<(i32, HashMap<String, OwnedValue>)>>;
+
     fn get_layout(
         &self,
         parent_id: i32,
@@ -71,6 +75,98 @@ trait DBusMenu {
         -> fdo::Result<()>;
 }
 
+/// The D-Bus calls made for tray items, so that tests can supply canned replies in place of the
+/// session bus
+trait TrayBus: fmt::Debug {
+    /// Send a message without waiting for a reply, such as an AddMatch call
+    fn send(&self, msg: zbus::Message);
+
+    /// Read all the properties of an item (org.freedesktop.DBus.Properties.GetAll)
+    fn get_all<'a>(
+        &'a self,
+        owner: &'a str,
+        path: &'a str,
+        interface: &'a str,
+    ) -> LocalBoxFuture<'a, Result<HashMap<String, OwnedValue>, Box<dyn Error>>>;
+}
+
+impl TrayBus for DBus {
+    fn send(&self, msg: zbus::Message) {
+        DBus::send(self, msg)
+    }
+
+    fn get_all<'a>(
+        &'a self,
+        owner: &'a str,
+        path: &'a str,
+        interface: &'a str,
+    ) -> LocalBoxFuture<'a, Result<HashMap<String, OwnedValue>, Box<dyn Error>>> {
+        Box::pin(async move {
+            let zbus = self.connection().await;
+            let reply = zbus
+                .call_method(
+                    Some(owner),
+                    path,
+                    Some("org.freedesktop.DBus.Properties"),
+                    "GetAll",
+                    &interface,
+                )
+                .await?;
+            Ok(reply.body()?)
+        })
+    }
+}
+
+/// The reply to GetLayout: the revision and the (id, properties, children) of the root item
+type MenuLayout = (u32, (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>));
+
+/// The com.canonical.dbusmenu calls used to read a menu, see [TrayBus]
+trait MenuBus {
+    fn about_to_show(&self, id: i32) -> LocalBoxFuture<'_, Result<bool, Box<dyn Error>>>;
+
+    fn get_group_properties<'a>(
+        &'a self,
+        ids: &'a [i32],
+        property_names: &'a [&'a str],
+    ) -> LocalBoxFuture<'a, Result<Vec<(i32, HashMap<String, OwnedValue>)>, Box<dyn Error>>>;
+
+    fn get_layout<'a>(
+        &'a self,
+        parent_id: i32,
+        recursion_depth: i32,
+        property_names: &'a [&'a str],
+    ) -> LocalBoxFuture<'a, Result<MenuLayout, Box<dyn Error>>>;
+}
+
+impl MenuBus for DBusMenuProxy<'static> {
+    fn about_to_show(&self, id: i32) -> LocalBoxFuture<'_, Result<bool, Box<dyn Error>>> {
+        Box::pin(async move { Ok(DBusMenuProxy::about_to_show(self, id).await?) })
+    }
+
+    fn get_group_properties<'a>(
+        &'a self,
+        ids: &'a [i32],
+        property_names: &'a [&'a str],
+    ) -> LocalBoxFuture<'a, Result<Vec<(i32, HashMap<String, OwnedValue>)>, Box<dyn Error>>> {
+        Box::pin(async move {
+            Ok(DBusMenuProxy::get_group_properties(self, ids, property_names).await?)
+        })
+    }
+
+    fn get_layout<'a>(
+        &'a self,
+        parent_id: i32,
+        recursion_depth: i32,
+        property_names: &'a [&'a str],
+    ) -> LocalBoxFuture<'a, Result<MenuLayout, Box<dyn Error>>> {
+        Box::pin(async move {
+            let layout =
+                DBusMenuProxy::get_layout(self, parent_id, recursion_depth, property_names);
+            Ok(layout.await?)
+        })
+    }
+}
+
 #[dbus_proxy(
     interface = "org.freedesktop.StatusNotifierItem",
     assume_defaults = true
@@ -142,6 +238,7 @@ thread_local! {
 
 #[derive(Debug)]
 pub struct TrayItem {
+    bus: Rc<dyn TrayBus>,
     owner: Rc<str>,
     path: Rc<str>,
     // sni: AsyncStatusNotifierItemProxy<'static>,
@@ -153,17 +250,70 @@ pub struct TrayItem {
     title: Cell<Option<Rc<str>>>,
     icon: Cell<Box<str>>,
     icon_path: Cell<Box<str>>,
+    /// AttentionIconName, shown in place of the icon while the status is NeedsAttention
+    attention_icon: Cell<Box<str>>,
+    /// OverlayIconName, drawn over a corner of the icon (for example, as an unread badge)
+    overlay_icon: Cell<Box<str>>,
+    /// The kinds of images that were provided as data, see [Self::pixmap_name]
+    pixmaps: Cell<Vec<&'static str>>,
     status: Cell<Box<str>>,
     tooltip: Cell<Option<Rc<str>>>,
+    tooltip_title: Cell<Option<Rc<str>>>,
+    tooltip_icon: Cell<Option<Rc<str>>>,
+    /// XAyatanaLabel, text to show beside the icon
+    label: Cell<Box<str>>,
+    /// XAyatanaLabelGuide, the longest text the label is expected to contain
+    label_guide: Cell<Box<str>>,
+    /// The orientation and delta of scrolling that has not yet been sent, see [do_click]
+    scroll: Cell<(&'static str, i32)>,
     inspection: Cell<Option<RemoteHandle<()>>>,
+    /// Set while the item is unregistered but still shown, see [REMOVAL_GRACE]
+    removal: Cell<Option<RemoteHandle<()>>>,
     menu: Cell<Option<Rc<TrayPopupMenu>>>,
     interested: Cell<NotifierList>,
 }
 
+/// Read an IconPixmap (or the image of a ToolTip): an array of (width, height, ARGB32 data) in
+/// various sizes
+fn read_pixmaps(value: &Variant) -> Vec<icon::OwnedImage> {
+    let mut images = Vec::new();
+    if let Variant::Array(list) = value {
+        for entry in list.get() {
+            let fields = match entry {
+                Variant::Structure(s) => s.fields(),
+                _ => continue,
+            };
+            let size = |i: usize| {
+                fields
+                    .get(i)
+                    .and_then(|v| i32::try_from(v).ok())
+                    .and_then(|v| u32::try_from(v).ok())
+            };
+            let data: Option<Vec<u8>> = match fields.get(2) {
+                Some(Variant::Array(data)) => {
+                    data.get().iter().map(|b| u8::try_from(b).ok()).collect()
+                }
+                _ => None,
+            };
+            if let (Some(w), Some(h), Some(data)) = (size(0), size(1), data) {
+                images.extend(icon::OwnedImage::from_argb32(w, h, &data));
+            }
+        }
+    }
+    images
+}
+
+/// Some applications unregister and then immediately re-register their items (for example, while
+/// reconnecting to the bus).  Wait this long before removing an unregistered item so that
+/// re-registering does not cause the icon to disappear and reappear.
+const REMOVAL_GRACE: Duration = Duration::from_millis(300);
+
 impl Drop for TrayItem {
     fn drop(&mut self) {
-        let dbus = DBus::get_session();
-        dbus.send(
+        for kind in self.pixmaps.take() {
+            icon::set_pixmaps(&self.pixmap_name(kind), Vec::new());
+        }
+        self.bus.send(
             zbus::Message::method(
                 None::<&str>,
                 Some("org.freedesktop.DBus"),
@@ -177,8 +327,9 @@ impl Drop for TrayItem {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct Tray {
+    bus: Rc<dyn TrayBus>,
     reg_db: Cell<Vec<(String, bool)>>,
     items: Cell<Vec<Rc<TrayItem>>>,
     interested: Cell<NotifierList>,
@@ -207,17 +358,15 @@ impl Tray {
                     let tray = cell.get();
                     let tray = tray.as_ref().unwrap();
                     tray.reg_db.take_in(|reg_db| {
-                        let dbus = DBus::get_session();
                         reg_db.retain(|(path, is_kde)| {
-                            if let Some(pos) = path.find('/') {
-                                let owner = &path[..pos];
+                            if let Some((owner, _)) = split_item(path) {
                                 if old == owner || name == owner {
                                     let iface = if *is_kde {
                                         "org.kde.StatusNotifierWatcher"
                                     } else {
                                         "org.freedesktop.StatusNotifierWatcher"
                                     };
-                                    dbus.send(
+                                    tray.bus.send(
                                         zbus::Message::signal(
                                             None::<&str>,
                                             None::<&str>,
@@ -242,7 +391,12 @@ impl Tray {
             Ok(())
         });
 
-        Tray::default()
+        Tray {
+            bus: DBus::get_session(),
+            reg_db: Default::default(),
+            items: Default::default(),
+            interested: Default::default(),
+        }
     }
 }
 
@@ -263,40 +417,40 @@ macro_rules! build_snw {
                     DATA.with(|cell| {
                         let tray = cell.get().unwrap();
                         tray.reg_db.take_in(|reg_db| {
-                            let dbus = DBus::get_session();
-                            if path.starts_with('/') {
-                                let service = format!("{}{}", hdr.sender()?.unwrap(), path);
-                                dbus.send(zbus::Message::signal(
-                                    None::<&str>,
-                                    None::<&str>,
-                                    "/StatusNotifierWatcher",
-                                    $name,
-                                    "StatusNotifierItemRegistered",
-                                    &service,
-                                )?);
-                                reg_db.push((service, $is_kde));
-                                reg_db.sort();
-                                reg_db.dedup();
+                            let sender = hdr.sender()?;
+                            let service = if path.starts_with('/') {
+                                sender.map(|s| format!("{}{}", s, path))
                             } else if path.starts_with(':') {
                                 // kde uses this style
-                                let service = format!("{}/StatusNotifierItem", path);
-                                dbus.send(zbus::Message::signal(
-                                    None::<&str>,
-                                    None::<&str>,
-                                    "/StatusNotifierWatcher",
-                                    $name,
-                                    "StatusNotifierItemRegistered",
-                                    &service,
-                                )?);
-                                reg_db.push((service, $is_kde));
-                                reg_db.sort();
-                                reg_db.dedup();
+                                Some(format!("{}/StatusNotifierItem", path))
+                            } else if !path.is_empty() {
+                                // A well-known bus name, which the spec also allows.  The item's
+                                // signals come from the caller's unique name, so use that to
+                                // identify it.
+                                sender.map(|s| format!("{}/StatusNotifierItem", s))
                             } else {
-                                warn!(
-                                    "Unknown RegisterStatusNotifierItem from {:?}: {}",
-                                    hdr.sender(),
-                                    path
-                                );
+                                None
+                            };
+                            match service {
+                                Some(service) => {
+                                    tray.bus.send(zbus::Message::signal(
+                                        None::<&str>,
+                                        None::<&str>,
+                                        "/StatusNotifierWatcher",
+                                        $name,
+                                        "StatusNotifierItemRegistered",
+                                        &service,
+                                    )?);
+                                    reg_db.push((service, $is_kde));
+                                    reg_db.sort();
+                                    reg_db.dedup();
+                                }
+                                None => {
+                                    warn!(
+                                        "Unknown RegisterStatusNotifierItem from {:?}: {}",
+                                        sender, path
+                                    );
+                                }
                             }
                             Ok(())
                         })
@@ -377,7 +531,8 @@ async fn init_snw(is_kde: bool) -> Result<(), Box<dyn Error>> {
     .1?
     {
         zbus::fdo::RequestNameReply::PrimaryOwner => {
-            dbus.send(zbus::Message::signal(
+            let bus = DATA.with(|cell| cell.get().unwrap().bus.clone());
+            bus.send(zbus::Message::signal(
                 None::<&str>,
                 None::<&str>,
                 "/StatusNotifierWatcher",
@@ -442,12 +597,18 @@ async fn init_snw(is_kde: bool) -> Result<(), Box<dyn Error>> {
     } else {
         "org.freedesktop.StatusNotifierItem"
     };
-    dbus.add_signal_watcher(move |path, iface, _member, msg| {
+    dbus.add_signal_watcher(move |path, iface, member, msg| {
         if iface != sni_path {
             return;
         }
         let hdr = msg.header().unwrap();
         let owner = hdr.sender().unwrap().unwrap();
+        // The new status is included in the signal, so it can be shown without waiting for the
+        // properties to be fetched again
+        let status = match member {
+            "NewStatus" => msg.body::<String>().ok(),
+            _ => None,
+        };
 
         DATA.with(|cell| {
             let tray = cell.get();
@@ -455,6 +616,9 @@ async fn init_snw(is_kde: bool) -> Result<(), Box<dyn Error>> {
             tray.items.take_in(|items| {
                 for item in items {
                     if &*item.owner == &**owner && &*item.path == &**path {
+                        if let Some(status) = &status {
+                            item.set_status(status.clone());
+                        }
                         item.reinspect();
                     }
                 }
@@ -495,9 +659,117 @@ async fn init_snw(is_kde: bool) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// The well-known name (and interface) of the given StatusNotifierWatcher
+fn snw_name(is_kde: bool) -> &'static str {
+    if is_kde {
+        "org.kde.StatusNotifierWatcher"
+    } else {
+        "org.freedesktop.StatusNotifierWatcher"
+    }
+}
+
+/// The interface of the items registered with the given StatusNotifierWatcher
+fn sni_interface(is_kde: bool) -> &'static str {
+    if is_kde {
+        "org.kde.StatusNotifierItem"
+    } else {
+        "org.freedesktop.StatusNotifierItem"
+    }
+}
+
+/// Split a registered item (like ":1.52/StatusNotifierItem") into its owner and path.  An item
+/// that is only a bus name uses the default path from the spec.
+fn split_item(item: &str) -> Option<(&str, &str)> {
+    match item.find('/') {
+        Some(0) => None,
+        Some(pos) => Some((&item[..pos], &item[pos..])),
+        None if !item.is_empty() => Some((item, "/StatusNotifierItem")),
+        None => None,
+    }
+}
+
+/// Print the items registered with a running StatusNotifierWatcher, for `--list-tray`
+pub async fn print_items() -> Result<(), Box<dyn Error>> {
+    let zbus = zbus::Connection::session().await?;
+    let mut seen = Vec::new();
+    let mut found_snw = false;
+    for is_kde in [true, false] {
+        let snw = snw_name(is_kde);
+        let reply = zbus
+            .call_method(
+                Some(snw),
+                "/StatusNotifierWatcher",
+                Some("org.freedesktop.DBus.Properties"),
+                "Get",
+                &(snw, "RegisteredStatusNotifierItems"),
+            )
+            .await;
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                debug!("Could not query {}: {}", snw, e);
+                continue;
+            }
+        };
+        found_snw = true;
+        let items: Vec<String> = match reply.body()? {
+            Variant::Array(items) => items
+                .get()
+                .iter()
+                .filter_map(|item| item.try_into().ok())
+                .collect(),
+            _ => continue,
+        };
+        for item in items {
+            if seen.contains(&item) {
+                continue;
+            }
+            let (owner, path) = match split_item(&item) {
+                Some(rv) => rv,
+                None => continue,
+            };
+            let props = zbus
+                .call_method(
+                    Some(owner),
+                    path,
+                    Some("org.freedesktop.DBus.Properties"),
+                    "GetAll",
+                    &sni_interface(is_kde),
+                )
+                .await
+                .and_then(|reply| reply.body::<HashMap<String, OwnedValue>>());
+            println!("{}", item);
+            match props {
+                Ok(props) => {
+                    let get = |key: &str| match props.get(key).map(|v| &**v) {
+                        Some(Variant::Str(s)) => s.as_str(),
+                        Some(Variant::ObjectPath(p)) => p.as_str(),
+                        _ => "",
+                    };
+                    println!("    id: {}", get("Id"));
+                    println!("    title: {}", get("Title"));
+                    println!("    status: {}", get("Status"));
+                    println!("    menu: {}", get("Menu"));
+                }
+                Err(e) => println!("    (could not read properties: {})", e),
+            }
+            seen.push(item);
+        }
+    }
+    if !found_snw {
+        return Err(
+            "No StatusNotifierWatcher is running; start a tray host such as rwaybar".into(),
+        );
+    }
+    if seen.is_empty() {
+        println!("No tray items are registered");
+    }
+    Ok(())
+}
+
 fn do_add_item(is_kde: bool, item: String) {
-    let (owner, path): (Rc<str>, Rc<str>) = match item.find('/') {
-        Some(pos) => (Rc::from(&item[..pos]), Rc::from(&item[pos..])),
+    let (owner, path): (Rc<str>, Rc<str>) = match split_item(&item) {
+        Some((owner, path)) => (owner.into(), path.into()),
         None => return,
     };
 
@@ -507,22 +779,24 @@ fn do_add_item(is_kde: bool, item: String) {
         tray.items.take_in(|items| {
             for item in &*items {
                 if &*item.owner == &*owner && item.path == path {
+                    // re-registered before the removal completed
+                    item.removal.set(None);
                     return;
                 }
             }
+            // An item with the same path that is being removed was probably the same application
+            // under another bus name; put the new item in its place.
+            let replace = items
+                .iter()
+                .position(|item| item.path == path && item.removal.take_in(|r| r.is_some()));
 
-            let sni_path = if is_kde {
-                "org.kde.StatusNotifierItem"
-            } else {
-                "org.freedesktop.StatusNotifierItem"
-            };
-            let dbus = DBus::get_session();
+            let sni_path = sni_interface(is_kde);
             let rule = format!(
                 "type='signal',interface='{}',sender='{}',path='{}'",
                 sni_path, owner, path
             );
 
-            dbus.send(
+            tray.bus.send(
                 zbus::Message::method(
                     None::<&str>,
                     Some("org.freedesktop.DBus"),
@@ -545,6 +819,7 @@ fn do_add_item(is_kde: bool, item: String) {
             */
 
             let item = Rc::new(TrayItem {
+                bus: tray.bus.clone(),
                 owner,
                 path,
                 is_kde,
@@ -552,24 +827,36 @@ fn do_add_item(is_kde: bool, item: String) {
                 title: Default::default(),
                 icon: Default::default(),
                 icon_path: Default::default(),
+                attention_icon: Default::default(),
+                overlay_icon: Default::default(),
+                pixmaps: Default::default(),
+                scroll: Cell::new(("", 0)),
                 status: Default::default(),
                 tooltip: Default::default(),
+                tooltip_title: Default::default(),
+                tooltip_icon: Default::default(),
+                label: Default::default(),
+                label_guide: Default::default(),
                 rule: rule.into(),
                 inspection: Default::default(),
+                removal: Default::default(),
                 menu: Default::default(),
                 interested: Default::default(),
             });
 
             item.reinspect();
-            items.push(item);
+            match replace {
+                Some(pos) => items[pos] = item,
+                None => items.push(item),
+            }
             // No need to update tray.interested; reinspect->handle_update will do it
         });
     });
 }
 
 fn do_del_item(item: String) {
-    let (owner, path) = match item.find('/') {
-        Some(pos) => (&item[..pos], &item[pos..]),
+    let (owner, path) = match split_item(&item) {
+        Some(rv) => rv,
         None => return,
     };
 
@@ -577,38 +864,96 @@ fn do_del_item(item: String) {
         let tray = cell.get();
         let tray = tray.as_ref().unwrap();
         tray.items.take_in(|items| {
-            items.retain(|item| &*item.owner != owner || &*item.path != path);
+            for item in &*items {
+                if &*item.owner != owner || &*item.path != path {
+                    continue;
+                }
+                let this = Rc::downgrade(item);
+                item.removal.set(Some(spawn_handle("Tray item removal", async move {
+                    tokio::time::sleep(REMOVAL_GRACE).await;
+                    remove_item(this);
+                    Ok(())
+                })));
+            }
+        });
+    });
+}
+
+fn remove_item(item: std::rc::Weak<TrayItem>) {
+    DATA.with(|cell| {
+        let tray = cell.get();
+        let tray = tray.as_ref().unwrap();
+        tray.items.take_in(|items| {
+            items.retain(|i| !std::ptr::eq(&**i, item.as_ptr()));
         });
         tray.interested.take().notify_data("tray:item-remove");
     });
 }
 
 impl TrayItem {
+    /// The icon name used for one of this item's images that were provided as data, such as
+    /// its IconPixmap ("pixmap") or the image in its ToolTip ("tooltip")
+    fn pixmap_name(&self, kind: &str) -> String {
+        format!("sni-{}:{}{}", kind, self.owner, self.path)
+    }
+
+    fn has_pixmap(&self, kind: &str) -> bool {
+        self.pixmaps.take_in(|kinds| kinds.contains(&kind))
+    }
+
+    /// Make images available as [Self::pixmap_name], or remove them if there are none.  Returns
+    /// true if there were any.
+    fn set_pixmap(&self, kind: &'static str, images: Vec<icon::OwnedImage>) -> bool {
+        let present = !images.is_empty();
+        icon::set_pixmaps(&self.pixmap_name(kind), images);
+        self.pixmaps.take_in(|kinds| {
+            kinds.retain(|k| *k != kind);
+            if present {
+                kinds.push(kind);
+            }
+        });
+        present
+    }
+
+    /// The name of the icon to show, which is the attention icon (if any) while the item needs
+    /// attention
+    fn icon_name(&self) -> String {
+        let attention = self.status.take_in(|s| &**s == "NeedsAttention")
+            && (self.attention_icon.take_in(|i| !i.is_empty()) || self.has_pixmap("attention"));
+        if attention {
+            self.resolve_icon(&self.attention_icon, "attention")
+        } else {
+            self.resolve_icon(&self.icon, "pixmap")
+        }
+    }
+
+    /// The full name of one of the item's icons (in its theme path, if it has one), or the name
+    /// of its image data if it only provided that.  This is empty if it has neither.
+    fn resolve_icon(&self, name: &Cell<Box<str>>, kind: &str) -> String {
+        let name = name.take_in(|name| name.to_string());
+        if name.is_empty() && self.has_pixmap(kind) {
+            return self.pixmap_name(kind);
+        }
+        self.icon_path.take_in(|path| {
+            if path.is_empty() || name.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            }
+        })
+    }
+
     fn reinspect(self: &Rc<Self>) {
         self.inspection.take_in(|i| {
             i.get_or_insert_with(|| {
-                let sni_path = if self.is_kde {
-                    "org.kde.StatusNotifierItem"
-                } else {
-                    "org.freedesktop.StatusNotifierItem"
-                };
+                let sni_path = sni_interface(self.is_kde);
                 let owner = self.owner.clone();
                 let path = self.path.clone();
+                let bus = self.bus.clone();
                 let this = Rc::downgrade(self);
                 spawn_handle("Tray item inspection", async move {
-                    let dbus = DBus::get_session();
-                    let zbus = dbus.connection().await;
-                    let props = zbus
-                        .call_method(
-                            Some(&*owner),
-                            &*path,
-                            Some("org.freedesktop.DBus.Properties"),
-                            "GetAll",
-                            &sni_path,
-                        )
-                        .await?;
+                    let props = bus.get_all(&owner, &path, sni_path).await?;
 
-                    let props = props.body()?;
                     // Avoid holding a strong reference from inside the self-owned task
                     if let Some(this) = this.upgrade() {
                         this.inspection.set(None);
@@ -620,10 +965,24 @@ impl TrayItem {
         });
     }
 
-    fn handle_update(&self, props: &HashMap<&str, OwnedValue>) {
+    fn set_status(&self, status: String) {
+        if self.status.take_in(|s| **s == *status) {
+            return;
+        }
+        self.status.set(status.into());
+        // item status is queried without setting self.interested, so updates need to
+        // notify anyone who iterated the tray and not just those who query this item
+        DATA.with(|cell| {
+            let tray = cell.get();
+            let tray = tray.as_ref().unwrap();
+            tray.interested.take().notify_data("tray:item-status");
+        });
+    }
+
+    fn handle_update<K: AsRef<str>>(&self, props: &HashMap<K, OwnedValue>) {
         for (key, value) in props {
             let value = &**value;
-            match &**key {
+            match key.as_ref() {
                 "Id" => {
                     drop(value.try_into().map(|v: String| self.id.set(v.into())));
                 }
@@ -637,6 +996,29 @@ impl TrayItem {
                 "IconName" => {
                     drop(value.try_into().map(|v: String| self.icon.set(v.into())));
                 }
+                "IconPixmap" => {
+                    self.set_pixmap("pixmap", read_pixmaps(value));
+                }
+                "AttentionIconName" => {
+                    drop(
+                        value
+                            .try_into()
+                            .map(|v: String| self.attention_icon.set(v.into())),
+                    );
+                }
+                "AttentionIconPixmap" => {
+                    self.set_pixmap("attention", read_pixmaps(value));
+                }
+                "OverlayIconName" => {
+                    drop(
+                        value
+                            .try_into()
+                            .map(|v: String| self.overlay_icon.set(v.into())),
+                    );
+                }
+                "OverlayIconPixmap" => {
+                    self.set_pixmap("overlay", read_pixmaps(value));
+                }
                 "IconThemePath" => {
                     drop(
                         value
@@ -644,27 +1026,45 @@ impl TrayItem {
                             .map(|v: String| self.icon_path.set(v.into())),
                     );
                 }
-                "Status" => drop(value.try_into().map(|v: String| {
-                    self.status.set(v.into());
-                    // item status is queried without setting self.interested, so updates need to
-                    // notify anyone who iterated the tray and not just those who query this item
-                    DATA.with(|cell| {
-                        let tray = cell.get();
-                        let tray = tray.as_ref().unwrap();
-                        tray.interested.take().notify_data("tray:item-status");
-                    });
-                })),
+                "Status" => drop(value.try_into().map(|v: String| self.set_status(v))),
                 "ToolTip" => match value {
                     Variant::Structure(v) => {
                         // value is (icon-name, icon-bitmap, title, text)
                         // see https://www.freedesktop.org/wiki/Specifications/StatusNotifierItem/StatusNotifierItem/#org.freedesktop.statusnotifieritem.tooltip
-                        v.fields()
-                            .get(3)
-                            .and_then(|v| v.try_into().ok())
-                            .map(|v: String| self.tooltip.set(Some(v.into())));
+                        let fields = v.fields();
+                        let get = |i: usize| {
+                            fields
+                                .get(i)
+                                .and_then(|v| v.try_into().ok())
+                                .filter(|v: &String| !v.is_empty())
+                                .map(Rc::from)
+                        };
+                        // Only use the image data if there is no icon name
+                        let images = match get(0) {
+                            Some(_) => Vec::new(),
+                            None => fields.get(1).map(read_pixmaps).unwrap_or_default(),
+                        };
+                        let icon = if self.set_pixmap("tooltip", images) {
+                            Some(self.pixmap_name("tooltip").into())
+                        } else {
+                            get(0)
+                        };
+                        self.tooltip_icon.set(icon);
+                        self.tooltip_title.set(get(2));
+                        self.tooltip.set(get(3));
                     }
                     _ => (),
                 },
+                "XAyatanaLabel" => {
+                    drop(value.try_into().map(|v: String| self.label.set(v.into())));
+                }
+                "XAyatanaLabelGuide" => {
+                    drop(
+                        value
+                            .try_into()
+                            .map(|v: String| self.label_guide.set(v.into())),
+                    );
+                }
                 "Menu" => {
                     drop(value.try_into().map(|v: zvariant::ObjectPath| {
                         self.menu.take_in(|menu| {
@@ -672,18 +1072,13 @@ impl TrayItem {
                                 .as_ref()
                                 .and_then(|menu| menu.menu_path.take_in(|mp| mp.clone()));
 
-                            if old_path.as_deref() != Some(&*v) {
-                                let v: Rc<str> = v.as_str().into();
-                                *menu = Some(Rc::new(TrayPopupMenu {
-                                    owner: self.owner.clone(),
-                                    menu_path: Cell::new(Some(v)),
-                                    menu: AsyncOnceCell::new(),
-                                    watcher: Default::default(),
-                                    refresh: Cell::new(None),
-                                    fresh: Default::default(),
-                                    items: Default::default(),
-                                    interested: Default::default(),
-                                }));
+                            // Items without a menu may still send a placeholder path
+                            let path = Some(v.as_str())
+                                .filter(|p| !matches!(*p, "" | "/" | "/NO_DBUSMENU"));
+                            if old_path.as_deref() != path {
+                                let path = path.map(Into::into);
+                                let (bus, owner) = (self.bus.clone(), self.owner.clone());
+                                *menu = Some(TrayPopupMenu::new(bus, owner, path));
                             }
                         });
                     }));
@@ -700,6 +1095,8 @@ impl TrayItem {
 pub struct TrayPopup {
     title: Option<Rc<str>>,
     tooltip: Option<Rc<str>>,
+    tooltip_title: Option<Rc<str>>,
+    tooltip_icon: Option<Rc<str>>,
     menu: Rc<TrayPopupMenu>,
     rendered_ids: Vec<(f32, f32, i32)>,
 }
@@ -712,6 +1109,7 @@ impl PartialEq for TrayPopup {
 
 #[derive(Debug)]
 struct TrayPopupMenu {
+    bus: Rc<dyn TrayBus>,
     owner: Rc<str>,
     menu_path: Cell<Option<Rc<str>>>,
     menu: AsyncOnceCell<DBusMenuProxy<'static>>,
@@ -719,6 +1117,11 @@ struct TrayPopupMenu {
     refresh: Cell<Option<RemoteHandle<()>>>,
     fresh: Cell<Option<Instant>>,
     items: Cell<Vec<MenuItem>>,
+    /// The id of the highlighted row.  This is kept with the menu and not the popup so that it
+    /// survives the popup being recreated, and so it can be moved by either the pointer or keyboard.
+    highlight: Cell<Option<i32>>,
+    /// The ids of the submenus that are shown; the others are collapsed to just their parent row
+    expanded: Cell<Vec<i32>>,
     interested: Cell<NotifierList>,
 }
 
@@ -729,10 +1132,85 @@ struct MenuItem {
     visible: bool,
     enabled: bool,
     is_sep: bool,
+    /// This item's children follow it, with a greater depth
+    submenu: bool,
+    toggle: Toggle,
     label: String,
 }
 
+/// A checkbox or radio button shown beside a menu item, with its state: 1 for on, 0 for off,
+/// and -1 for indeterminate
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Toggle {
+    #[default]
+    None,
+    Check(i32),
+    Radio(i32),
+}
+
+impl Toggle {
+    fn new(kind: Option<&str>, state: Option<i32>) -> Self {
+        let state = state.unwrap_or(-1);
+        match kind {
+            Some("checkmark") => Toggle::Check(state),
+            Some("radio") => Toggle::Radio(state),
+            _ => Toggle::None,
+        }
+    }
+}
+
+/// The menu item properties that are displayed
+const MENU_PROPS: &[&str] = &[
+    "type",
+    "label",
+    "visible",
+    "enabled",
+    "children-display",
+    "toggle-type",
+    "toggle-state",
+];
+
+/// The scroll delta sent for each scroll event on an item; this matches one step of a mouse wheel
+const SCROLL_STEP: i32 = 15;
+
+/// Space to the left of the label for the checkbox or radio button of a toggle item
+const TOGGLE_WIDTH: f32 = 16.0;
+
+/// Submenus nested deeper than this are not shown
+const MAX_MENU_DEPTH: u32 = 8;
+
+/// Remove the mnemonic markers (underscores) from a menu label
+fn unescape_label(label: &str) -> String {
+    let mut text = String::with_capacity(label.len());
+    let mut esc = false;
+    for c in label.chars() {
+        if c == '_' && !esc {
+            esc = true;
+            continue;
+        }
+        esc = false;
+        text.push(c);
+    }
+    text
+}
+
 impl TrayPopupMenu {
+    fn new(bus: Rc<dyn TrayBus>, owner: Rc<str>, menu_path: Option<Rc<str>>) -> Rc<Self> {
+        Rc::new(TrayPopupMenu {
+            bus,
+            owner,
+            menu_path: Cell::new(menu_path),
+            menu: AsyncOnceCell::new(),
+            watcher: Default::default(),
+            refresh: Cell::new(None),
+            fresh: Default::default(),
+            items: Default::default(),
+            highlight: Cell::new(None),
+            expanded: Default::default(),
+            interested: Default::default(),
+        })
+    }
+
     fn add_items<'a, I>(items: &mut Vec<MenuItem>, iter: I, depth: u32)
     where
         I: Iterator<Item = &'a Variant<'a>>,
@@ -747,6 +1225,7 @@ impl TrayPopupMenu {
                 Variant::Structure(s) => s.fields(),
                 _ => continue,
             };
+            let mut parent = None;
             if let Some(id) = fields.get(0).and_then(|v| v.try_into().ok()) {
                 item.id = id;
             }
@@ -755,17 +1234,7 @@ impl TrayPopupMenu {
                 .and_then(|v| v.downcast_ref::<zvariant::Dict>())
             {
                 if let Ok(Some(label)) = props.get::<_, str>("label") {
-                    let mut text = String::with_capacity(label.len());
-                    let mut esc = false;
-                    for c in label.chars() {
-                        if c == '_' && !esc {
-                            esc = true;
-                            continue;
-                        }
-                        esc = false;
-                        text.push(c);
-                    }
-                    item.label = text.into();
+                    item.label = unescape_label(label);
                 }
                 item.visible = props.get("visible").ok().flatten().copied().unwrap_or(true);
                 item.enabled = props.get("enabled").ok().flatten().copied().unwrap_or(true);
@@ -778,12 +1247,26 @@ impl TrayPopupMenu {
                     Ok(Some(v)) => debug!("Unknown menu item type: {}", v),
                     _ => (),
                 }
+                // Some applications only fill in a submenu once it is about to be shown
+                item.submenu =
+                    matches!(props.get::<_, str>("children-display"), Ok(Some("submenu")));
+                item.toggle = Toggle::new(
+                    props.get::<_, str>("toggle-type").ok().flatten(),
+                    props.get("toggle-state").ok().flatten().copied(),
+                );
                 item.depth = depth;
+                parent = Some(items.len());
                 items.push(item);
                 item = MenuItem::default();
             }
+            let start = items.len();
             if let Some(Variant::Array(submenu)) = fields.get(2) {
-                Self::add_items(items, submenu.into_iter(), depth + 1);
+                if depth < MAX_MENU_DEPTH {
+                    Self::add_items(items, submenu.into_iter(), depth + 1);
+                }
+            }
+            if let Some(parent) = parent.filter(|_| items.len() > start) {
+                items[parent].submenu = true;
             }
         }
     }
@@ -844,6 +1327,32 @@ impl TrayPopupMenu {
             _ => return,
         }
 
+        if msg.member().as_deref() == Some("ItemsPropertiesUpdated") {
+            // Only some properties changed; avoid fetching the entire layout if possible
+            let ids = match msg.body::<(
+                Vec<(i32, HashMap<String, OwnedValue>)>,
+                Vec<(i32, Vec<String>)>,
+            )>() {
+                Ok((updated, removed)) => {
+                    let mut ids: Vec<i32> = updated.iter().map(|(id, _)| *id).collect();
+                    ids.extend(removed.iter().map(|(id, _)| *id));
+                    ids.sort_unstable();
+                    ids.dedup();
+                    ids
+                }
+                Err(_) => Vec::new(),
+            };
+            if !ids.is_empty() && self.fresh.get().is_some() {
+                match self.update_items(dbm, &ids).await {
+                    Ok(true) => return,
+                    Ok(false) => {}
+                    Err(e) => {
+                        debug!("GetGroupProperties failed, fetching the entire menu: {}", e);
+                    }
+                }
+            }
+        }
+
         if self.fresh.replace(None).is_some() {
             match self.refresh().await {
                 Ok(()) => (),
@@ -858,32 +1367,80 @@ impl TrayPopupMenu {
         self.menu.get()
     }
 
+    /// Update the properties of some existing menu items, using a single GetGroupProperties call.
+    ///
+    /// Returns false if the layout of the menu changed, in which case it must be fetched again.
+    async fn update_items(&self, dbm: &dyn MenuBus, ids: &[i32]) -> Result<bool, Box<dyn Error>> {
+        let updates = dbm.get_group_properties(ids, MENU_PROPS).await?;
+        let mut layout_ok = true;
+        self.items.take_in(|items| {
+            for (id, props) in &updates {
+                let item = match items.iter_mut().find(|item| item.id == *id) {
+                    Some(item) => item,
+                    // possibly an item that was hidden before
+                    None => {
+                        layout_ok = false;
+                        return;
+                    }
+                };
+                let get = |key: &str| props.get(key).map(|v| &**v);
+                let visible = get("visible")
+                    .and_then(|v| v.downcast_ref::<bool>().copied())
+                    .unwrap_or(true);
+                let is_sep = get("type").and_then(|v| v.downcast_ref::<str>()) == Some("separator");
+                let display = get("children-display").and_then(|v| v.downcast_ref::<str>());
+                let submenu = display == Some("submenu");
+                if !visible || is_sep != item.is_sep || (submenu && !item.submenu) {
+                    layout_ok = false;
+                    return;
+                }
+                item.label = get("label")
+                    .and_then(|v| v.downcast_ref::<str>())
+                    .map(unescape_label)
+                    .unwrap_or_default();
+                item.enabled = get("enabled")
+                    .and_then(|v| v.downcast_ref::<bool>().copied())
+                    .unwrap_or(true);
+                item.toggle = Toggle::new(
+                    get("toggle-type").and_then(|v| v.downcast_ref::<str>()),
+                    get("toggle-state").and_then(|v| v.downcast_ref::<i32>().copied()),
+                );
+            }
+        });
+        if layout_ok {
+            self.interested.take().notify_data("tray:menu-props");
+        }
+        Ok(layout_ok)
+    }
+
     async fn refresh(self: Rc<Self>) -> Result<(), Box<dyn Error>> {
         let dbm = match self.get_proxy().await? {
             Some(dbm) => dbm,
             None => return Ok(()),
         };
+        self.load(dbm).await?;
+        self.refresh.set(None);
+        Ok(())
+    }
 
+    /// Fetch the entire menu
+    async fn load(&self, dbm: &dyn MenuBus) -> Result<(), Box<dyn Error>> {
         dbm.about_to_show(0).await?;
 
-        let (_rev, (_id, _props, contents)) = dbm
-            .get_layout(0, -1, &["type", "label", "visible", "enabled"] as &[&str])
-            .await?;
+        let (_rev, (_id, _props, contents)) = dbm.get_layout(0, -1, MENU_PROPS).await?;
 
         let mut items = Vec::new();
         TrayPopupMenu::add_items(&mut items, contents.iter().map(|v| &**v), 0);
         self.items.set(items);
         self.interested.take().notify_data("tray:menu");
         self.fresh.set(Some(Instant::now()));
-        self.refresh.set(None);
-
         Ok(())
     }
 
-    fn add_remove_match(&self, dbus: &DBus, method: &str) {
+    fn add_remove_match(&self, method: &str) {
         if let Some(menu_path) = self.menu_path.take_in(|m| m.clone()) {
             // NoReply would be nice
-            dbus.send(zbus::Message::method(
+            self.bus.send(zbus::Message::method(
                 None::<&str>,
                 Some("org.freedesktop.DBus"),
                 "/org/freedesktop/DBus",
@@ -891,7 +1448,7 @@ impl TrayPopupMenu {
                 method,
                 &format!("type='signal',interface='com.canonical.dbusmenu',member='ItemsPropertiesUpdated',sender='{}',path='{}'", self.owner, menu_path),
             ).unwrap());
-            dbus.send(zbus::Message::method(
+            self.bus.send(zbus::Message::method(
                 None::<&str>,
                 Some("org.freedesktop.DBus"),
                 "/org/freedesktop/DBus",
@@ -901,21 +1458,42 @@ impl TrayPopupMenu {
             ).unwrap());
         }
     }
+
+    /// Send an event (such as "clicked") for a menu item, without waiting for a reply
+    fn send_event(&self, dbm: &DBusMenuProxy, id: i32, event: &str) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        self.bus.send(
+            zbus::Message::method(
+                None::<&str>,
+                Some(dbm.destination()),
+                dbm.path(),
+                Some(dbm.interface()),
+                "Event",
+                &(id, event, Variant::I32(0), ts as u32),
+            )
+            .unwrap(),
+        );
+    }
 }
 
 impl Drop for TrayPopupMenu {
     fn drop(&mut self) {
-        let dbus = DBus::get_session();
-        self.add_remove_match(&dbus, "RemoveMatch");
+        self.add_remove_match("RemoveMatch");
     }
 }
 
+/// Size of the icon shown in a tray item's tooltip
+const TOOLTIP_ICON_SIZE: f32 = 32.0;
+
 impl TrayPopup {
     pub fn lazy_refresh(&mut self) {
         self.menu.fresh.take();
     }
 
-    pub fn render(&mut self, ctx: &mut Render) {
+    pub fn render(&mut self, ctx: &mut Render, wrap: Option<f32>) {
         let width = ctx.render_extents.1.x;
         let rendered_ids = &mut self.rendered_ids;
         rendered_ids.clear();
@@ -926,14 +1504,70 @@ impl TrayPopup {
         };
 
         let xbase = ctx.render_pos.x;
+        // Wrap text that would extend past the maximum popup width (if any)
+        fn wrapped<'a>(
+            ctx: &Render,
+            text: &'a str,
+            markup: bool,
+            wrap: Option<f32>,
+            xbase: f32,
+        ) -> Cow<'a, str> {
+            match wrap {
+                Some(max) => wrap_text(ctx, text, markup, max - (ctx.render_pos.x - xbase)),
+                None => Cow::Borrowed(text),
+            }
+        }
 
-        let (mut xsize, ysize) = render_font(ctx, self.title.as_deref().unwrap_or_default(), false);
-        xsize += xbase;
-        ctx.render_pos.y += ysize;
+        let mut xsize;
+        if self.tooltip_icon.is_some() || self.tooltip_title.is_some() {
+            // A full tooltip: icon on the left, with the (emboldened) title and body beside it
+            let ybase = ctx.render_pos.y;
+            let mut text_x = xbase;
+            if let Some(icon) = &self.tooltip_icon {
+                let extents = ctx.render_extents;
+                ctx.render_extents.1 = tiny_skia::Point {
+                    x: xbase + TOOLTIP_ICON_SIZE,
+                    y: ybase + TOOLTIP_ICON_SIZE,
+                };
+                let _ = icon::render(ctx, icon, None);
+                ctx.render_extents = extents;
+                // reserve the space even if the icon was not found, so the size is predictable
+                text_x += TOOLTIP_ICON_SIZE + 4.0;
+            }
+            ctx.render_pos = tiny_skia::Point {
+                x: text_x,
+                y: ybase,
+            };
+            let title = self.tooltip_title.as_ref().or(self.title.as_ref());
+            let title = wrapped(ctx, title.map_or("", |t| &**t), false, wrap, xbase);
+            let stroke = (ctx.text_stroke, ctx.text_stroke_size);
+            ctx.text_stroke = Some(ctx.font_color);
+            ctx.text_stroke_size = Some(0.6);
+            let tsize = render_font(ctx, &title, false);
+            (ctx.text_stroke, ctx.text_stroke_size) = stroke;
+            xsize = text_x + tsize.0;
+            ctx.render_pos.y += tsize.1.ceil();
+
+            if let Some(tooltip) = self.tooltip.as_ref() {
+                let tooltip = wrapped(ctx, tooltip, true, wrap, xbase);
+                let tsize = render_font(ctx, &tooltip, true);
+                xsize = xsize.max(text_x + tsize.0);
+                ctx.render_pos.y += tsize.1.ceil();
+            }
+            ctx.render_pos.x = xbase;
+            if self.tooltip_icon.is_some() {
+                ctx.render_pos.y = ctx.render_pos.y.max(ybase + TOOLTIP_ICON_SIZE);
+            }
+        } else {
+            let title = self.title.as_deref().unwrap_or_default();
+            let title = wrapped(ctx, title, false, wrap, xbase);
+            let (tsize, ysize) = render_font(ctx, &title, false);
+            xsize = xbase + tsize;
+            ctx.render_pos.y += ysize;
 
-        if let Some(tooltip) = self.tooltip.as_ref() {
-            if !tooltip.is_empty() {
+            if let Some(tooltip) = self.tooltip.as_ref() {
                 ctx.render_pos.x += 8.0;
+                let tooltip = wrapped(ctx, tooltip, true, wrap, xbase);
                 let tsize = render_font(ctx, &tooltip, true);
                 xsize = xsize.max(tsize.0 + ctx.render_pos.x);
                 ctx.render_pos.x = xbase;
@@ -969,10 +1603,21 @@ impl TrayPopup {
 
                 ctx.render_pos.y += 9.0;
             }
+            let expanded = self.menu.expanded.take();
+            // The depth of a collapsed submenu's parent, while skipping its children
+            let mut collapsed = None;
             for item in items {
                 if !item.visible {
                     continue;
                 }
+                match collapsed {
+                    Some(depth) if item.depth > depth => continue,
+                    _ => collapsed = None,
+                }
+                let is_open = expanded.contains(&item.id);
+                if item.submenu && !is_open {
+                    collapsed = Some(item.depth);
+                }
                 let indent = xbase + item.depth as f32 * 20.0;
                 if item.is_sep {
                     if let Some(rect) = tiny_skia::Rect::from_xywh(
@@ -987,19 +1632,73 @@ impl TrayPopup {
 
                     ctx.render_pos.y += 7.0;
                 } else {
-                    ctx.render_pos.x = indent;
-                    let tsize = render_font(ctx, &item.label, false);
+                    let label_x = match item.toggle {
+                        Toggle::None => indent,
+                        _ => indent + TOGGLE_WIDTH,
+                    };
+                    ctx.render_pos.x = label_x;
+                    let label = match (item.submenu, is_open) {
+                        (true, true) => Cow::Owned(format!("{} ▾", item.label)),
+                        (true, false) => Cow::Owned(format!("{} ▸", item.label)),
+                        (false, _) => Cow::Borrowed(&*item.label),
+                    };
+                    let label = wrapped(ctx, &label, false, wrap, xbase);
+                    let color = ctx.font_color;
+                    if item.enabled && self.menu.highlight.get() == Some(item.id) {
+                        // reverse video: a row in the text color, with the label in its inverse
+                        let (_, size) =
+                            layout_font(ctx.font, ctx.font_size, ctx.runtime, color, &label, false);
+                        let paint = tiny_skia::Paint {
+                            shader: tiny_skia::Shader::SolidColor(color),
+                            ..Default::default()
+                        };
+                        if let Some(rect) = tiny_skia::Rect::from_xywh(
+                            xbase,
+                            ctx.render_pos.y - 2.0,
+                            width - xbase,
+                            size.1.ceil() + 4.0,
+                        ) {
+                            ctx.canvas.fill_rect(rect, &paint, ctx.render_xform, None);
+                        }
+                        ctx.font_color = tiny_skia::Color::from_rgba(
+                            1.0 - color.red(),
+                            1.0 - color.green(),
+                            1.0 - color.blue(),
+                            color.alpha(),
+                        )
+                        .unwrap_or(tiny_skia::Color::BLACK);
+                    }
+                    if !item.enabled {
+                        ctx.font_color.apply_opacity(0.5);
+                    }
+                    let tsize = render_font(ctx, &label, false);
+                    draw_toggle(ctx, item.toggle, indent, tsize.1);
+                    ctx.font_color = color;
                     let end = ctx.render_pos.y + tsize.1.ceil();
-                    xsize = xsize.max(indent + tsize.0);
+                    xsize = xsize.max(label_x + tsize.0);
                     rendered_ids.push((ctx.render_pos.y, end, item.id));
                     ctx.render_pos.y = end + 5.0;
                 }
             }
+            self.menu.expanded.set(expanded);
         });
 
         ctx.render_pos.x = xsize.ceil();
     }
 
+    /// Highlight the menu row under the pointer, or none if it is not over the popup.  Returns
+    /// true if the highlight changed.
+    pub fn hover(&mut self, y: Option<f64>) -> bool {
+        let id = y.and_then(|y| {
+            let y = y as f32;
+            self.rendered_ids
+                .iter()
+                .find(|&&(min, max, _)| y >= min && y <= max)
+                .map(|&(_, _, id)| id)
+        });
+        self.menu.highlight.replace(id) != id
+    }
+
     pub fn button(&mut self, x: f64, y: f64, button: Button, _runtime: &mut Runtime) {
         let y = y as f32;
         let _ = (x, button);
@@ -1007,37 +1706,201 @@ impl TrayPopup {
             if y < min || y > max {
                 continue;
             }
+            let found = self.menu.items.take_in(|items| {
+                let item = items.iter().find(|item| item.id == id)?;
+                Some((item.enabled, item.submenu))
+            });
+            match found {
+                Some((false, _)) => debug!("Ignoring click on disabled menu item {}", id),
+                Some((true, true)) => self.toggle_submenu(id),
+                _ => {
+                    if let Some(dbm) = self.menu.proxy() {
+                        debug!("Clicking {} {} id {}", dbm.destination(), dbm.path(), id);
+                        self.menu.send_event(dbm, id, "clicked");
+                        if self.menu.items.take_in(|items| flip_toggle(items, id)) {
+                            // Show the new state right away; some applications only report it
+                            // later (or not at all), so also fetch the menu again
+                            self.menu.interested.take().notify_data("tray:toggle");
+                            let menu = self.menu.clone();
+                            self.menu
+                                .refresh
+                                .set(Some(spawn_handle("Tray menu population", menu.refresh())));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Expand or collapse a submenu.  The application is asked to update the submenu before it
+    /// is shown, since some only populate it then.
+    fn toggle_submenu(&self, id: i32) {
+        let opening = self.menu.expanded.take_in(|expanded| {
+            let opening = !expanded.contains(&id);
+            if opening {
+                expanded.push(id);
+            } else {
+                expanded.retain(|&e| e != id);
+            }
+            opening
+        });
+        self.menu.interested.take().notify_data("tray:submenu");
+        if !opening {
             if let Some(dbm) = self.menu.proxy() {
-                let ts = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-                debug!("Clicking {} {} id {}", dbm.destination(), dbm.path(), id);
-                let dbus = DBus::get_session();
-                dbus.send(
-                    zbus::Message::method(
-                        None::<&str>,
-                        Some(dbm.destination()),
-                        dbm.path(),
-                        Some(dbm.interface()),
-                        "Event",
-                        &(id, "clicked", Variant::I32(0), ts as u32),
-                    )
-                    .unwrap(),
-                );
+                self.menu.send_event(dbm, id, "closed");
+            }
+            return;
+        }
+        let menu = self.menu.clone();
+        spawn("Tray submenu", async move {
+            let dbm = match menu.proxy() {
+                Some(dbm) => dbm,
+                None => return Ok(()),
+            };
+            let changed = dbm.about_to_show(id).await?;
+            menu.send_event(dbm, id, "opened");
+            if changed {
+                menu.clone().refresh().await?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Draw the checkbox or radio button of a menu item whose row is at the current position
+fn draw_toggle(ctx: &mut Render, toggle: Toggle, x: f32, height: f32) {
+    let (radio, state) = match toggle {
+        Toggle::None => return,
+        Toggle::Check(state) => (false, state),
+        Toggle::Radio(state) => (true, state),
+    };
+    let size = (height * 0.6).round().clamp(6.0, TOGGLE_WIDTH - 4.0);
+    let left = x + 1.0;
+    let top = ctx.render_pos.y + ((height - size) / 2.0).round();
+    let paint = tiny_skia::Paint {
+        shader: tiny_skia::Shader::SolidColor(ctx.font_color),
+        anti_alias: radio,
+        ..Default::default()
+    };
+    let stroke = tiny_skia::Stroke::default();
+    let (outline, mark) = if radio {
+        let r = size / 2.0;
+        let (cx, cy) = (left + r, top + r);
+        (
+            tiny_skia::PathBuilder::from_circle(cx, cy, r - 0.5),
+            tiny_skia::PathBuilder::from_circle(cx, cy, r - 2.5),
+        )
+    } else {
+        let outline = tiny_skia::Rect::from_xywh(left + 0.5, top + 0.5, size - 1.0, size - 1.0);
+        let mark = match state {
+            // a dash for indeterminate
+            -1 => tiny_skia::Rect::from_xywh(left + 2.0, top + size / 2.0 - 1.0, size - 4.0, 2.0),
+            _ => tiny_skia::Rect::from_xywh(left + 2.0, top + 2.0, size - 4.0, size - 4.0),
+        };
+        (
+            outline.map(tiny_skia::PathBuilder::from_rect),
+            mark.map(tiny_skia::PathBuilder::from_rect),
+        )
+    };
+    if let Some(outline) = outline {
+        ctx.canvas
+            .stroke_path(&outline, &paint, &stroke, ctx.render_xform, None);
+    }
+    let marked = state == 1 || (state == -1 && !radio);
+    if let Some(mark) = mark.filter(|_| marked) {
+        ctx.canvas.fill_path(
+            &mark,
+            &paint,
+            tiny_skia::FillRule::Winding,
+            ctx.render_xform,
+            None,
+        );
+    }
+}
+
+/// Change the state of a toggle item as if the application had done so in response to a click.
+/// Turning on a radio item turns off the others in its group.  Returns false if the item is not a
+/// toggle item.
+fn flip_toggle(items: &mut [MenuItem], id: i32) -> bool {
+    let pos = match items.iter().position(|item| item.id == id) {
+        Some(pos) => pos,
+        None => return false,
+    };
+    let depth = items[pos].depth;
+    match items[pos].toggle {
+        Toggle::None => return false,
+        Toggle::Check(state) => items[pos].toggle = Toggle::Check(if state == 1 { 0 } else { 1 }),
+        Toggle::Radio(_) => {
+            // the group is the run of siblings between separators
+            let ends_group =
+                |item: &MenuItem| item.depth < depth || (item.depth == depth && item.is_sep);
+            let start = items[..pos]
+                .iter()
+                .rposition(ends_group)
+                .map_or(0, |p| p + 1);
+            let end = items[pos..]
+                .iter()
+                .position(ends_group)
+                .map_or(items.len(), |p| pos + p);
+            for item in items[start..end].iter_mut().filter(|i| i.depth == depth) {
+                if let Toggle::Radio(state) = &mut item.toggle {
+                    *state = 0;
+                }
             }
+            items[pos].toggle = Toggle::Radio(1);
         }
     }
+    true
 }
 
-pub fn show(ctx: &mut Render, rv: &mut EventSink, [passive, active, urgent]: [&Rc<Item>; 3]) {
-    let items = DATA.with(|cell| {
+/// Get the current items, in the configured order
+fn sorted_items(rt: &Runtime, sort: &TraySort) -> Vec<Rc<TrayItem>> {
+    let mut items = DATA.with(|cell| {
         let tray = cell.get_or_init(Tray::init);
-        tray.interested
-            .take_in(|interest| interest.add(&ctx.runtime));
+        tray.interested.take_in(|interest| interest.add(rt));
         tray.items
             .take_in(|items| items.iter().cloned().collect::<Vec<_>>())
     });
+    // The list is kept in the order the items registered, and these sorts are stable, so ties
+    // keep that order
+    match sort {
+        TraySort::Arrival => {}
+        TraySort::Id => items.sort_by_cached_key(|item| item.id.take_in(|id| id.to_lowercase())),
+        TraySort::Title => items.sort_by_cached_key(|item| {
+            item.title
+                .take_in(|t| t.as_deref().unwrap_or("").to_lowercase())
+        }),
+        TraySort::Pinned(ids) => items.sort_by_cached_key(|item| {
+            item.id
+                .take_in(|id| match ids.iter().position(|p| **p == **id) {
+                    Some(pos) => (pos, String::new()),
+                    None => (ids.len(), id.to_lowercase()),
+                })
+        }),
+    }
+    items
+}
+
+/// Iterate the tray items, for focus-list blocks and indexed reads like {tray.0.title}
+pub fn read_focus_list<F: FnMut(bool, IterationItem)>(rt: &Runtime, sort: &TraySort, mut f: F) {
+    for item in sorted_items(rt, sort) {
+        f(false, IterationItem::Tray(item));
+    }
+}
+
+pub fn show(
+    ctx: &mut Render,
+    rv: &mut EventSink,
+    [passive, active, urgent]: [&Rc<Item>; 3],
+    spacing: f32,
+    reverse: bool,
+    sort: &TraySort,
+    scroll_threshold: i32,
+) {
+    let mut items = sorted_items(&ctx.runtime, sort);
+    if reverse {
+        items.reverse();
+    }
     let mut group = ctx.group();
 
     for tray_item in items {
@@ -1048,33 +1911,82 @@ pub fn show(ctx: &mut Render, rv: &mut EventSink, [passive, active, urgent]: [&R
         });
 
         let iter_item = IterationItem::Tray(tray_item.clone());
-        let x0 = ctx.render_pos.x;
+        let any_visible = group.bounds.x != group.origin.x;
+        ctx.render_pos.x = next_icon_pos(ctx.render_pos.x, any_visible, spacing);
+        let (x0, y0) = (ctx.render_pos.x, ctx.render_pos.y);
         render.render_clamped_item(ctx, rv, &iter_item);
         let x1 = ctx.render_pos.x;
+        if x0 == x1 {
+            // hidden item; undo the spacing
+            ctx.render_pos.x = group.bounds.x;
+        } else {
+            render_overlay(ctx, &tray_item, x1, y0);
+        }
         group.next_h(ctx);
         if x0 != x1 {
-            if let Some(menu) = tray_item.menu.take_in(|m| m.clone()) {
-                let title = tray_item.title.take_in(|t| t.clone());
-                let tooltip = tray_item.tooltip.take_in(|t| t.clone());
-                let mut es = EventSink::from_tray(tray_item.clone());
-                es.offset_clamp(0.0, x0, x1);
-                es.add_hover(
-                    x0,
-                    x1,
-                    PopupDesc::Tray(TrayPopup {
-                        title,
-                        menu,
-                        tooltip,
-                        rendered_ids: Vec::new(),
-                    }),
-                );
-                rv.merge(es);
-            }
+            // An item with no menu gets a popup with just its title and tooltip; this is
+            // kept so that the popup stays the same across redraws
+            let menu = tray_item.menu.take_in(|m| {
+                m.get_or_insert_with(|| {
+                    TrayPopupMenu::new(tray_item.bus.clone(), tray_item.owner.clone(), None)
+                })
+                .clone()
+            });
+            let title = tray_item.title.take_in(|t| t.clone());
+            let tooltip = tray_item.tooltip.take_in(|t| t.clone());
+            let tooltip_title = tray_item.tooltip_title.take_in(|t| t.clone());
+            let tooltip_icon = tray_item.tooltip_icon.take_in(|t| t.clone());
+            let mut es = EventSink::from_tray(tray_item.clone(), scroll_threshold);
+            es.offset_clamp(0.0, x0, x1);
+            es.add_hover(
+                x0,
+                x1,
+                PopupDesc::Tray(TrayPopup {
+                    title,
+                    menu,
+                    tooltip,
+                    tooltip_title,
+                    tooltip_icon,
+                    rendered_ids: Vec::new(),
+                }),
+            );
+            rv.merge(es);
         }
     }
     ctx.render_pos = group.bounds;
 }
 
+/// Where the next icon of the tray starts, given the end of the previous one.  Spacing only goes
+/// between visible icons, so the tray (and its total width) ends at the last visible icon; this
+/// lets a tray in the right group of a bar grow leftward as icons are added.
+fn next_icon_pos(end: f32, any_visible: bool, spacing: f32) -> f32 {
+    if spacing > 0.0 && any_visible {
+        (end + spacing).ceil()
+    } else {
+        end
+    }
+}
+
+/// Draw an item's overlay icon over the lower right corner of the item, at a third of its height
+fn render_overlay(ctx: &mut Render, item: &TrayItem, x1: f32, y0: f32) {
+    let name = item.resolve_icon(&item.overlay_icon, "overlay");
+    if name.is_empty() {
+        return;
+    }
+    let pos = ctx.render_pos;
+    let extents = ctx.render_extents;
+    let bottom = extents.1.y;
+    let size = ((bottom - y0) / 3.0).round().max(1.0);
+    ctx.render_pos = tiny_skia::Point {
+        x: x1 - size,
+        y: bottom - size,
+    };
+    ctx.render_extents.1 = tiny_skia::Point { x: x1, y: bottom };
+    let _ = icon::render(ctx, &name, None);
+    ctx.render_pos = pos;
+    ctx.render_extents = extents;
+}
+
 pub fn read_in<F: FnOnce(Value) -> R, R>(
     _name: &str,
     item: &TrayItem,
@@ -1084,15 +1996,10 @@ pub fn read_in<F: FnOnce(Value) -> R, R>(
 ) -> R {
     item.interested.take_in(|i| i.add(rt));
     match key {
-        "icon" => item.icon.take_in(|icon| {
-            item.icon_path.take_in(|path| {
-                if path.is_empty() {
-                    f(Value::Borrow(icon))
-                } else {
-                    f(Value::Owned(format!("{}/{}", path, icon)))
-                }
-            })
-        }),
+        "icon" => f(Value::Owned(item.icon_name())),
+        "overlay-icon" => f(Value::Owned(
+            item.resolve_icon(&item.overlay_icon, "overlay"),
+        )),
         "title" => item
             .title
             .take_in(|t| f(t.as_deref().map_or(Value::Null, Value::Borrow))),
@@ -1100,7 +2007,15 @@ pub fn read_in<F: FnOnce(Value) -> R, R>(
         "tooltip" => item
             .tooltip
             .take_in(|t| f(t.as_deref().map_or(Value::Null, Value::Borrow))),
+        "tooltip-title" => item
+            .tooltip_title
+            .take_in(|t| f(t.as_deref().map_or(Value::Null, Value::Borrow))),
+        "tooltip-icon" => item
+            .tooltip_icon
+            .take_in(|t| f(t.as_deref().map_or(Value::Null, Value::Borrow))),
         "id" => item.id.take_in(|id| f(Value::Borrow(&id))),
+        "label" => item.label.take_in(|l| f(Value::Borrow(&l))),
+        "label-guide" => item.label_guide.take_in(|l| f(Value::Borrow(&l))),
         _ => f(Value::Null),
     }
 }
@@ -1110,8 +2025,9 @@ pub fn write(name: &str, item: &TrayItem, key: &str, value: Value, rt: &Runtime)
     let _ = (name, item, key, value, rt);
 }
 
-/// A click or scroll on the tray icon itself
-pub fn do_click(item: &Rc<TrayItem>, how: u32) {
+/// A click or scroll on the tray icon itself.  Scrolling is only sent once it adds up to at least
+/// `scroll_threshold`.
+pub fn do_click(item: &Rc<TrayItem>, how: u32, scroll_threshold: i32) {
     let method = match how {
         0 => "Activate",
         1 => "ContextMenu",
@@ -1121,17 +2037,34 @@ pub fn do_click(item: &Rc<TrayItem>, how: u32) {
         _ => return,
     };
 
-    let dbus = DBus::get_session();
-    let sni_path = if item.is_kde {
-        "org.kde.StatusNotifierItem"
-    } else {
-        "org.freedesktop.StatusNotifierItem"
-    };
+    let mut delta = 0;
+    if how >= 5 {
+        // Like swaybar, down and right are positive
+        let step = if how % 2 == 0 {
+            SCROLL_STEP
+        } else {
+            -SCROLL_STEP
+        };
+        let (orientation, pending) = item.scroll.get();
+        // start over when the direction changes
+        delta = if orientation == method && pending.signum() == step.signum() {
+            pending + step
+        } else {
+            step
+        };
+        if delta.abs() < scroll_threshold {
+            item.scroll.set((method, delta));
+            return;
+        }
+        item.scroll.set((method, 0));
+    }
+
+    let sni_path = sni_interface(item.is_kde);
 
     debug!("Invoking {} on {}", method, item.id.take_in(|i| i.clone()));
     let _ = (|| -> zbus::Result<()> {
         if how < 3 {
-            dbus.send(
+            item.bus.send(
                 zbus::MessageBuilder::method_call(&*item.path, method)?
                     .destination(&*item.owner)?
                     .interface(sni_path)?
@@ -1139,14 +2072,382 @@ pub fn do_click(item: &Rc<TrayItem>, how: u32) {
                     .build(&(0i32, 0i32))?,
             );
         } else {
-            dbus.send(
+            item.bus.send(
                 zbus::MessageBuilder::method_call(&*item.path, "Scroll")?
                     .destination(&*item.owner)?
                     .interface(sni_path)?
                     .with_flags(zbus::MessageFlags::NoReplyExpected)?
-                    .build(&(15i32, method))?,
+                    .build(&(delta, method))?,
             );
         }
         Ok(())
     })();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::FontMapped;
+    use std::future::Future;
+
+    /// Canned replies for the tray's D-Bus calls, and a record of the calls made
+    #[derive(Debug, Default)]
+    struct MockBus {
+        props: Vec<(&'static str, OwnedValue)>,
+        layout: Vec<OwnedValue>,
+        group: Vec<(i32, HashMap<String, OwnedValue>)>,
+        calls: Cell<Vec<String>>,
+    }
+
+    impl MockBus {
+        fn record(&self, call: String) {
+            self.calls.take_in(|calls| calls.push(call));
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.take()
+        }
+    }
+
+    impl TrayBus for MockBus {
+        fn send(&self, msg: zbus::Message) {
+            let member = msg.member().unwrap();
+            let args = match msg.body_signature().unwrap().as_str() {
+                "s" => msg.body::<String>().unwrap(),
+                "ii" => format!("{:?}", msg.body::<(i32, i32)>().unwrap()),
+                "is" => format!("{:?}", msg.body::<(i32, String)>().unwrap()),
+                sig => sig.to_owned(),
+            };
+            self.record(format!("{} {}", member, args));
+        }
+
+        fn get_all<'a>(
+            &'a self,
+            owner: &'a str,
+            path: &'a str,
+            interface: &'a str,
+        ) -> LocalBoxFuture<'a, Result<HashMap<String, OwnedValue>, Box<dyn Error>>> {
+            self.record(format!("GetAll {} {} {}", owner, path, interface));
+            let props = self.props.iter().map(|(k, v)| (k.to_string(), v.clone()));
+            Box::pin(futures_util::future::ready(Ok(props.collect())))
+        }
+    }
+
+    impl MenuBus for MockBus {
+        fn about_to_show(&self, id: i32) -> LocalBoxFuture<'_, Result<bool, Box<dyn Error>>> {
+            self.record(format!("AboutToShow {}", id));
+            Box::pin(futures_util::future::ready(Ok(false)))
+        }
+
+        fn get_group_properties<'a>(
+            &'a self,
+            ids: &'a [i32],
+            _property_names: &'a [&'a str],
+        ) -> LocalBoxFuture<'a, Result<Vec<(i32, HashMap<String, OwnedValue>)>, Box<dyn Error>>>
+        {
+            self.record(format!("GetGroupProperties {:?}", ids));
+            Box::pin(futures_util::future::ready(Ok(self.group.clone())))
+        }
+
+        fn get_layout<'a>(
+            &'a self,
+            parent_id: i32,
+            recursion_depth: i32,
+            _property_names: &'a [&'a str],
+        ) -> LocalBoxFuture<'a, Result<MenuLayout, Box<dyn Error>>> {
+            self.record(format!("GetLayout {} {}", parent_id, recursion_depth));
+            let layout = (1, (0, HashMap::new(), self.layout.clone()));
+            Box::pin(futures_util::future::ready(Ok(layout)))
+        }
+    }
+
+    fn value(v: impl Into<Variant<'static>>) -> OwnedValue {
+        v.into().into()
+    }
+
+    /// A menu item as it appears in a GetLayout reply
+    fn menu_entry(
+        id: i32,
+        props: Vec<(&'static str, Variant<'static>)>,
+        children: Vec<Variant<'static>>,
+    ) -> Variant<'static> {
+        let props: HashMap<_, _> = props.into_iter().collect();
+        zvariant::StructureBuilder::new()
+            .add_field(id)
+            .add_field(props)
+            .add_field(children)
+            .build()
+            .into()
+    }
+
+    fn run(test: impl Future<Output = ()>) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        tokio::task::LocalSet::new().block_on(&rt, test);
+    }
+
+    /// Start tracking items using the mock in place of the session bus
+    fn init_tray(bus: &Rc<MockBus>) {
+        DATA.with(|cell| {
+            let tray = Tray {
+                bus: bus.clone(),
+                reg_db: Default::default(),
+                items: Default::default(),
+                interested: Default::default(),
+            };
+            cell.set(tray).unwrap();
+        });
+    }
+
+    fn tray_items() -> Vec<Rc<TrayItem>> {
+        DATA.with(|cell| cell.get().unwrap().items.take_in(|items| items.clone()))
+    }
+
+    /// Add an item and wait for its properties to be read
+    async fn add_item(item: &str) -> Rc<TrayItem> {
+        do_add_item(false, item.into());
+        let item = tray_items().pop().unwrap();
+        while item.inspection.take_in(|i| i.is_some()) {
+            tokio::task::yield_now().await;
+        }
+        item
+    }
+
+    /// Draw a 400x20 bar whose right group is the tray (with 16px red icons spaced 2px apart)
+    /// followed by a 40px blue item, and return the color of each column
+    fn render_right_group(font: &FontMapped) -> Vec<(u8, u8, u8)> {
+        let cfg = toml::toml! {
+            [right]
+            type = "group"
+            items = [
+                { type = "tray", spacing = 2, item = { format = " ", min-width = 16, bg = "#f00" } },
+                { format = " ", min-width = 40, bg = "#00f" },
+            ]
+        };
+        let bar = Rc::new(Item::new_bar(cfg.into()));
+        let runtime = Runtime::new_test();
+        let mut pixmap = tiny_skia::Pixmap::new(400, 20).unwrap();
+        let size = tiny_skia::Point::from_xy(400.0, 20.0);
+        let mut ctx = Render {
+            canvas: &mut pixmap.as_mut(),
+            cache: &runtime.cache,
+            render_xform: tiny_skia::Transform::identity(),
+            render_extents: (tiny_skia::Point::zero(), size),
+            render_pos: tiny_skia::Point::zero(),
+            render_flex: false,
+            font,
+            font_size: 12.0,
+            font_color: tiny_skia::Color::WHITE,
+            text_stroke: None,
+            text_stroke_size: None,
+            align: Default::default(),
+            err_name: "bar",
+            runtime: &runtime,
+        };
+        bar.render(&mut ctx);
+        (0..400)
+            .map(|x| {
+                let px = pixmap.pixel(x, 10).unwrap();
+                (px.red(), px.green(), px.blue())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tray_in_right_group() {
+        let path = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+        let font = match FontMapped::new("sans".into(), path.into()) {
+            Ok(font) => font,
+            Err(e) => return eprintln!("Skipping, could not load {}: {}", path, e),
+        };
+        let bus = Rc::new(MockBus::default());
+        run(async {
+            init_tray(&bus);
+            assert_eq!(tray_runs(&render_right_group(&font)), [(360, BLUE, 40)]);
+
+            for owner in [":1.1", ":1.2", ":1.3"] {
+                add_item(owner).await;
+            }
+            // the tray grows leftward, with no spacing before the first icon or after the last
+            assert_eq!(
+                tray_runs(&render_right_group(&font)),
+                [
+                    (308, RED, 16),
+                    (324, BLACK, 2),
+                    (326, RED, 16),
+                    (342, BLACK, 2),
+                    (344, RED, 16),
+                    (360, BLUE, 40),
+                ]
+            );
+        });
+    }
+
+    const RED: (u8, u8, u8) = (255, 0, 0);
+    const BLUE: (u8, u8, u8) = (0, 0, 255);
+    const BLACK: (u8, u8, u8) = (0, 0, 0);
+
+    /// The start, color, and width of each run of same-colored columns, after the bar's black
+    /// background at the start
+    fn tray_runs(columns: &[(u8, u8, u8)]) -> Vec<(usize, (u8, u8, u8), usize)> {
+        let mut runs: Vec<(usize, (u8, u8, u8), usize)> = Vec::new();
+        for (x, &color) in columns.iter().enumerate() {
+            match runs.last_mut() {
+                Some((_, c, len)) if *c == color => *len += 1,
+                _ => runs.push((x, color, 1)),
+            }
+        }
+        if runs.first().map_or(false, |r| r.1 == BLACK) {
+            runs.remove(0);
+        }
+        runs
+    }
+
+    #[test]
+    fn register_item() {
+        let bus = Rc::new(MockBus {
+            props: vec![
+                ("Id", value("example")),
+                ("Title", value("Example")),
+                ("IconName", value("example-icon")),
+            ],
+            ..Default::default()
+        });
+        run(async {
+            init_tray(&bus);
+            let item = add_item(":1.42").await;
+            assert_eq!(&*item.owner, ":1.42");
+            assert_eq!(&*item.path, "/StatusNotifierItem");
+            assert_eq!(&*item.id.take_in(|id| id.clone()), "example");
+            assert_eq!(
+                item.title.take_in(|t| t.clone()).as_deref(),
+                Some("Example")
+            );
+            assert_eq!(item.icon_name(), "example-icon");
+
+            // registering the same item again does not add or inspect it again
+            do_add_item(false, ":1.42/StatusNotifierItem".into());
+            assert_eq!(tray_items().len(), 1);
+            assert_eq!(
+                bus.calls(),
+                [
+                    "AddMatch type='signal',interface='org.freedesktop.StatusNotifierItem',\
+                     sender=':1.42',path='/StatusNotifierItem'",
+                    "GetAll :1.42 /StatusNotifierItem org.freedesktop.StatusNotifierItem",
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn update_item_properties() {
+        let bus = Rc::new(MockBus {
+            props: vec![("IconName", value("mail")), ("Status", value("Active"))],
+            ..Default::default()
+        });
+        run(async {
+            init_tray(&bus);
+            let item = add_item(":1.7/org/ayatana/NotificationItem/mail").await;
+            assert_eq!(item.icon_name(), "mail");
+            assert!(item.menu.take_in(|m| m.is_none()));
+
+            let path = zvariant::ObjectPath::try_from("/MenuBar").unwrap();
+            item.handle_update(&HashMap::from([
+                ("Status", value("NeedsAttention")),
+                ("AttentionIconName", value("mail-unread")),
+                ("IconThemePath", value("/opt/mail/icons")),
+                ("Menu", value(path)),
+            ]));
+            assert_eq!(item.icon_name(), "/opt/mail/icons/mail-unread");
+            let menu = item.menu.take_in(|m| m.clone()).unwrap();
+            assert_eq!(
+                menu.menu_path.take_in(|p| p.clone()).as_deref(),
+                Some("/MenuBar")
+            );
+
+            // a placeholder path replaces the menu with an empty one
+            let path = zvariant::ObjectPath::try_from("/NO_DBUSMENU").unwrap();
+            item.handle_update(&HashMap::from([("Menu", value(path))]));
+            let menu = item.menu.take_in(|m| m.clone()).unwrap();
+            assert!(menu.menu_path.take_in(|p| p.is_none()));
+        });
+    }
+
+    #[test]
+    fn fetch_menu() {
+        let bus = MockBus {
+            layout: vec![
+                value(menu_entry(
+                    1,
+                    vec![("label", "_Open".into())],
+                    vec![menu_entry(4, vec![("label", "Recent".into())], vec![])],
+                )),
+                value(menu_entry(2, vec![("type", "separator".into())], vec![])),
+                value(menu_entry(
+                    3,
+                    vec![("label", "Hidden".into()), ("visible", false.into())],
+                    vec![],
+                )),
+                value(menu_entry(5, vec![("label", "Quit".into())], vec![])),
+            ],
+            group: vec![(5, HashMap::from([("label".into(), value("Exit"))]))],
+            ..Default::default()
+        };
+        run(async {
+            let bus = Rc::new(bus);
+            let menu = TrayPopupMenu::new(bus.clone(), ":1.42".into(), Some("/MenuBar".into()));
+            menu.load(&*bus).await.unwrap();
+            menu.items.take_in(|items| {
+                let rows: Vec<_> = items
+                    .iter()
+                    .map(|i| (i.id, i.depth, &*i.label, i.is_sep, i.submenu))
+                    .collect();
+                assert_eq!(
+                    rows,
+                    [
+                        (1, 0, "Open", false, true),
+                        (4, 1, "Recent", false, false),
+                        (2, 0, "", true, false),
+                        (5, 0, "Quit", false, false),
+                    ]
+                );
+            });
+
+            assert!(menu.update_items(&*bus, &[5]).await.unwrap());
+            let label = menu.items.take_in(|items| items[3].label.clone());
+            assert_eq!(label, "Exit");
+            assert_eq!(
+                bus.calls(),
+                ["AboutToShow 0", "GetLayout 0 -1", "GetGroupProperties [5]"]
+            );
+        });
+    }
+
+    #[test]
+    fn click_and_scroll() {
+        let bus = Rc::new(MockBus::default());
+        run(async {
+            init_tray(&bus);
+            let item = add_item(":1.42").await;
+            bus.calls();
+
+            do_click(&item, 0, 20);
+            do_click(&item, 2, 20);
+            // one step down is below the threshold, the second one sends both
+            do_click(&item, 6, 20);
+            assert_eq!(bus.calls(), ["Activate (0, 0)", "SecondaryActivate (0, 0)"]);
+            do_click(&item, 6, 20);
+            assert_eq!(bus.calls(), ["Scroll (30, \"vertical\")"]);
+
+            // changing direction drops what was pending
+            do_click(&item, 7, 20);
+            do_click(&item, 8, 20);
+            do_click(&item, 8, 20);
+            assert_eq!(bus.calls(), ["Scroll (30, \"horizontal\")"]);
+            do_click(&item, 5, 10);
+            assert_eq!(bus.calls(), ["Scroll (-15, \"vertical\")"]);
+        });
+    }
+}
@@ -0,0 +1,255 @@
+//! Battery and power supply status from UPower, with a sysfs fallback
+use crate::battery::Reading;
+use crate::data::{Periodic, Value};
+use crate::dbus::DBus;
+use crate::state::{NotifierList, Runtime};
+use crate::util::{self, Cell};
+use log::{debug, info};
+use once_cell::unsync::OnceCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::rc::Rc;
+use zbus::zvariant::OwnedValue;
+
+const UPOWER: &str = "org.freedesktop.UPower";
+const DEVICE_IFACE: &str = "org.freedesktop.UPower.Device";
+const DEVICE_PATH: &str = "/org/freedesktop/UPower/devices/";
+
+#[derive(Debug)]
+struct Device {
+    path: Rc<str>,
+    /// None until the initial query completes
+    props: Option<HashMap<String, OwnedValue>>,
+}
+
+#[derive(Debug, Default)]
+struct Upower {
+    devices: Cell<Vec<Device>>,
+    /// Set if the UPower service is not running; sysfs is read instead
+    missing: Cell<bool>,
+    interested: Cell<NotifierList>,
+}
+
+thread_local! {
+    static DATA: OnceCell<Rc<Upower>> = Default::default();
+}
+
+impl Upower {
+    fn new() -> Rc<Self> {
+        let rv = Rc::new(Upower::default());
+        let this = rv.clone();
+        DBus::get_system().add_property_change_watcher(move |hdr, iface, changed, _inval| {
+            if iface != DEVICE_IFACE {
+                return;
+            }
+            let path = match hdr.path() {
+                Ok(Some(path)) => path,
+                _ => return,
+            };
+            let found = this.devices.take_in(|devices| {
+                let props = devices
+                    .iter_mut()
+                    .find(|d| &*d.path == path.as_str())
+                    .and_then(|d| d.props.as_mut());
+                match props {
+                    Some(props) => {
+                        for (&key, value) in changed {
+                            props.insert(key.to_owned(), value.clone());
+                        }
+                        true
+                    }
+                    None => false,
+                }
+            });
+            if found {
+                this.interested.take().notify_data("upower");
+            }
+        });
+        rv
+    }
+
+    fn query(self: &Rc<Self>, path: Rc<str>) {
+        let this = self.clone();
+        util::spawn("UPower query", async move {
+            let zbus = DBus::get_system().connection().await;
+            let reply = zbus
+                .call_method(
+                    Some(UPOWER),
+                    &*path,
+                    Some("org.freedesktop.DBus.Properties"),
+                    "GetAll",
+                    &DEVICE_IFACE,
+                )
+                .await;
+            let props: HashMap<String, OwnedValue> = match reply {
+                Ok(msg) => msg.body()?,
+                Err(zbus::Error::MethodError(name, ..))
+                    if name.as_str() == "org.freedesktop.DBus.Error.ServiceUnknown" =>
+                {
+                    info!("UPower is not running, reading battery status from sysfs");
+                    this.missing.set(true);
+                    this.interested.take().notify_data("upower");
+                    return Ok(());
+                }
+                Err(e) => return Err(format!("Could not query {}: {}", path, e).into()),
+            };
+            debug!("UPower device {} has {} properties", path, props.len());
+            this.devices.take_in(|devices| {
+                if let Some(dev) = devices.iter_mut().find(|d| d.path == path) {
+                    dev.props = Some(props);
+                }
+            });
+            this.interested.take().notify_data("upower");
+            Ok(())
+        });
+    }
+}
+
+/// Format a duration in seconds as H:MM
+fn format_time(secs: i64) -> Value<'static> {
+    if secs <= 0 {
+        return Value::Null;
+    }
+    let mins = (secs + 30) / 60;
+    Value::Owned(format!("{}:{:02}", mins / 60, mins % 60))
+}
+
+fn state_name(state: u32) -> &'static str {
+    match state {
+        1 => "charging",
+        2 => "discharging",
+        3 => "empty",
+        4 => "fully-charged",
+        5 => "pending-charge",
+        6 => "pending-discharge",
+        _ => "unknown",
+    }
+}
+
+fn lookup(props: &HashMap<String, OwnedValue>, key: &str) -> Value<'static> {
+    let float = |name: &str| {
+        props
+            .get(name)
+            .and_then(|v| f64::try_from(&**v).ok())
+            .map_or(Value::Null, Value::Float)
+    };
+    let time = |name: &str| props.get(name).and_then(|v| i64::try_from(&**v).ok());
+    let string = |name: &str| {
+        props
+            .get(name)
+            .and_then(|v| (&**v).try_into().ok())
+            .map_or(Value::Null, |s: &str| Value::Owned(s.to_owned()))
+    };
+    let state = props
+        .get("State")
+        .and_then(|v| u32::try_from(&**v).ok())
+        .unwrap_or(0);
+    match key {
+        "" | "percentage" => float("Percentage"),
+        "state" => Value::Borrow(state_name(state)),
+        "time" => match state {
+            1 => format_time(time("TimeToFull").unwrap_or(0)),
+            2 => format_time(time("TimeToEmpty").unwrap_or(0)),
+            _ => Value::Null,
+        },
+        "time-to-empty" => time("TimeToEmpty").map_or(Value::Null, |t| Value::Float(t as f64)),
+        "time-to-full" => time("TimeToFull").map_or(Value::Null, |t| Value::Float(t as f64)),
+        "energy-rate" => float("EnergyRate"),
+        "temperature" => float("Temperature"),
+        "icon" => string("IconName"),
+        "model" => string("Model"),
+        "present" => props
+            .get("IsPresent")
+            .and_then(|v| bool::try_from(&**v).ok())
+            .map_or(Value::Null, Value::Bool),
+        _ => Value::Null,
+    }
+}
+
+/// The last reading of an item's battery, which is polled while UPower is not running
+#[derive(Debug, Default)]
+pub struct Fallback(Cell<Option<Reading>>);
+
+/// Look up a value in a sysfs reading, using the same names as the UPower properties
+fn lookup_sysfs(reading: &Reading, key: &str) -> Value<'static> {
+    let time = |status: &str| match reading.time {
+        Some(secs) if reading.status == status => Value::Float(secs.round()),
+        _ => Value::Float(0.0),
+    };
+    match key {
+        "" | "percentage" => Value::Float(reading.percent),
+        "state" => Value::Borrow(match reading.status {
+            "full" => "fully-charged",
+            "not-charging" => "pending-charge",
+            status => status,
+        }),
+        "time" => reading
+            .time
+            .map_or(Value::Null, |secs| format_time(secs.round() as i64)),
+        "time-to-empty" => time("discharging"),
+        "time-to-full" => time("charging"),
+        "model" => reading
+            .model
+            .as_ref()
+            .map_or(Value::Null, |m| Value::Owned(m.to_string())),
+        "present" => Value::Bool(true),
+        _ => Value::Null,
+    }
+}
+
+pub fn read_in<F: FnOnce(Value) -> R, R>(
+    _name: &str,
+    device: &str,
+    sysfs: &Periodic<Fallback>,
+    key: &str,
+    rt: &Runtime,
+    f: F,
+) -> R {
+    let state = DATA.with(|cell| cell.get_or_init(Upower::new).clone());
+    state.interested.take_in(|i| i.add(rt));
+    if state.missing.get() {
+        let device: Box<str> = device.strip_prefix("battery_").unwrap_or(device).into();
+        sysfs.read_refresh(rt, move |last| {
+            let new = Reading::read(&device);
+            if last.0.replace(new.clone()) == new {
+                None
+            } else {
+                Some("upower")
+            }
+        });
+        let value = sysfs
+            .data()
+            .0
+            .take_in(|r| r.as_ref().map_or(Value::Null, |r| lookup_sysfs(r, key)));
+        return f(value);
+    }
+    let path: Rc<str> = if device.is_empty() {
+        format!("{}DisplayDevice", DEVICE_PATH).into()
+    } else if device.starts_with('/') {
+        device.into()
+    } else {
+        format!("{}{}", DEVICE_PATH, device).into()
+    };
+    let value = state
+        .devices
+        .take_in(|devices| match devices.iter().find(|d| d.path == path) {
+            Some(Device {
+                props: Some(props), ..
+            }) => Some(lookup(props, key)),
+            Some(_) => Some(Value::Null),
+            None => {
+                devices.push(Device {
+                    path: path.clone(),
+                    props: None,
+                });
+                None
+            }
+        });
+    match value {
+        Some(value) => f(value),
+        None => {
+            state.query(path);
+            f(Value::Null)
+        }
+    }
+}
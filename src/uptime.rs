@@ -0,0 +1,70 @@
+//! Time since boot from /proc/uptime
+use crate::data::Value;
+use crate::state::{NotifierList, Runtime};
+use crate::util::{humanize_duration, spawn_handle, Cell};
+use futures_util::future::RemoteHandle;
+use log::debug;
+use std::fs;
+use std::time::Duration;
+
+fn read_uptime() -> Option<f64> {
+    let text = match fs::read_to_string("/proc/uptime") {
+        Ok(text) => text,
+        Err(e) => {
+            debug!("Could not read /proc/uptime: {}", e);
+            return None;
+        }
+    };
+    // "350735.47 234388.90": the uptime and the idle time, in seconds
+    text.split_ascii_whitespace().next()?.parse().ok()
+}
+
+#[derive(Debug)]
+pub struct Uptime {
+    /// The number of units shown by `human`
+    parts: usize,
+    timer: Cell<Option<RemoteHandle<()>>>,
+}
+
+impl Uptime {
+    pub fn from_toml(config: &toml::Value) -> Self {
+        Uptime {
+            parts: config
+                .get("parts")
+                .and_then(|v| v.as_integer())
+                .map_or(2, |v| v.clamp(1, 3) as usize),
+            timer: Cell::default(),
+        }
+    }
+
+    pub fn read_in<F: FnOnce(Value) -> R, R>(
+        &self,
+        _name: &str,
+        key: &str,
+        rt: &Runtime,
+        f: F,
+    ) -> R {
+        let uptime = match read_uptime() {
+            Some(uptime) => uptime,
+            None => return f(Value::Null),
+        };
+        // Nothing shown changes until the next whole minute
+        let wake = Duration::from_secs_f64(60.0 - uptime % 60.0);
+        let mut notify = NotifierList::active(rt);
+        self.timer.set(Some(spawn_handle("Uptime tick", async move {
+            tokio::time::sleep(wake).await;
+            notify.notify_data("uptime");
+            Ok(())
+        })));
+
+        let mins = uptime as u64 / 60;
+        match key {
+            "" | "human" => f(Value::Owned(humanize_duration(mins * 60, self.parts))),
+            "days" => f(Value::Float((mins / 1440) as f64)),
+            "hours" => f(Value::Float((mins / 60 % 24) as f64)),
+            "mins" => f(Value::Float((mins % 60) as f64)),
+            "seconds" => f(Value::Float(uptime.floor())),
+            _ => f(Value::Null),
+        }
+    }
+}
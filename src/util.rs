@@ -37,6 +37,52 @@ pub fn toml_to_f64(value: Option<&toml::Value>) -> Option<f64> {
     })
 }
 
+/// Format a size in bytes, like "3.2 GiB", using the largest power-of-two unit that keeps the
+/// number at least 1
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else if value < 10.0 {
+        format!("{:.1} {}", value, UNITS[unit])
+    } else {
+        format!("{:.0} {}", value, UNITS[unit])
+    }
+}
+
+/// Format a duration like "3d 4h", showing at most `parts` units starting with the largest
+/// non-zero one.  Units that are zero are left out, so 3 days and 5 minutes is "3d" with two
+/// parts and "3d 5m" with three.
+pub fn humanize_duration(secs: u64, parts: usize) -> String {
+    let units = [
+        (secs / 86400, 'd'),
+        (secs / 3600 % 24, 'h'),
+        (secs / 60 % 60, 'm'),
+        (secs % 60, 's'),
+    ];
+    let mut rv = String::new();
+    let first = units
+        .iter()
+        .position(|&(n, _)| n != 0)
+        .unwrap_or(units.len() - 1);
+    for &(n, unit) in units.iter().skip(first).take(parts.max(1)) {
+        if n == 0 && !rv.is_empty() {
+            continue;
+        }
+        if !rv.is_empty() {
+            rv.push(' ');
+        }
+        rv.push_str(&format!("{}{}", n, unit));
+    }
+    rv
+}
+
 #[derive(Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ImplDebug<T>(pub T);
 
@@ -1,4 +1,4 @@
-use log::debug;
+use log::{debug, info, warn};
 use smithay_client_toolkit::compositor::{CompositorState, SurfaceData as SctkSurfaceData};
 use smithay_client_toolkit::output::OutputState;
 use smithay_client_toolkit::registry::{RegistryState, SimpleGlobal};
@@ -15,7 +15,7 @@ use std::error::Error;
 use std::io;
 use std::rc::Rc;
 use std::sync::{
-    atomic::{AtomicU32, AtomicU8, Ordering},
+    atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering},
     Arc, Mutex,
 };
 use tokio::io::unix::AsyncFd;
@@ -26,7 +26,13 @@ use wayland_client::protocol::wl_pointer::WlPointer;
 use wayland_client::protocol::wl_seat::WlSeat;
 use wayland_client::protocol::wl_surface::WlSurface;
 use wayland_client::protocol::wl_touch::WlTouch;
-use wayland_client::{Connection, Proxy, QueueHandle};
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
+use wayland_protocols::wp::fractional_scale::v1::client::wp_fractional_scale_manager_v1::WpFractionalScaleManagerV1;
+use wayland_protocols::wp::fractional_scale::v1::client::wp_fractional_scale_v1::{
+    self, WpFractionalScaleV1,
+};
+use wayland_protocols::wp::viewporter::client::wp_viewport::WpViewport;
+use wayland_protocols::wp::viewporter::client::wp_viewporter::WpViewporter;
 use wayland_protocols::xdg::shell::client::xdg_popup;
 use wayland_protocols::xdg::shell::client::xdg_positioner;
 use wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_manager_v1::ZwlrDataControlManagerV1;
@@ -51,6 +57,20 @@ pub enum Button {
     Tap,
 }
 
+impl Button {
+    /// Convert a linux input event code (as used by wl_pointer) to a button
+    fn from_linux(code: u32) -> Option<Self> {
+        match code {
+            0x110 => Some(Button::Left),    // BTN_LEFT
+            0x111 => Some(Button::Right),   // BTN_RIGHT
+            0x112 => Some(Button::Middle),  // BTN_MIDDLE
+            0x113 => Some(Button::Back),    // BTN_SIDE or "back"
+            0x114 => Some(Button::Forward), // BTN_EXTRA or "forward"
+            _ => None,
+        }
+    }
+}
+
 /// Structures related to the Wayland display
 #[derive(Debug)]
 pub struct WaylandClient {
@@ -65,12 +85,44 @@ pub struct WaylandClient {
     pub shm: Shm,
     pub wlr_dcm: SimpleGlobal<ZwlrDataControlManagerV1, 2>,
     pub xdg: XdgShell,
+    /// Both are needed for fractional scaling; bars use integer scales without them
+    fractional: Option<SimpleGlobal<WpFractionalScaleManagerV1, 1>>,
+    viewporter: Option<SimpleGlobal<WpViewporter, 1>>,
 
     taps: Vec<TapState>,
 
     io: Arc<WaylandIO>,
 }
 
+/// The [WaylandClient] of a [Runtime].  Unit tests have no compositor to connect to, so their
+/// runtime does without one.
+#[derive(Debug)]
+pub struct Wayland(Option<WaylandClient>);
+
+impl Wayland {
+    pub fn new(client: WaylandClient) -> Self {
+        Wayland(Some(client))
+    }
+
+    #[cfg(test)]
+    pub fn none() -> Self {
+        Wayland(None)
+    }
+}
+
+impl std::ops::Deref for Wayland {
+    type Target = WaylandClient;
+    fn deref(&self) -> &WaylandClient {
+        self.0.as_ref().expect("No Wayland connection")
+    }
+}
+
+impl std::ops::DerefMut for Wayland {
+    fn deref_mut(&mut self) -> &mut WaylandClient {
+        self.0.as_mut().expect("No Wayland connection")
+    }
+}
+
 #[derive(Debug)]
 struct WaylandIO {
     conn: wayland_client::Connection,
@@ -86,6 +138,8 @@ smithay_client_toolkit::delegate_pointer!(State, pointer: [PointerData]);
 smithay_client_toolkit::delegate_registry!(State);
 smithay_client_toolkit::delegate_seat!(State);
 smithay_client_toolkit::delegate_shm!(State);
+smithay_client_toolkit::delegate_simple!(State, WpFractionalScaleManagerV1, 1);
+smithay_client_toolkit::delegate_simple!(State, WpViewporter, 1);
 smithay_client_toolkit::delegate_simple!(State, ZwlrDataControlManagerV1, 1);
 smithay_client_toolkit::delegate_touch!(State);
 smithay_client_toolkit::delegate_xdg_popup!(State);
@@ -96,6 +150,8 @@ struct PointerState {
     axis_h: f64,
     axis_v: f64,
     axis_ts: u32,
+    /// The serial of the last enter event, needed to change the cursor
+    enter_serial: u32,
 }
 
 #[derive(Debug)]
@@ -120,10 +176,27 @@ pub struct SurfaceData {
     sctk: SctkSurfaceData,
     width: AtomicU32,
     height: AtomicU32,
+    /// Scale set in the config, used in place of the one from the output
+    fixed_scale: Option<i32>,
+    /// Preferred fractional scale in units of 1/120, or 0 if the compositor has not sent one
+    fractional_scale: AtomicU32,
+    /// The fractional scale and viewport objects for this surface, if supported
+    fractional: Mutex<Option<(WpFractionalScaleV1, WpViewport)>>,
+    /// Hash of the last frame committed, or 0 if the next frame must be committed
+    last_frame: AtomicU64,
 
     state: AtomicU8,
 }
 
+impl Drop for SurfaceData {
+    fn drop(&mut self) {
+        if let Some((fractional, viewport)) = self.fractional.get_mut().unwrap().take() {
+            fractional.destroy();
+            viewport.destroy();
+        }
+    }
+}
+
 impl smithay_client_toolkit::compositor::SurfaceDataExt for SurfaceData {
     fn surface_data(&self) -> &SctkSurfaceData {
         &self.sctk
@@ -138,6 +211,11 @@ impl SurfaceData {
     const NEED_RENDER: u8 = SurfaceData::DAMAGED | SurfaceData::CONFIGURED;
     const POST_RENDER: u8 = SurfaceData::THROTTLED | SurfaceData::CONFIGURED;
 
+    /// Largest width or height (in logical pixels) we will accept from a configure event.  This
+    /// is well above any real output, but keeps a buggy compositor from making us allocate an
+    /// enormous shm buffer.
+    const MAX_SIZE: u32 = 16384;
+
     pub fn try_from_wl(wl: &WlSurface) -> Option<&Self> {
         wl.data()
     }
@@ -157,6 +235,33 @@ impl SurfaceData {
             .is_ok()
     }
 
+    /// Undo start_render when nothing was committed, so the next draw tries again
+    pub fn render_failed(&self) {
+        self.state.store(SurfaceData::NEED_RENDER, Ordering::Relaxed);
+    }
+
+    /// Undo start_render when the new frame was the same as the one already shown.
+    ///
+    /// Returns true if the surface was damaged during the render and should be drawn again.
+    pub fn render_skipped(&self) -> bool {
+        let prev = self
+            .state
+            .fetch_and(!SurfaceData::THROTTLED, Ordering::Relaxed);
+        prev & SurfaceData::DAMAGED != 0
+    }
+
+    /// Record the hash of a newly rendered frame.  Returns false if it matches the last frame.
+    pub fn set_last_frame(&self, hash: u64) -> bool {
+        let hash = hash.max(1);
+        self.last_frame.swap(hash, Ordering::Relaxed) != hash
+    }
+
+    /// Commit the next frame even if it matches the last one, such as when only the input or
+    /// opaque region changed
+    pub fn force_commit(&self) {
+        self.last_frame.store(0, Ordering::Relaxed);
+    }
+
     /// Returns true if a render should be requested now
     pub fn damage_full(&self) -> bool {
         let prev = self.state.fetch_or(SurfaceData::DAMAGED, Ordering::Relaxed);
@@ -164,12 +269,54 @@ impl SurfaceData {
     }
 
     pub fn scale_transform(&self) -> tiny_skia::Transform {
-        let scale = self.scale_factor();
-        tiny_skia::Transform::from_scale(scale as f32, scale as f32)
+        let scale = self.scale();
+        tiny_skia::Transform::from_scale(scale, scale)
     }
 
+    /// The integer scale, used for the buffer scale when fractional scaling is not in use
     pub fn scale_factor(&self) -> i32 {
-        self.sctk.scale_factor()
+        self.fixed_scale.unwrap_or_else(|| self.sctk.scale_factor())
+    }
+
+    /// The scale buffers are drawn at, which may be fractional
+    pub fn scale(&self) -> f32 {
+        match self.fractional_scale.load(Ordering::Relaxed) {
+            0 => self.scale_factor() as f32,
+            n => n as f32 / 120.0,
+        }
+    }
+
+    /// Set the viewport for a buffer drawn at a fractional scale.  Must be called before
+    /// attaching each buffer, as the logical size may have changed.
+    pub fn set_viewport(&self) {
+        if self.fractional_scale.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let (width, height) = (self.width(), self.height());
+        if let Some((_, viewport)) = &*self.fractional.lock().unwrap() {
+            if width > 0 && height > 0 {
+                viewport.set_destination(width as i32, height as i32);
+            }
+        }
+    }
+
+    pub fn fixed_scale(&self) -> Option<i32> {
+        self.fixed_scale
+    }
+
+    /// Store the size from a configure event, clamping it to a sane range
+    fn set_size(&self, width: i64, height: i64) {
+        let clamp = |what, v: i64| {
+            let rv = v.clamp(0, Self::MAX_SIZE as i64);
+            if rv != v {
+                warn!("Compositor sent invalid surface {} {}, using {}", what, v, rv);
+            }
+            rv as u32
+        };
+        self.width.store(clamp("width", width), Ordering::Relaxed);
+        self.height.store(clamp("height", height), Ordering::Relaxed);
+        // a configure must be followed by a commit, even if the contents did not change
+        self.last_frame.store(0, Ordering::Relaxed);
     }
 
     pub fn height(&self) -> u32 {
@@ -181,11 +328,11 @@ impl SurfaceData {
     }
 
     pub fn pixel_width(&self) -> i32 {
-        self.width.load(Ordering::Relaxed) as i32 * self.scale_factor()
+        (self.width() as f32 * self.scale()).ceil() as i32
     }
 
     pub fn pixel_height(&self) -> i32 {
-        self.height.load(Ordering::Relaxed) as i32 * self.scale_factor()
+        (self.height() as f32 * self.scale()).ceil() as i32
     }
 }
 
@@ -198,6 +345,13 @@ impl smithay_client_toolkit::compositor::CompositorHandler for State {
         new_factor: i32,
     ) {
         if let Some(data) = SurfaceData::try_from_wl(surf) {
+            if data.fixed_scale.is_some() {
+                return;
+            }
+            if data.fractional_scale.load(Ordering::Relaxed) != 0 {
+                // the buffer scale stays at 1; the preferred scale event handles the change
+                return;
+            }
             surf.set_buffer_scale(new_factor);
             if data.damage_full() {
                 self.request_draw();
@@ -237,8 +391,22 @@ impl smithay_client_toolkit::shell::wlr_layer::LayerShellHandler for State {
         _serial: u32,
     ) {
         let data = SurfaceData::from_wl(ls.wl_surface());
-        data.width.store(config.new_size.0, Ordering::Relaxed);
-        data.height.store(config.new_size.1, Ordering::Relaxed);
+        let fixed = self
+            .bars
+            .iter()
+            .find(|bar| bar.ls == *ls)
+            .and_then(|bar| bar.fixed_size());
+        match fixed {
+            Some(size) if size != config.new_size => {
+                debug!(
+                    "Compositor suggested size {:?}, keeping {:?}",
+                    config.new_size, size
+                );
+                ls.set_size(size.0, size.1);
+                data.set_size(size.0.into(), size.1.into());
+            }
+            _ => data.set_size(config.new_size.0.into(), config.new_size.1.into()),
+        }
         data.state.fetch_or(
             SurfaceData::CONFIGURED | SurfaceData::DAMAGED,
             Ordering::Relaxed,
@@ -279,12 +447,17 @@ impl smithay_client_toolkit::output::OutputHandler for State {
 
     fn new_output(&mut self, _: &Connection, _: &QueueHandle<Self>, output: WlOutput) {
         self.output_ready(&output);
+        self.output_count_changed(None);
     }
-    fn update_output(&mut self, _: &Connection, _: &QueueHandle<Self>, _: WlOutput) {
-        // anything we care about will get applied via configure requests on our surface
+    fn update_output(&mut self, _: &Connection, _: &QueueHandle<Self>, output: WlOutput) {
+        // The bar size is applied via configure requests on our surface, but the values shown
+        // in {output.*} and the scale need the bar to be recreated
+        self.output_changed(&output);
     }
-    fn output_destroyed(&mut self, _: &Connection, _: &QueueHandle<Self>, _: WlOutput) {
-        // do nothing and rely on the Closed event for destroy
+    fn output_destroyed(&mut self, _: &Connection, _: &QueueHandle<Self>, output: WlOutput) {
+        // rely on the Closed event to destroy the bars on this output, but bars on the other
+        // outputs may depend on the number of outputs
+        self.output_count_changed(Some(&output));
     }
 }
 
@@ -321,38 +494,47 @@ impl PointerHandler for State {
         for event in events {
             match event.kind {
                 Enter { serial } => {
-                    self.renderer
-                        .set_cursor(&self.runtime.wayland, &pointer, serial);
-
-                    self.dispatch_surface_event(&event.surface, |surf, rt| {
-                        surf.hover(event.position, rt);
-                    });
+                    pointer
+                        .data::<PointerData>()
+                        .unwrap()
+                        .state
+                        .lock()
+                        .unwrap()
+                        .enter_serial = serial;
+                    self.runtime.set_pointer_over(true);
+                    self.pointer_hover(pointer, &event.surface, event.position);
                 }
                 Leave { .. } => {
+                    self.renderer.cursor_left();
+                    self.runtime.set_pointer_over(false);
                     self.dispatch_surface_event(&event.surface, |surf, rt| {
                         surf.no_hover(rt);
                     });
                 }
                 Motion { .. } => {
-                    self.dispatch_surface_event(&event.surface, |surf, rt| {
-                        surf.hover(event.position, rt);
-                    });
+                    self.pointer_hover(pointer, &event.surface, event.position);
                 }
                 Press { button, .. } => {
-                    let button_id = match button {
-                        0x110 => Button::Left,    // BTN_LEFT
-                        0x111 => Button::Right,   // BTN_RIGHT
-                        0x112 => Button::Middle,  // BTN_MIDDLE
-                        0x113 => Button::Back,    // BTN_SIDE or "back"
-                        0x114 => Button::Forward, // BTN_EXTRA or "forward"
-                        _ => {
+                    let button_id = match Button::from_linux(button) {
+                        Some(id) => id,
+                        None => {
                             debug!("You can add events for this button ({})", button);
                             return;
                         }
                     };
-                    self.dispatch_pointer_button(&event.surface, event.position, button_id);
+                    self.dispatch_surface_event(&event.surface, |surf, rt| {
+                        surf.press(event.position, button_id, rt);
+                    });
+                    self.request_draw();
+                }
+                Release { button, .. } => {
+                    if let Some(button_id) = Button::from_linux(button) {
+                        self.dispatch_surface_event(&event.surface, |surf, rt| {
+                            surf.release(event.position, button_id, rt);
+                        });
+                        self.request_draw();
+                    }
                 }
-                Release { .. } => {}
                 Axis {
                     time,
                     horizontal,
@@ -605,8 +787,7 @@ impl smithay_client_toolkit::shell::xdg::popup::PopupHandler for State {
         config: popup::PopupConfigure,
     ) {
         let data = SurfaceData::from_wl(popup.wl_surface());
-        data.width.store(config.width as u32, Ordering::Relaxed);
-        data.height.store(config.height as u32, Ordering::Relaxed);
+        data.set_size(config.width.into(), config.height.into());
         data.state
             .fetch_or(SurfaceData::CONFIGURED, Ordering::Relaxed);
         if data.damage_full() {
@@ -634,7 +815,24 @@ pub trait SurfaceEvents {
     fn no_hover(&mut self, rt: &mut Runtime) {
         let _ = rt;
     }
+    /// The name of the cursor to show at this position
+    fn cursor(&self, pos: (f64, f64)) -> &str {
+        let _ = pos;
+        "default"
+    }
     fn button(&mut self, pos: (f64, f64), button: Button, runtime: &mut Runtime);
+    /// A pointer button was pressed; by default, the click is handled immediately
+    fn press(&mut self, pos: (f64, f64), button: Button, runtime: &mut Runtime) {
+        self.button(pos, button, runtime);
+    }
+    fn release(&mut self, pos: (f64, f64), button: Button, runtime: &mut Runtime) {
+        let _ = (pos, button, runtime);
+    }
+}
+
+/// Convert a failure to bind a global into an error that explains what is missing
+fn required<T>(bound: Result<T, impl std::fmt::Display>, name: &str) -> Result<T, String> {
+    bound.map_err(|e| format!("The compositor does not support {name} ({e}); rwaybar requires it"))
 }
 
 impl WaylandClient {
@@ -645,6 +843,23 @@ impl WaylandClient {
         let (globals, wl_queue) = wayland_client::globals::registry_queue_init::<State>(&conn)?;
         let queue = wl_queue.handle();
 
+        globals.contents().with_list(|list| {
+            for global in list {
+                debug!("Global {} version {}", global.interface, global.version);
+            }
+            // Show what the compositor supports, to explain how bars are scaled
+            for name in [
+                "wp_fractional_scale_manager_v1",
+                "wp_viewporter",
+                "zwlr_foreign_toplevel_manager_v1",
+            ] {
+                match list.iter().find(|g| g.interface == name) {
+                    Some(global) => info!("Compositor supports {} version {}", name, global.version),
+                    None => info!("Compositor does not support {}", name),
+                }
+            }
+        });
+
         let registry = RegistryState::new(&globals);
         let fd = conn.prepare_read().unwrap().connection_fd().as_raw_fd();
         let io = Arc::new(WaylandIO {
@@ -661,13 +876,15 @@ impl WaylandClient {
             registry,
             io,
 
-            compositor: CompositorState::bind(&globals, &queue)?,
+            compositor: required(CompositorState::bind(&globals, &queue), "wl_compositor")?,
             output: OutputState::new(&globals, &queue),
             seat: SeatState::new(&globals, &queue),
-            shm: Shm::bind(&globals, &queue)?,
-            layer: LayerShell::bind(&globals, &queue)?,
-            wlr_dcm: SimpleGlobal::bind(&globals, &queue)?,
-            xdg: XdgShell::bind(&globals, &queue)?,
+            shm: required(Shm::bind(&globals, &queue), "wl_shm")?,
+            layer: required(LayerShell::bind(&globals, &queue), "wlr-layer-shell")?,
+            wlr_dcm: required(SimpleGlobal::bind(&globals, &queue), "wlr-data-control")?,
+            xdg: required(XdgShell::bind(&globals, &queue), "xdg-shell")?,
+            fractional: SimpleGlobal::bind(&globals, &queue).ok(),
+            viewporter: SimpleGlobal::bind(&globals, &queue).ok(),
 
             taps: Default::default(),
             queue,
@@ -685,14 +902,71 @@ impl WaylandClient {
         self.io.flush.notify_one()
     }
 
-    pub fn create_surface(&self, scale: i32) -> WlSurface {
+    pub fn create_surface(&self, scale: i32, fixed_scale: Option<i32>) -> WlSurface {
         let sd = SurfaceData {
             sctk: SctkSurfaceData::new(None, scale),
             height: AtomicU32::new(0),
             width: AtomicU32::new(0),
+            fixed_scale,
+            fractional_scale: AtomicU32::new(0),
+            fractional: Mutex::new(None),
+            last_frame: AtomicU64::new(0),
             state: AtomicU8::new(SurfaceData::NEW),
         };
-        self.compositor.create_surface_with_data(&self.queue, sd)
+        let surf = self.compositor.create_surface_with_data(&self.queue, sd);
+        // A scale set in the config is always an integer, so there is no need to ask
+        if fixed_scale.is_none() {
+            if let (Some(fractional), Some(viewporter)) = (&self.fractional, &self.viewporter) {
+                if let (Ok(fractional), Ok(viewporter)) = (fractional.get(), viewporter.get()) {
+                    let fs = fractional.get_fractional_scale(&surf, &self.queue, surf.clone());
+                    let vp = viewporter.get_viewport(&surf, &self.queue, ());
+                    *SurfaceData::from_wl(&surf).fractional.lock().unwrap() = Some((fs, vp));
+                }
+            }
+        }
+        surf
+    }
+}
+
+impl Dispatch<WpFractionalScaleV1, WlSurface> for State {
+    fn event(
+        state: &mut State,
+        _: &WpFractionalScaleV1,
+        event: wp_fractional_scale_v1::Event,
+        surf: &WlSurface,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let scale = match event {
+            wp_fractional_scale_v1::Event::PreferredScale { scale } => scale,
+            _ => return,
+        };
+        let data = match SurfaceData::try_from_wl(surf) {
+            Some(data) => data,
+            None => return,
+        };
+        if data.fractional_scale.swap(scale, Ordering::Relaxed) == scale {
+            return;
+        }
+        debug!("Surface scale is now {}", scale as f32 / 120.0);
+        // the viewport maps the buffer to the logical size, so it is not scaled again
+        surf.set_buffer_scale(1);
+        data.last_frame.store(0, Ordering::Relaxed);
+        if data.damage_full() {
+            state.request_draw();
+        }
+    }
+}
+
+impl Dispatch<WpViewport, ()> for State {
+    fn event(
+        _: &mut State,
+        _: &WpViewport,
+        _: <WpViewport as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
     }
 }
 
@@ -745,8 +1019,28 @@ pub async fn run_queue(
 }
 
 impl State {
+    fn pointer_hover(&mut self, pointer: &WlPointer, over: &WlSurface, position: (f64, f64)) {
+        let mut cursor = None;
+        self.dispatch_surface_event(over, |surf, rt| {
+            surf.hover(position, rt);
+            cursor = Some(surf.cursor(position).to_owned());
+        });
+        let serial = pointer
+            .data::<PointerData>()
+            .unwrap()
+            .state
+            .lock()
+            .unwrap()
+            .enter_serial;
+        let cursor = cursor.as_deref().unwrap_or("default");
+        self.renderer
+            .set_cursor(&self.runtime.wayland, pointer, serial, cursor);
+    }
+
     fn dispatch_pointer_button(&mut self, over: &WlSurface, position: (f64, f64), button: Button) {
         for bar in &mut self.bars {
+            self.runtime.set_render_bar(Some(bar.id));
+            bar.set_items(&mut self.runtime);
             if bar.ls.wl_surface() == over {
                 bar.button(position, button, &mut self.runtime);
             }
@@ -756,6 +1050,7 @@ impl State {
                 }
             }
         }
+        self.runtime.set_render_bar(None);
     }
 
     fn dispatch_surface_event(
@@ -764,6 +1059,8 @@ impl State {
         mut f: impl FnMut(&mut dyn SurfaceEvents, &mut Runtime),
     ) {
         for bar in &mut self.bars {
+            self.runtime.set_render_bar(Some(bar.id));
+            bar.set_items(&mut self.runtime);
             if surf == bar.ls.wl_surface() {
                 f(bar, &mut self.runtime);
             }
@@ -773,6 +1070,7 @@ impl State {
                 }
             }
         }
+        self.runtime.set_render_bar(None);
     }
 }
 
@@ -784,7 +1082,20 @@ pub struct Popup {
     pub sctk: popup::Popup,
     pub anchor: (i32, i32, i32, i32),
     pub req_size: (i32, i32), // requested logical size; may be rejected by compositor
-    pub prefer_top: bool,
+    /// The side of the anchor rectangle the popup opens on
+    side: xdg_positioner::Anchor,
+}
+
+/// Place a popup on one side of its anchor rectangle
+fn set_side(pos: &XdgPositioner, side: xdg_positioner::Anchor) {
+    use xdg_positioner::{Anchor, Gravity};
+    pos.set_anchor(side);
+    pos.set_gravity(match side {
+        Anchor::Top => Gravity::Top,
+        Anchor::Left => Gravity::Left,
+        Anchor::Right => Gravity::Right,
+        _ => Gravity::Bottom,
+    });
 }
 
 impl Popup {
@@ -796,12 +1107,17 @@ impl Popup {
     ) -> Popup {
         match bar.ls.kind() {
             smithay_client_toolkit::shell::wlr_layer::SurfaceKind::Wlr(ls) => {
-                let scale = bar
-                    .ls
-                    .wl_surface()
-                    .data::<SurfaceData>()
-                    .map_or(1, |d| d.scale_factor());
-                Self::new(wayland, &ls, !bar.anchor_top, anchor, size, scale)
+                let data = bar.ls.wl_surface().data::<SurfaceData>();
+                let scale = data.map_or(1, |d| d.scale_factor());
+                let fixed_scale = data.and_then(|d| d.fixed_scale());
+                // the top of a vertical bar is on its right
+                let side = match (bar.vertical(), bar.popup_above) {
+                    (false, true) => xdg_positioner::Anchor::Top,
+                    (false, false) => xdg_positioner::Anchor::Bottom,
+                    (true, true) => xdg_positioner::Anchor::Right,
+                    (true, false) => xdg_positioner::Anchor::Left,
+                };
+                Self::new(wayland, &ls, side, anchor, size, scale, fixed_scale)
             }
             _ => unreachable!(),
         }
@@ -810,27 +1126,20 @@ impl Popup {
     pub fn new(
         wayland: &mut WaylandClient,
         parent: &ZwlrLayerSurfaceV1,
-        prefer_top: bool,
+        side: xdg_positioner::Anchor,
         anchor: (i32, i32, i32, i32),
         size: (i32, i32),
         scale: i32,
+        fixed_scale: Option<i32>,
     ) -> Self {
-        use xdg_positioner::{Anchor, Gravity};
-
-        let surf = wayland.create_surface(scale);
+        let surf = wayland.create_surface(scale, fixed_scale);
 
         let pos = XdgPositioner::new(&wayland.xdg).unwrap();
 
         pos.set_size(size.0, size.1);
         pos.set_anchor_rect(anchor.0, anchor.1, anchor.2, anchor.3);
         pos.set_offset(0, 0);
-        if prefer_top {
-            pos.set_anchor(Anchor::Top);
-            pos.set_gravity(Gravity::Top);
-        } else {
-            pos.set_anchor(Anchor::Bottom);
-            pos.set_gravity(Gravity::Bottom);
-        }
+        set_side(&pos, side);
         pos.set_constraint_adjustment(0xF); // allow moving but not resizing
 
         let sctk =
@@ -848,7 +1157,7 @@ impl Popup {
             queue: wayland.queue.clone(),
             anchor,
             req_size: size,
-            prefer_top,
+            side,
         }
     }
 
@@ -860,7 +1169,6 @@ impl Popup {
         scale: i32,
     ) {
         if self.sctk.xdg_popup().version() >= xdg_popup::REQ_REPOSITION_SINCE {
-            use xdg_positioner::{Anchor, Gravity};
             self.sctk
                 .xdg_surface()
                 .set_window_geometry(0, 0, size.0, size.1);
@@ -869,19 +1177,22 @@ impl Popup {
             pos.set_size(size.0, size.1);
             pos.set_anchor_rect(self.anchor.0, self.anchor.1, self.anchor.2, self.anchor.3);
             pos.set_offset(0, 0);
-            if self.prefer_top {
-                pos.set_anchor(Anchor::Top);
-                pos.set_gravity(Gravity::Top);
-            } else {
-                pos.set_anchor(Anchor::Bottom);
-                pos.set_gravity(Gravity::Bottom);
-            }
+            set_side(&pos, self.side);
             pos.set_constraint_adjustment(0xF); // allow moving but not resizing
             self.sctk.xdg_popup().reposition(&pos, 0);
             self.req_size = size;
         } else {
             // can't resize; emulate by destroying and re-creating.
-            *self = Self::new(wayland, ls_surf, self.prefer_top, self.anchor, size, scale);
+            let fixed_scale = SurfaceData::from_wl(&self.surf).fixed_scale();
+            *self = Self::new(
+                wayland,
+                ls_surf,
+                self.side,
+                self.anchor,
+                size,
+                scale,
+                fixed_scale,
+            );
         }
     }
 }